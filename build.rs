@@ -5,4 +5,5 @@ fn main() {
     println!("cargo:rustc-link-lib=static=tree-sitter-typescript");
     println!("cargo:rustc-link-lib=static=tree-sitter-bash");
     println!("cargo:rustc-link-lib=static=tree-sitter-rust");
+    println!("cargo:rustc-link-lib=static=tree-sitter-json");
 }
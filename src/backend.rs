@@ -0,0 +1,263 @@
+//! The LLM backends requests are sent to, selected by the `[backend]` table of
+//! the configuration (or of a profile).
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::BackendConfig;
+
+/// A request sent to a backend.
+pub struct CompletionRequest<'a> {
+    /// The mode being run.
+    pub mode: &'a str,
+    /// The selected structure, or an empty string.
+    pub structure: &'a str,
+    /// The flow configured for this request. FluentCLI runs it, the command
+    /// backend exposes it as `RFCU_FLOW`.
+    pub flow: &'a str,
+    pub prompt: &'a str,
+    /// The user request read from stdin, which FluentCLI also reads on stdin.
+    pub user_request: &'a str,
+    /// A file the backend may read as additional context.
+    pub context_file: Option<&'a str>,
+    /// Whether only the code blocks of the answer are wanted.
+    pub code_only: bool,
+}
+
+/// Backends are shared by the threads that send the requests of a run.
+pub trait CompletionBackend: Sync {
+    /// A short name for progress messages.
+    fn name(&self) -> &str;
+
+    /// Sends the request and returns the raw answer.
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String>;
+}
+
+/// Creates the backend described by the configuration.
+pub fn from_config(config: &BackendConfig) -> Box<dyn CompletionBackend> {
+    match config {
+        BackendConfig::Fluent { binary } => Box::new(FluentBackend { binary: binary.clone() }),
+        BackendConfig::Command { command } => Box::new(CommandBackend { command: command.clone() }),
+        BackendConfig::Openai { url, model, api_key_env, system_prompt, temperature, timeout_secs } => {
+            Box::new(OpenAiBackend {
+                url: url.clone(),
+                model: model.clone(),
+                api_key_env: api_key_env.clone(),
+                system_prompt: system_prompt.clone(),
+                temperature: *temperature,
+                timeout: Duration::from_secs(timeout_secs.unwrap_or(300)),
+            })
+        }
+        BackendConfig::Replay { fixtures } => Box::new(ReplayBackend { fixtures: PathBuf::from(fixtures), upstream: None }),
+        BackendConfig::Record { fixtures, upstream } => Box::new(ReplayBackend {
+            fixtures: PathBuf::from(fixtures),
+            upstream: Some(from_config(upstream)),
+        }),
+    }
+}
+
+/// Runs a child process, writes `input` to its stdin and returns its stdout.
+fn run_with_input(mut command: Command, description: &str, input: &str) -> io::Result<String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to start {}: {}", description, e)))?;
+
+    // Write from another thread and close stdin once done, so neither side
+    // blocks on a full pipe. A child that exits without reading all of its
+    // input is not an error.
+    let mut stdin = child.stdin.take().expect("Failed to open stdin");
+    let input = input.to_string();
+    let writer = thread::spawn(move || match stdin.write_all(input.as_bytes()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(()),
+    });
+
+    let output = child.wait_with_output()?;
+    writer.join().expect("The stdin writer panicked")?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", description, output.status)));
+    }
+
+    String::from_utf8(output.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The FluentCLI binary, called as `fluent <flow> <prompt>` with the user
+/// request on stdin.
+pub struct FluentBackend {
+    binary: String,
+}
+
+impl CompletionBackend for FluentBackend {
+    fn name(&self) -> &str {
+        "fluentcli"
+    }
+
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String> {
+        eprintln!("Starting fluentcli with flowname: {}, request: {}", request.flow, request.prompt);
+        let mut command = Command::new(&self.binary);
+        command.arg(request.flow).arg(request.prompt);
+        if let Some(context_file) = request.context_file {
+            command.arg("--additional-context-file").arg(context_file);
+        }
+        if request.code_only {
+            command.arg("-p"); // Parse the code blocks
+        }
+
+        eprintln!("Writing user request to fluentcli stdin...");
+        run_with_input(command, &format!("'{}' (set [backend] to use another backend)", self.binary), request.user_request)
+    }
+}
+
+/// Any shell command that reads the prompt on stdin and writes the answer on
+/// stdout. The flow and the context file are passed as `RFCU_FLOW` and
+/// `RFCU_CONTEXT_FILE`.
+pub struct CommandBackend {
+    command: String,
+}
+
+impl CompletionBackend for CommandBackend {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String> {
+        eprintln!("Running backend command: {}", self.command);
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .env("RFCU_FLOW", request.flow)
+            .env("RFCU_CONTEXT_FILE", request.context_file.unwrap_or_default());
+        run_with_input(command, &format!("backend command '{}'", self.command), request.prompt)
+    }
+}
+
+/// An OpenAI-compatible chat completions API, as served by OpenAI, llama.cpp,
+/// Ollama and others.
+pub struct OpenAiBackend {
+    url: String,
+    model: String,
+    api_key_env: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f64>,
+    timeout: Duration,
+}
+
+impl CompletionBackend for OpenAiBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String> {
+        let endpoint = format!("{}/chat/completions", self.url.trim_end_matches('/'));
+        eprintln!("Sending the request to {} with model {}", endpoint, self.model);
+
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = &self.system_prompt {
+            messages.push(serde_json::json!({ "role": "system", "content": system_prompt }));
+        }
+        messages.push(serde_json::json!({ "role": "user", "content": request.prompt }));
+
+        let mut body = serde_json::json!({ "model": self.model, "messages": messages });
+        if let Some(temperature) = self.temperature {
+            body["temperature"] = serde_json::json!(temperature);
+        }
+
+        let agent = ureq::AgentBuilder::new().timeout(self.timeout).build();
+        let mut http_request = agent.post(&endpoint);
+        if let Some(api_key_env) = &self.api_key_env {
+            let api_key = std::env::var(api_key_env)
+                .map_err(|_| io::Error::other(format!("the {} environment variable is not set", api_key_env)))?;
+            http_request = http_request.set("Authorization", &format!("Bearer {}", api_key));
+        }
+
+        let response: serde_json::Value = http_request
+            .send_json(body)
+            .map_err(|e| io::Error::other(format!("request to {} failed: {}", endpoint, e)))?
+            .into_json()?;
+
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response from {}: {}", endpoint, response)))
+    }
+}
+
+/// Serves answers saved in a fixtures directory, one file per request, named
+/// after the mode and the structure and keyed by a hash of the mode, the
+/// structure and the prompt. With an upstream backend, requests are sent to it
+/// and its answers are saved first.
+pub struct ReplayBackend {
+    fixtures: PathBuf,
+    upstream: Option<Box<dyn CompletionBackend>>,
+}
+
+impl ReplayBackend {
+    fn fixture_path(&self, request: &CompletionRequest) -> PathBuf {
+        let mut key = fnv1a(request.mode.as_bytes(), FNV_OFFSET_BASIS);
+        key = fnv1a(&[0], key);
+        key = fnv1a(request.structure.as_bytes(), key);
+        key = fnv1a(&[0], key);
+        key = fnv1a(request.prompt.as_bytes(), key);
+
+        let structure: String = request
+            .structure
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let file_name = if structure.is_empty() {
+            format!("{}-{:016x}.txt", request.mode, key)
+        } else {
+            format!("{}-{}-{:016x}.txt", request.mode, structure, key)
+        };
+        self.fixtures.join(file_name)
+    }
+}
+
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// The 64-bit FNV-1a hash, which unlike `DefaultHasher` is stable across Rust
+/// releases, so fixture names and the hashes in the job state do not change.
+pub fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl CompletionBackend for ReplayBackend {
+    fn name(&self) -> &str {
+        if self.upstream.is_some() {
+            "record"
+        } else {
+            "replay"
+        }
+    }
+
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String> {
+        let fixture_path = self.fixture_path(request);
+
+        match &self.upstream {
+            Some(upstream) => {
+                let response = upstream.complete(request)?;
+                eprintln!("Recording the response in {}", fixture_path.display());
+                fs::create_dir_all(&self.fixtures)?;
+                fs::write(&fixture_path, &response)?;
+                Ok(response)
+            }
+            None => {
+                eprintln!("Replaying the response from {}", fixture_path.display());
+                fs::read_to_string(&fixture_path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("no recorded response in {}: {}", fixture_path.display(), e))
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! The message of the commit made once an edit passed validation.
+//!
+//! The commit flow is asked for a subject line only, from the diff of the
+//! edit. The rest of the message comes from `commit_template`, so it always
+//! describes the run even when the flow answers something unusable.
+
+use std::path::{Path, PathBuf};
+use regex::Regex;
+
+use crate::backend::{CompletionBackend, CompletionRequest};
+use crate::config::CommitStyle;
+use crate::template;
+
+/// The message template used when `commit_template` is unset.
+pub const DEFAULT_TEMPLATE: &str = "\
+{subject}
+
+{#if structure_name}Edited {structure_name} in {file_path}{#else}Edited {file_path}{/if} with the {mode} mode.
+
+Request:
+{user_request}
+
+Validation: {validation}";
+
+/// Diffs longer than this are cut before they are sent to the commit flow.
+const MAX_DIFF_LENGTH: usize = 20_000;
+
+/// What a commit message is written from.
+pub struct Details<'a> {
+    pub file_path: &'a str,
+    pub mode: &'a str,
+    pub structure_name: &'a str,
+    pub user_request: &'a str,
+    /// The diff of the edit, as `git diff` prints it.
+    pub diff: &'a str,
+    /// A one-line summary of the validation report.
+    pub validation: &'a str,
+}
+
+/// Writes the commit message of an edit: asks the commit flow for the subject,
+/// falling back to a generated one, then renders the message template.
+pub fn generate(
+    backend: &dyn CompletionBackend,
+    flow: &str,
+    style: CommitStyle,
+    message_template: Option<&str>,
+    prompt_dir: Option<&str>,
+    details: &Details,
+) -> String {
+    let (commit_type, scope) = conventional_prefix(details);
+    eprintln!("Generating the commit message using {}...", backend.name());
+
+    let subject = match backend.complete(&CompletionRequest {
+        mode: details.mode,
+        structure: details.structure_name,
+        flow,
+        prompt: &prompt(style, details, commit_type, &scope),
+        user_request: details.user_request,
+        context_file: None,
+        code_only: false,
+    }) {
+        Ok(response) => {
+            eprintln!("Response from {}: {}", backend.name(), response);
+            clean_subject(&response)
+        }
+        Err(e) => {
+            eprintln!("Failed to generate the commit message: {}", e);
+            None
+        }
+    };
+    let subject = subject.unwrap_or_else(|| {
+        let subject = fallback_subject(details);
+        eprintln!("Using a generated subject instead: {}", subject);
+        subject
+    });
+    let subject = match style {
+        CommitStyle::Conventional if !is_conventional(&subject) => format!("{}({}): {}", commit_type, scope, subject),
+        _ => subject,
+    };
+
+    let mut context = template::Context::new(prompt_dir.map(PathBuf::from));
+    context.insert("subject", subject.as_str());
+    context.insert("type", commit_type);
+    context.insert("scope", scope.as_str());
+    context.insert("mode", details.mode);
+    context.insert("file_path", details.file_path);
+    context.insert("structure_name", details.structure_name);
+    context.insert("user_request", details.user_request.trim());
+    context.insert("validation", details.validation);
+    context.insert("diff", details.diff);
+    match template::render(message_template.unwrap_or(DEFAULT_TEMPLATE), &context) {
+        Ok(message) if !message.trim().is_empty() => message.trim().to_string(),
+        Ok(_) => subject,
+        Err(e) => {
+            eprintln!("Error rendering the commit template: {}", e);
+            subject
+        }
+    }
+}
+
+/// The request sent to the commit flow.
+fn prompt(style: CommitStyle, details: &Details, commit_type: &str, scope: &str) -> String {
+    let mut prompt = String::from(
+        "Write the subject line of a git commit message for the change below: a single line of at most 72 \
+         characters in the imperative mood, describing what the change does. Answer with that line only.\n",
+    );
+    if style == CommitStyle::Conventional {
+        prompt.push_str(&format!(
+            "Use the Conventional Commits format `<type>(<scope>): <summary>`, where the type is one of feat, fix, \
+             refactor, docs, test, perf or chore, such as `{}({}): ...`.\n",
+            commit_type, scope
+        ));
+    }
+    prompt.push_str(&format!("\nFile: {}\nMode: {}\n", details.file_path, details.mode));
+    if !details.structure_name.is_empty() {
+        prompt.push_str(&format!("Structure: {}\n", details.structure_name));
+    }
+    prompt.push_str(&format!("Request: {}\nValidation: {}\n\n```diff\n", details.user_request.trim(), details.validation));
+    match details.diff.char_indices().nth(MAX_DIFF_LENGTH) {
+        Some((cut, _)) => {
+            prompt.push_str(&details.diff[..cut]);
+            prompt.push_str("\n[diff truncated]\n");
+        }
+        None => prompt.push_str(details.diff),
+    }
+    prompt.push_str("```\n");
+    prompt
+}
+
+/// The subject in an answer of the commit flow, without code fences or
+/// quotes. Returns `None` when the answer is empty or has several paragraphs,
+/// which is a body or an explanation rather than a subject.
+fn clean_subject(response: &str) -> Option<String> {
+    let lines: Vec<&str> = response.lines().map(str::trim).filter(|line| !line.starts_with("```")).collect();
+    let text = lines.join("\n");
+    let text = text.trim();
+    if text.is_empty() || text.contains("\n\n") {
+        return None;
+    }
+    let subject = lines.iter().filter(|line| !line.is_empty()).copied().collect::<Vec<_>>().join(" ");
+    let subject = subject.trim_matches(|c| c == '"' || c == '\'' || c == '`').trim();
+    if subject.is_empty() {
+        None
+    } else {
+        Some(subject.to_string())
+    }
+}
+
+/// A subject written without the commit flow.
+fn fallback_subject(details: &Details) -> String {
+    let request = details.user_request.lines().map(str::trim).find(|line| !line.is_empty());
+    let target = if details.structure_name.is_empty() { details.file_path } else { details.structure_name };
+    match request {
+        Some(request) => {
+            let request: String = request.chars().take(60).collect();
+            format!("Apply {} to {}: {}", details.mode, target, request.trim_end_matches('.'))
+        }
+        None => format!("Apply {} to {}", details.mode, target),
+    }
+}
+
+/// The Conventional Commits type suggested by the mode, and the file name as
+/// the scope.
+fn conventional_prefix(details: &Details) -> (&'static str, String) {
+    let commit_type = match details.mode {
+        "add_functionality" => "feat",
+        "add_tests_function" => "test",
+        "documentation_structure" | "documentation_whole_file" => "docs",
+        "improvement" | "whole_file" => "refactor",
+        _ => "chore",
+    };
+    let scope = Path::new(details.file_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    (commit_type, scope)
+}
+
+fn is_conventional(subject: &str) -> bool {
+    let pattern = Regex::new(r"^[a-z]+(\([^)]*\))?!?: \S").expect("Invalid Conventional Commits pattern");
+    pattern.is_match(subject)
+}
@@ -0,0 +1,644 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+use crate::language::LanguageSpec;
+
+/// Name of the project-local configuration file looked up next to the edited file.
+pub const PROJECT_CONFIG_FILE: &str = ".rfcu.toml";
+
+#[derive(Deserialize)]
+pub struct Settings {
+    pub flowname: String,
+    pub commit_message_flow: String,
+    pub documentation_flow: String,
+    /// Forces a language for every file. When unset or `auto`, the language is
+    /// detected from the file extension.
+    pub language: Option<String>,
+    /// Maps extra file extensions to language names.
+    #[serde(default)]
+    pub language_extensions: BTreeMap<String, String>,
+    #[serde(default)]
+    pub requests: Requests,
+    pub lint_command: Option<String>,
+    pub max_retries: usize,
+    /// How many requests may be waiting on the backend at once, across every
+    /// `rfcu` process of the repository.
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    /// How many requests may be sent to the backend in a minute. Unlimited
+    /// when unset.
+    pub requests_per_minute: Option<u32>,
+    #[serde(default)]
+    pub modes: BTreeMap<String, ModeConfig>,
+    /// Directory that relative `{>include}` paths in templates are resolved from.
+    pub prompt_dir: Option<String>,
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Ordered `[[validation]]` stages. When empty, see [`Settings::validation_stages`].
+    #[serde(default)]
+    pub validation: Vec<ValidationStage>,
+    #[serde(default)]
+    pub commit: CommitPolicy,
+    #[serde(default)]
+    pub branch_strategy: BranchStrategy,
+    #[serde(default)]
+    pub commit_style: CommitStyle,
+    /// The template of commit messages, with the [`COMMIT_PLACEHOLDERS`]. When
+    /// unset, the subject is followed by a body describing the run.
+    pub commit_template: Option<String>,
+}
+
+fn default_max_concurrency() -> usize {
+    4
+}
+
+/// How the subject line of a commit message is written.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitStyle {
+    /// A summary of the change.
+    #[default]
+    Plain,
+    /// A Conventional Commits summary, such as `refactor(parser): ...`.
+    Conventional,
+}
+
+/// Which branch an edit is committed on.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchStrategy {
+    /// The checked out branch.
+    #[default]
+    Current,
+    /// A new `rfcu/<mode>/<structure>-<timestamp>` branch, leaving the checked
+    /// out branch as it was.
+    New,
+}
+
+/// What happens in git once an edit passed validation.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitPolicy {
+    /// Leave the edit unstaged.
+    Never,
+    /// Stage the edited file with `git add`.
+    Stage,
+    /// Stage the edited file and commit it with a generated message.
+    #[default]
+    Always,
+}
+
+impl CommitPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommitPolicy::Never => "never",
+            CommitPolicy::Stage => "stage",
+            CommitPolicy::Always => "always",
+        }
+    }
+}
+
+/// A check the edited file must pass, from a `[[validation]]` table.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationStage {
+    pub stage: StageKind,
+    /// Name shown in the report. Defaults to the stage kind.
+    pub name: Option<String>,
+    /// The shell command of a `command` stage.
+    pub command: Option<String>,
+    /// Extra arguments for `cargo_check` and `cargo_test`.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Kills the stage and fails it after this many seconds.
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+impl ValidationStage {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.stage.name())
+    }
+}
+
+/// What a validation stage runs.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StageKind {
+    /// Re-parse the edited code and reject syntax errors, before the file is written.
+    Parse,
+    /// `cargo check --message-format=json`.
+    CargoCheck,
+    /// `cargo test`, filtered by the name of the edited structure.
+    CargoTest,
+    /// A shell command, failing on a non-zero exit status.
+    Command,
+}
+
+impl StageKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StageKind::Parse => "parse",
+            StageKind::CargoCheck => "cargo_check",
+            StageKind::CargoTest => "cargo_test",
+            StageKind::Command => "command",
+        }
+    }
+}
+
+/// What a failing validation stage does to the attempt.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Reject the attempt and retry.
+    #[default]
+    Required,
+    /// Report the failure and carry on.
+    Advisory,
+}
+
+/// The LLM backend requests are sent to, from the `[backend]` table.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackendConfig {
+    /// The FluentCLI binary, which runs the configured flows.
+    Fluent {
+        #[serde(default = "default_fluent_binary")]
+        binary: String,
+    },
+    /// A shell command that reads the prompt on stdin and answers on stdout.
+    Command { command: String },
+    /// An OpenAI-compatible chat completions API.
+    Openai {
+        /// Base URL of the API, e.g. `http://localhost:11434/v1`.
+        url: String,
+        model: String,
+        /// Environment variable holding the API key, if the server needs one.
+        api_key_env: Option<String>,
+        system_prompt: Option<String>,
+        temperature: Option<f64>,
+        timeout_secs: Option<u64>,
+    },
+    /// Answers from files in `fixtures`, without calling any LLM.
+    Replay { fixtures: String },
+    /// Sends requests to `upstream` and saves its answers in `fixtures` for `replay`.
+    Record {
+        fixtures: String,
+        upstream: Box<BackendConfig>,
+    },
+}
+
+fn default_fluent_binary() -> String {
+    "fluent".to_string()
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig::Fluent { binary: default_fluent_binary() }
+    }
+}
+
+/// Templates for the built-in modes. A template left out falls back to the
+/// default template of the file's language.
+#[derive(Deserialize, Default)]
+pub struct Requests {
+    pub improvement: Option<String>,
+    pub whole_file: Option<String>,
+    pub add_functionality: Option<String>,
+    pub add_tests_function: Option<String>,
+    pub documentation_whole_file: Option<String>,
+    pub documentation_structure: Option<String>,
+}
+
+impl Requests {
+    /// Returns the configured template for `mode`, if any.
+    pub fn template(&self, mode: &str) -> Option<&str> {
+        let template = match mode {
+            "improvement" => &self.improvement,
+            "whole_file" => &self.whole_file,
+            "add_functionality" => &self.add_functionality,
+            "add_tests_function" => &self.add_tests_function,
+            "documentation_whole_file" => &self.documentation_whole_file,
+            "documentation_structure" => &self.documentation_structure,
+            _ => return None,
+        };
+        template.as_deref()
+    }
+}
+
+/// A mode of operation declared in a `[modes.<name>]` table. Fields left out
+/// fall back to the built-in mode of the same name, if there is one.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ModeConfig {
+    pub template: Option<String>,
+    pub scope: Option<Scope>,
+    pub insertion: Option<Insertion>,
+    pub flow: Option<String>,
+}
+
+/// What part of the file a mode sends to the LLM.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// The structure named by `--structure_name`.
+    Structure,
+    /// The whole file.
+    WholeFile,
+    /// The whole file, with the response added after `--structure_name`, after
+    /// `main`, or at the end of the file.
+    Append,
+}
+
+/// How the LLM response is put back into the file.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Insertion {
+    /// Replace the target with the response.
+    Replace,
+    /// Insert the response before the target.
+    InsertBefore,
+    /// Insert the response after the target.
+    InsertAfter,
+    /// Insert the response into the `#[cfg(test)]` module, creating it if needed.
+    AppendToCfgTest,
+    /// Replace the doc comment above the target (or at the top of the file), or
+    /// insert it if there is none.
+    ReplaceDocs,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Structure => "structure",
+            Scope::WholeFile => "whole_file",
+            Scope::Append => "append",
+        }
+    }
+}
+
+impl Insertion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Insertion::Replace => "replace",
+            Insertion::InsertBefore => "insert_before",
+            Insertion::InsertAfter => "insert_after",
+            Insertion::AppendToCfgTest => "append_to_cfg_test",
+            Insertion::ReplaceDocs => "replace_docs",
+        }
+    }
+}
+
+/// A fully resolved mode of operation.
+pub struct Mode {
+    pub name: String,
+    /// The configured template, if any. See [`Mode::template_for`].
+    pub template: Option<String>,
+    pub scope: Scope,
+    pub insertion: Insertion,
+    pub flow: String,
+}
+
+impl Mode {
+    /// Returns the template to render for a file in `language`: the configured
+    /// one, or the language's default template for built-in modes.
+    pub fn template_for(&self, language: &LanguageSpec) -> Option<String> {
+        self.template.clone().or_else(|| language.default_template(&self.name))
+    }
+}
+
+impl Settings {
+    /// Resolves `name` against the `[modes]` table and the built-in modes.
+    pub fn mode(&self, name: &str) -> Option<Mode> {
+        let builtin = self.builtin_mode(name);
+        let declared = self.modes.get(name);
+        if builtin.is_none() && declared.is_none() {
+            return None;
+        }
+
+        let declared = declared.cloned().unwrap_or_default();
+        let builtin = builtin.unwrap_or_else(|| ModeConfig {
+            scope: Some(Scope::Structure),
+            insertion: Some(Insertion::Replace),
+            ..ModeConfig::default()
+        });
+
+        Some(Mode {
+            name: name.to_string(),
+            template: declared.template.or(builtin.template),
+            scope: declared.scope.or(builtin.scope).unwrap_or(Scope::Structure),
+            insertion: declared.insertion.or(builtin.insertion).unwrap_or(Insertion::Replace),
+            flow: declared.flow.or(builtin.flow).unwrap_or_else(|| self.flowname.clone()),
+        })
+    }
+
+    /// Returns the validation stages to run: the `[[validation]]` tables, or a
+    /// `parse` stage followed by `lint_command` as a `lint` stage.
+    pub fn validation_stages(&self) -> Vec<ValidationStage> {
+        if !self.validation.is_empty() {
+            return self.validation.clone();
+        }
+
+        let stage = |kind: StageKind, name: Option<&str>, command: Option<&String>| ValidationStage {
+            stage: kind,
+            name: name.map(str::to_string),
+            command: command.cloned(),
+            args: Vec::new(),
+            timeout_secs: None,
+            policy: Policy::Required,
+        };
+        let mut stages = vec![stage(StageKind::Parse, None, None)];
+        if let Some(lint_command) = &self.lint_command {
+            stages.push(stage(StageKind::Command, Some("lint"), Some(lint_command)));
+        }
+        stages
+    }
+
+    /// Returns the names of every available mode, built-in ones first.
+    pub fn mode_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = MODES.iter().map(|mode| mode.to_string()).collect();
+        names.extend(self.modes.keys().filter(|name| !MODES.contains(&name.as_str())).cloned());
+        names
+    }
+
+    fn builtin_mode(&self, name: &str) -> Option<ModeConfig> {
+        let (scope, insertion, flow) = match name {
+            "improvement" => (Scope::Structure, Insertion::Replace, None),
+            "add_tests_function" => (Scope::Structure, Insertion::InsertAfter, None),
+            "documentation_structure" => (Scope::Structure, Insertion::ReplaceDocs, Some(&self.documentation_flow)),
+            "whole_file" => (Scope::WholeFile, Insertion::Replace, None),
+            "documentation_whole_file" => (Scope::WholeFile, Insertion::ReplaceDocs, Some(&self.documentation_flow)),
+            "add_functionality" => (Scope::Append, Insertion::InsertAfter, None),
+            _ => return None,
+        };
+
+        Some(ModeConfig {
+            template: self.requests.template(name).map(str::to_string),
+            scope: Some(scope),
+            insertion: Some(insertion),
+            flow: flow.cloned(),
+        })
+    }
+}
+
+/// Keys accepted at the top level of a configuration file and inside a profile.
+pub const SETTINGS_KEYS: &[&str] = &[
+    "flowname",
+    "commit_message_flow",
+    "documentation_flow",
+    "language",
+    "language_extensions",
+    "requests",
+    "lint_command",
+    "max_retries",
+    "max_concurrency",
+    "requests_per_minute",
+    "modes",
+    "prompt_dir",
+    "backend",
+    "validation",
+    "commit",
+    "branch_strategy",
+    "commit_style",
+    "commit_template",
+];
+
+/// Tables that are only valid at the top level of a configuration file.
+pub const TOP_LEVEL_TABLES: &[&str] = &["profiles", "mode_profiles"];
+
+/// The built-in modes of operation, which are also the keys of the `[requests]` table.
+pub const MODES: &[&str] = &[
+    "improvement",
+    "add_functionality",
+    "add_tests_function",
+    "documentation_whole_file",
+    "documentation_structure",
+    "whole_file",
+];
+
+/// Placeholders that are substituted into the request templates.
+pub const PLACEHOLDERS: &[&str] = &[
+    "structure_code",
+    "source_code",
+    "user_request",
+    "structure_name",
+    "file_path",
+    "language",
+    "crate_name",
+    "impl_type",
+    "sibling_signatures",
+    "previous_errors",
+];
+
+/// Placeholders available to `commit_template`.
+pub const COMMIT_PLACEHOLDERS: &[&str] = &[
+    "subject",
+    "type",
+    "scope",
+    "mode",
+    "file_path",
+    "structure_name",
+    "user_request",
+    "validation",
+    "diff",
+];
+
+/// List placeholders usable with `{#each}`, with the fields of their items.
+pub const LIST_PLACEHOLDERS: &[(&str, &[&str])] = &[("siblings", &["name", "kind", "signature"])];
+
+/// Returns the placeholders a template must contain for a mode with `scope` to make sense.
+pub fn required_placeholders(scope: Scope) -> &'static [&'static str] {
+    match scope {
+        Scope::Structure => &["structure_code"],
+        Scope::WholeFile | Scope::Append => &["source_code"],
+    }
+}
+
+/// Returns the configuration files to load, lowest precedence first.
+///
+/// The user-level file comes from `--config`, then `RFCU_CONFIG`, then
+/// `$XDG_CONFIG_HOME/rfcu/config.toml`, then `~/.rfcu/config.toml`. Every
+/// `.rfcu.toml` found walking up from the edited file is layered on top of it,
+/// the one closest to the file last.
+pub fn discover_config_files(config_flag: Option<&str>, file_path: Option<&str>) -> io::Result<Vec<PathBuf>> {
+    let mut config_files = Vec::new();
+
+    if let Some(user_config) = user_config_path(config_flag)? {
+        config_files.push(user_config);
+    }
+
+    if let Some(file_path) = file_path {
+        config_files.extend(project_config_paths(Path::new(file_path)));
+    }
+
+    if config_files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No configuration file found. Pass --config, set RFCU_CONFIG or create ~/.rfcu/config.toml",
+        ));
+    }
+
+    Ok(config_files)
+}
+
+fn user_config_path(config_flag: Option<&str>) -> io::Result<Option<PathBuf>> {
+    if let Some(path) = config_flag {
+        return explicit_config_path(path, "--config");
+    }
+
+    if let Ok(path) = env::var("RFCU_CONFIG") {
+        if !path.is_empty() {
+            return explicit_config_path(&path, "RFCU_CONFIG");
+        }
+    }
+
+    if let Ok(xdg_config_home) = env::var("XDG_CONFIG_HOME") {
+        let path = Path::new(&xdg_config_home).join("rfcu").join("config.toml");
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+    }
+
+    if let Ok(home) = env::var("HOME") {
+        let path = Path::new(&home).join(".rfcu").join("config.toml");
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+    }
+
+    Ok(None)
+}
+
+fn explicit_config_path(path: &str, source: &str) -> io::Result<Option<PathBuf>> {
+    let path = PathBuf::from(path);
+    if !path.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Config file from {} does not exist: {}", source, path.display()),
+        ));
+    }
+    Ok(Some(path))
+}
+
+fn project_config_paths(file_path: &Path) -> Vec<PathBuf> {
+    let file_path = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+    let start_dir = if file_path.is_dir() {
+        file_path.as_path()
+    } else {
+        file_path.parent().unwrap_or(Path::new("."))
+    };
+
+    let mut paths: Vec<PathBuf> = start_dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.reverse();
+    paths
+}
+
+/// Merges `overlay` into `base`. Tables are merged key by key, any other value
+/// in `overlay` replaces the one in `base`. A table that sets another `type`,
+/// such as a `[backend]` switching from `command` to `http`, replaces the base
+/// table whole, as the other fields of the base belong to its previous type.
+pub fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base_table), toml::Value::Table(overlay_table)) => {
+            if matches!((base_table.get("type"), overlay_table.get("type")), (Some(base_type), Some(overlay_type)) if base_type != overlay_type) {
+                *base_table = overlay_table;
+                return;
+            }
+            for (key, value) in overlay_table {
+                match base_table.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base_table.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Picks the profile for this run: the one passed with `--profile`, otherwise
+/// the default named for `mode` in `[mode_profiles]`, otherwise none.
+fn select_profile(merged: &toml::Value, profile_flag: Option<&str>, mode: Option<&str>) -> Option<String> {
+    if let Some(profile) = profile_flag {
+        return Some(profile.to_string());
+    }
+
+    let mode = mode?;
+    merged
+        .get("mode_profiles")
+        .and_then(|mode_profiles| mode_profiles.get(mode))
+        .and_then(|profile| profile.as_str())
+        .map(str::to_string)
+}
+
+/// Applies `[profiles.<name>]` on top of the merged configuration. The
+/// `profiles` and `mode_profiles` tables are removed once they have been used.
+pub fn apply_profile(merged: &mut toml::Value, profile_flag: Option<&str>, mode: Option<&str>) -> io::Result<()> {
+    let profile_name = select_profile(merged, profile_flag, mode);
+
+    let table = match merged.as_table_mut() {
+        Some(table) => table,
+        None => return Ok(()),
+    };
+    let profiles = table.remove("profiles");
+    table.remove("mode_profiles");
+
+    let profile_name = match profile_name {
+        Some(profile_name) => profile_name,
+        None => return Ok(()),
+    };
+
+    let profile = profiles
+        .as_ref()
+        .and_then(|profiles| profiles.get(&profile_name))
+        .cloned()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Profile '{}' is not defined in any [profiles] table", profile_name),
+            )
+        })?;
+
+    eprintln!("Using profile: {}", profile_name);
+    merge_values(merged, profile);
+    Ok(())
+}
+
+/// Reads every configuration layer and merges them into a single [`Settings`],
+/// then applies the selected profile, if any.
+pub fn load_settings(
+    config_flag: Option<&str>,
+    file_path: Option<&str>,
+    profile_flag: Option<&str>,
+    mode: Option<&str>,
+) -> io::Result<Settings> {
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+
+    for config_file in discover_config_files(config_flag, file_path)? {
+        eprintln!("Reading configuration file: {}", config_file.display());
+        let config_content = fs::read_to_string(&config_file)?;
+        let layer: toml::Value = toml::from_str(&config_content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse config file {}: {}", config_file.display(), e),
+            )
+        })?;
+        merge_values(&mut merged, layer);
+    }
+
+    apply_profile(&mut merged, profile_flag, mode)?;
+
+    merged
+        .try_into()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid configuration: {}", e)))
+}
@@ -0,0 +1,609 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+
+use crate::config::{self, BackendConfig, Settings, StageKind, ValidationStage};
+use crate::language;
+use crate::template::{self, Node, Position};
+
+#[derive(PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Error, location: location.into(), message: message.into() }
+    }
+
+    fn warning(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Diagnostic { severity: Severity::Warning, location: location.into(), message: message.into() }
+    }
+}
+
+/// Checks every configuration layer and the merged result, returning all problems found.
+pub fn check_config(config_flag: Option<&str>, file_path: Option<&str>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let config_files = match config::discover_config_files(config_flag, file_path) {
+        Ok(config_files) => config_files,
+        Err(e) => {
+            diagnostics.push(Diagnostic::error("config", e.to_string()));
+            return diagnostics;
+        }
+    };
+
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    let mut parse_failed = false;
+    for config_file in &config_files {
+        eprintln!("Checking configuration file: {}", config_file.display());
+        match check_layer(config_file, &mut diagnostics) {
+            Some(layer) => config::merge_values(&mut merged, layer),
+            None => parse_failed = true,
+        }
+    }
+
+    if parse_failed {
+        return diagnostics;
+    }
+
+    check_merged(&merged, None, &mut diagnostics);
+
+    let profile_names: Vec<String> = merged
+        .get("profiles")
+        .and_then(|profiles| profiles.as_table())
+        .map(|profiles| profiles.keys().cloned().collect())
+        .unwrap_or_default();
+    for profile_name in profile_names {
+        let mut profiled = merged.clone();
+        if let Err(e) = config::apply_profile(&mut profiled, Some(&profile_name), None) {
+            diagnostics.push(Diagnostic::error(format!("profiles.{}", profile_name), e.to_string()));
+            continue;
+        }
+        let profile = merged.get("profiles").and_then(|profiles| profiles.get(&profile_name));
+        check_merged(&profiled, profile.map(|profile| (profile_name.as_str(), profile)), &mut diagnostics);
+    }
+
+    if let Some(mode_profiles) = merged.get("mode_profiles").and_then(|m| m.as_table()) {
+        for (mode, profile) in mode_profiles {
+            let location = format!("mode_profiles.{}", mode);
+            let declared = merged.get("modes").and_then(|modes| modes.get(mode)).is_some();
+            if !config::MODES.contains(&mode.as_str()) && !declared {
+                diagnostics.push(Diagnostic::warning(&location, format!("unknown mode '{}'", mode)));
+            }
+            let defined = profile
+                .as_str()
+                .and_then(|profile| merged.get("profiles").and_then(|profiles| profiles.get(profile)))
+                .is_some();
+            if !defined {
+                diagnostics.push(Diagnostic::error(&location, format!("profile {} is not defined", profile)));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Parses a single layer, reporting syntax errors with their line and column and
+/// flagging keys that RFCU does not know about.
+fn check_layer(config_file: &Path, diagnostics: &mut Vec<Diagnostic>) -> Option<toml::Value> {
+    let location = config_file.display().to_string();
+    let config_content = match fs::read_to_string(config_file) {
+        Ok(config_content) => config_content,
+        Err(e) => {
+            diagnostics.push(Diagnostic::error(location, format!("failed to read file: {}", e)));
+            return None;
+        }
+    };
+
+    let layer: toml::Value = match toml::from_str(&config_content) {
+        Ok(layer) => layer,
+        Err(e) => {
+            let location = match e.line_col() {
+                Some((line, col)) => format!("{}:{}:{}", location, line + 1, col + 1),
+                None => location,
+            };
+            diagnostics.push(Diagnostic::error(location, e.to_string()));
+            return None;
+        }
+    };
+
+    let table = layer.as_table()?;
+    for (key, value) in table {
+        let key_location = key_location(config_file, &config_content, key);
+        match key.as_str() {
+            "profiles" => {
+                for (profile_name, profile) in value.as_table().into_iter().flatten() {
+                    for (profile_key, profile_value) in profile.as_table().into_iter().flatten() {
+                        let location = key_location_in(config_file, &config_content, &format!("profiles.{}", profile_name), profile_key);
+                        check_settings_key(profile_key, profile_value, &location, &config_content, config_file, diagnostics);
+                    }
+                }
+            }
+            "mode_profiles" => {}
+            _ => check_settings_key(key, value, &key_location, &config_content, config_file, diagnostics),
+        }
+    }
+
+    Some(layer)
+}
+
+fn check_settings_key(
+    key: &str,
+    value: &toml::Value,
+    location: &str,
+    config_content: &str,
+    config_file: &Path,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if !config::SETTINGS_KEYS.contains(&key) {
+        diagnostics.push(Diagnostic::warning(location, format!("unknown key '{}'", key)));
+        return;
+    }
+
+    if key == "requests" {
+        for request_key in value.as_table().into_iter().flatten().map(|(k, _)| k) {
+            if !config::MODES.contains(&request_key.as_str()) {
+                let location = key_location_in(config_file, config_content, "requests", request_key);
+                diagnostics.push(Diagnostic::warning(location, format!("unknown request template '{}'", request_key)));
+            }
+        }
+    }
+}
+
+/// Checks the fully merged configuration (optionally with a profile applied):
+/// required fields, template placeholders and external commands.
+fn check_merged(merged: &toml::Value, profile: Option<(&str, &toml::Value)>, diagnostics: &mut Vec<Diagnostic>) {
+    let scope = match profile {
+        Some((profile_name, _)) => format!("profile {}", profile_name),
+        None => "merged configuration".to_string(),
+    };
+
+    let mut stripped = merged.clone();
+    if let Some(table) = stripped.as_table_mut() {
+        for top_level_table in config::TOP_LEVEL_TABLES {
+            table.remove(*top_level_table);
+        }
+    }
+
+    let settings: Settings = match stripped.try_into() {
+        Ok(settings) => settings,
+        Err(e) => {
+            diagnostics.push(Diagnostic::error(scope, e.to_string()));
+            return;
+        }
+    };
+
+    // Templates and commands of the base configuration are inherited by every
+    // profile, so only report them again when the profile changes them.
+    let profile = profile.map(|(_, profile)| profile);
+    let overrides = |key: &str| profile.is_none_or(|profile| profile.get(key).is_some());
+
+    for mode_name in settings.mode_names() {
+        let overridden = profile.is_none_or(|profile| {
+            profile.get("requests").and_then(|requests| requests.get(&mode_name)).is_some()
+                || profile.get("modes").and_then(|modes| modes.get(&mode_name)).is_some()
+        });
+        if !overridden {
+            continue;
+        }
+
+        let mode = match settings.mode(&mode_name) {
+            Some(mode) => mode,
+            None => continue,
+        };
+        let location = format!("{}: mode {}", scope, mode_name);
+        let template = match &mode.template {
+            Some(template) => template,
+            None if config::MODES.contains(&mode_name.as_str()) => continue,
+            None => {
+                diagnostics.push(Diagnostic::error(&location, "mode has no template"));
+                continue;
+            }
+        };
+
+        let include_dir = settings.prompt_dir.as_deref().map(Path::new);
+        let mut used = Vec::new();
+        if !check_template(template, &location, include_dir, &[], 0, &mut used, diagnostics) {
+            continue;
+        }
+
+        for required in config::required_placeholders(mode.scope) {
+            if !used.iter().any(|name| name == required) {
+                diagnostics.push(Diagnostic::error(
+                    &location,
+                    format!("template is missing the {{{}}} placeholder required by the {} scope", required, mode.scope.name()),
+                ));
+            }
+        }
+    }
+
+    if overrides("max_concurrency") && settings.max_concurrency == 0 {
+        diagnostics.push(Diagnostic::error(format!("{}: max_concurrency", scope), "must be at least 1"));
+    }
+    if overrides("requests_per_minute") && settings.requests_per_minute == Some(0) {
+        diagnostics.push(Diagnostic::error(format!("{}: requests_per_minute", scope), "must be at least 1, or unset for no limit"));
+    }
+
+    if overrides("commit_template") {
+        if let Some(commit_template) = &settings.commit_template {
+            check_commit_template(commit_template, &format!("{}: commit_template", scope), diagnostics);
+        }
+    }
+
+    if overrides("language") {
+        if let Some(name) = settings.language.as_deref().filter(|name| *name != "auto") {
+            if language::by_name(name).is_none() {
+                diagnostics.push(Diagnostic::error(format!("{}: language", scope), format!("unsupported language '{}'", name)));
+            }
+        }
+    }
+
+    if overrides("language_extensions") {
+        for (extension, name) in &settings.language_extensions {
+            if language::by_name(name).is_none() {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}: language_extensions.{}", scope, extension),
+                    format!("unsupported language '{}'", name),
+                ));
+            }
+        }
+    }
+
+    if overrides("lint_command") {
+        if let Some(lint_command) = &settings.lint_command {
+            for program in command_programs(lint_command) {
+                if find_on_path(&program).is_none() {
+                    diagnostics.push(Diagnostic::error(
+                        format!("{}: lint_command", scope),
+                        format!("'{}' was not found on PATH", program),
+                    ));
+                }
+            }
+        }
+    }
+
+    if overrides("backend") {
+        check_backend(&settings.backend, &format!("{}: backend", scope), diagnostics);
+    }
+
+    if overrides("validation") {
+        for (index, stage) in settings.validation.iter().enumerate() {
+            check_validation_stage(stage, &format!("{}: validation[{}] ({})", scope, index, stage.name()), diagnostics);
+        }
+    }
+}
+
+/// Checks that a validation stage has the command it needs and that the
+/// programs it runs can be found.
+fn check_validation_stage(stage: &ValidationStage, location: &str, diagnostics: &mut Vec<Diagnostic>) {
+    if matches!(stage.stage, StageKind::Parse | StageKind::Command) && !stage.args.is_empty() {
+        diagnostics.push(Diagnostic::warning(location, format!("args are ignored by {} stages", stage.stage.name())));
+    }
+    if stage.timeout_secs == Some(0) {
+        diagnostics.push(Diagnostic::error(location, "timeout_secs must be greater than 0"));
+    }
+
+    let programs = match (stage.stage, &stage.command) {
+        (StageKind::Command, Some(command)) => command_programs(command),
+        (StageKind::Command, None) => {
+            diagnostics.push(Diagnostic::error(location, "a command stage needs a command"));
+            return;
+        }
+        (_, Some(_)) => {
+            diagnostics.push(Diagnostic::warning(location, format!("command is ignored by {} stages", stage.stage.name())));
+            Vec::new()
+        }
+        (StageKind::CargoCheck | StageKind::CargoTest, None) => vec!["cargo".to_string()],
+        (StageKind::Parse, None) => Vec::new(),
+    };
+
+    for program in programs {
+        if find_on_path(&program).is_none() {
+            diagnostics.push(Diagnostic::error(location, format!("'{}' was not found on PATH", program)));
+        }
+    }
+}
+
+/// Checks that the programs a backend runs can be found, and that an HTTP
+/// backend has a usable URL and API key.
+fn check_backend(backend: &BackendConfig, location: &str, diagnostics: &mut Vec<Diagnostic>) {
+    match backend {
+        BackendConfig::Fluent { binary } => {
+            if find_on_path(binary).is_none() {
+                diagnostics.push(Diagnostic::error(location, format!("the '{}' binary was not found on PATH", binary)));
+            }
+        }
+        BackendConfig::Command { command } => {
+            for program in command_programs(command) {
+                if find_on_path(&program).is_none() {
+                    diagnostics.push(Diagnostic::error(location, format!("'{}' was not found on PATH", program)));
+                }
+            }
+        }
+        BackendConfig::Openai { url, api_key_env, .. } => {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                diagnostics.push(Diagnostic::error(location, format!("url '{}' is not an http:// or https:// URL", url)));
+            }
+            if let Some(api_key_env) = api_key_env {
+                if env::var_os(api_key_env).is_none() {
+                    diagnostics.push(Diagnostic::warning(
+                        location,
+                        format!("the {} environment variable is not set", api_key_env),
+                    ));
+                }
+            }
+        }
+        BackendConfig::Replay { fixtures } => {
+            if !Path::new(fixtures).is_dir() {
+                diagnostics.push(Diagnostic::warning(location, format!("the fixtures directory '{}' does not exist", fixtures)));
+            }
+        }
+        BackendConfig::Record { upstream, .. } => check_backend(upstream, &format!("{}.upstream", location), diagnostics),
+    }
+}
+
+/// Parses a template (and the fragments it includes), reporting syntax errors,
+/// unknown placeholders and missing includes. Every placeholder used is added to
+/// `used`. Returns `false` when the template itself cannot be parsed.
+fn check_template(
+    template: &str,
+    location: &str,
+    include_dir: Option<&Path>,
+    loop_fields: &[&str],
+    depth: usize,
+    used: &mut Vec<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> bool {
+    match template::parse(template) {
+        Ok(nodes) => {
+            check_nodes(&nodes, location, include_dir, loop_fields, depth, used, diagnostics);
+            true
+        }
+        Err(e) => {
+            diagnostics.push(Diagnostic::error(location, format!("template syntax error: {}", e)));
+            false
+        }
+    }
+}
+
+fn check_nodes(
+    nodes: &[Node],
+    location: &str,
+    include_dir: Option<&Path>,
+    loop_fields: &[&str],
+    depth: usize,
+    used: &mut Vec<String>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let is_known = |name: &str| config::PLACEHOLDERS.contains(&name) || loop_fields.contains(&name) || list_fields(name).is_some();
+    let check_name = |name: &str, position: &Position, diagnostics: &mut Vec<Diagnostic>| {
+        if !is_known(name) {
+            diagnostics.push(Diagnostic::error(
+                location,
+                format!(
+                    "unknown placeholder {{{}}} at line {} column {}, expected one of {}",
+                    name,
+                    position.line,
+                    position.column,
+                    placeholder_list()
+                ),
+            ));
+        }
+    };
+
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Variable(name, position) => {
+                // Unknown names are sent as literal text, which is how code
+                // such as `format!("{x}")` reads; it may also be a typo.
+                if !is_known(name) {
+                    diagnostics.push(Diagnostic::warning(
+                        location,
+                        format!(
+                            "{{{}}} at line {} column {} is not a placeholder and is sent as is; the placeholders are {}",
+                            name,
+                            position.line,
+                            position.column,
+                            placeholder_list()
+                        ),
+                    ));
+                }
+                used.push(name.clone());
+            }
+            Node::If { name, then, otherwise, position } => {
+                check_name(name, position, diagnostics);
+                used.push(name.clone());
+                check_nodes(then, location, include_dir, loop_fields, depth, used, diagnostics);
+                check_nodes(otherwise, location, include_dir, loop_fields, depth, used, diagnostics);
+            }
+            Node::Each { name, body, position } => {
+                used.push(name.clone());
+                match list_fields(name) {
+                    Some(fields) => {
+                        let mut fields = fields.to_vec();
+                        fields.push("index");
+                        fields.extend_from_slice(loop_fields);
+                        check_nodes(body, location, include_dir, &fields, depth, used, diagnostics);
+                    }
+                    None => diagnostics.push(Diagnostic::error(
+                        location,
+                        format!(
+                            "{{#each {}}} at line {} column {} does not name a list placeholder",
+                            name, position.line, position.column
+                        ),
+                    )),
+                }
+            }
+            Node::Include(path, position) => {
+                let include_path = template::resolve_include(include_dir, path);
+                let fragment = match fs::read_to_string(&include_path) {
+                    Ok(fragment) => fragment,
+                    Err(e) => {
+                        diagnostics.push(Diagnostic::error(
+                            location,
+                            format!(
+                                "cannot include {} at line {} column {}: {}",
+                                include_path.display(),
+                                position.line,
+                                position.column,
+                                e
+                            ),
+                        ));
+                        continue;
+                    }
+                };
+                if depth < template::MAX_INCLUDE_DEPTH {
+                    let location = format!("{} (included from {})", include_path.display(), location);
+                    check_template(&fragment, &location, include_dir, loop_fields, depth + 1, used, diagnostics);
+                }
+            }
+        }
+    }
+}
+
+/// Reports syntax errors and placeholders other than the
+/// [`config::COMMIT_PLACEHOLDERS`] in the commit message template.
+fn check_commit_template(commit_template: &str, location: &str, diagnostics: &mut Vec<Diagnostic>) {
+    fn check(nodes: &[Node], location: &str, diagnostics: &mut Vec<Diagnostic>) {
+        for node in nodes {
+            let (name, position) = match node {
+                Node::Text(_) | Node::Include(..) => continue,
+                Node::Variable(name, position) => (name, position),
+                Node::If { name, then, otherwise, position } => {
+                    check(then, location, diagnostics);
+                    check(otherwise, location, diagnostics);
+                    (name, position)
+                }
+                Node::Each { name, position, .. } => {
+                    diagnostics.push(Diagnostic::error(
+                        location,
+                        format!("{{#each {}}} at line {} column {} does not name a list placeholder", name, position.line, position.column),
+                    ));
+                    continue;
+                }
+            };
+            if !config::COMMIT_PLACEHOLDERS.contains(&name.as_str()) {
+                let expected: Vec<String> = config::COMMIT_PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect();
+                diagnostics.push(Diagnostic::error(
+                    location,
+                    format!(
+                        "unknown placeholder {{{}}} at line {} column {}, expected one of {}",
+                        name,
+                        position.line,
+                        position.column,
+                        expected.join(", ")
+                    ),
+                ));
+            }
+        }
+    }
+
+    match template::parse(commit_template) {
+        Ok(nodes) => check(&nodes, location, diagnostics),
+        Err(e) => diagnostics.push(Diagnostic::error(location, format!("template syntax error: {}", e))),
+    }
+}
+
+fn list_fields(name: &str) -> Option<&'static [&'static str]> {
+    config::LIST_PLACEHOLDERS
+        .iter()
+        .find(|(list, _)| *list == name)
+        .map(|(_, fields)| *fields)
+}
+
+fn placeholder_list() -> String {
+    config::PLACEHOLDERS
+        .iter()
+        .map(|placeholder| format!("{{{}}}", placeholder))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Best-effort location of a top-level key, as `file:line`.
+fn key_location(config_file: &Path, config_content: &str, key: &str) -> String {
+    key_location_in(config_file, config_content, "", key)
+}
+
+/// Best-effort location of `key` inside the `[table]` header, as `file:line`.
+/// Falls back to the bare file name when the key cannot be found.
+fn key_location_in(config_file: &Path, config_content: &str, table: &str, key: &str) -> String {
+    let mut current_table = String::new();
+    for (i, line) in config_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_matches(|c| c == '[' || c == ']').trim();
+            if table.is_empty() && header == key {
+                return format!("{}:{}", config_file.display(), i + 1);
+            }
+            current_table = header.to_string();
+            continue;
+        }
+        if current_table == table {
+            let line_key = trimmed.split('=').next().unwrap_or("").trim().trim_matches('"');
+            if trimmed.contains('=') && line_key == key {
+                return format!("{}:{}", config_file.display(), i + 1);
+            }
+        }
+    }
+    config_file.display().to_string()
+}
+
+/// Shell keywords that come before the program of a command, such as `if`.
+const SHELL_KEYWORDS: &[&str] = &["!", "{", "}", "(", ")", "if", "then", "else", "elif", "fi", "do", "done", "while", "until", "time"];
+
+/// Shell builtins, which run without a program on PATH.
+const SHELL_BUILTINS: &[&str] = &[
+    ":", ".", "[", "[[", "alias", "break", "builtin", "cd", "command", "continue", "declare", "echo", "eval", "exec", "exit",
+    "export", "false", "hash", "local", "printf", "pwd", "read", "readonly", "return", "set", "shift", "source", "test",
+    "trap", "true", "type", "ulimit", "umask", "unalias", "unset", "wait",
+];
+
+/// Returns the programs a shell command line would run, skipping leading
+/// `VAR=value` assignments and keywords, and leaving out shell builtins.
+fn command_programs(command_line: &str) -> Vec<String> {
+    let separators = Regex::new(r"&&|\|\||[;|]").unwrap();
+    separators
+        .split(command_line)
+        .filter_map(|command| {
+            command
+                .split_whitespace()
+                .map(|word| word.trim_start_matches('('))
+                .find(|word| !word.is_empty() && !word.contains('=') && !SHELL_KEYWORDS.contains(word))
+                .filter(|word| !SHELL_BUILTINS.contains(word))
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+pub fn find_on_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return if is_executable(&path) { Some(path) } else { None };
+    }
+
+    let path_var = env::var_os("PATH")?;
+    env::split_paths(&path_var)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
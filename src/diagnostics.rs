@@ -0,0 +1,99 @@
+//! Picks the lint and compiler diagnostics that concern an edit, so they can be
+//! sent back with the next attempt through `{previous_errors}`.
+
+use std::path::Path;
+use regex::Regex;
+
+use crate::diff;
+
+/// Maximum length of the diagnostics added to a prompt.
+const MAX_DIAGNOSTICS_LEN: usize = 8000;
+
+/// Lines around the edited range whose diagnostics are still kept.
+const LINE_MARGIN: usize = 3;
+
+/// Returns the 1-based, inclusive line range of `updated` that differs from
+/// `original`.
+pub fn changed_lines(original: &str, updated: &str) -> (usize, usize) {
+    let prefix = diff::common_prefix(original, updated);
+    let suffix = diff::common_suffix(&original[prefix..], &updated[prefix..]);
+
+    let first = updated[..prefix].matches('\n').count() + 1;
+    let last = updated[..updated.len() - suffix].matches('\n').count() + 1;
+    (first, last.max(first))
+}
+
+/// A diagnostic in the lint output and the lines of `file_path` it points at.
+struct Block<'a> {
+    text: &'a str,
+    lines: Vec<usize>,
+}
+
+fn same_file(location: &str, file_path: &str) -> bool {
+    let location = location.trim_start_matches("./");
+    let file_path = file_path.trim_start_matches("./");
+    Path::new(file_path).ends_with(location) || Path::new(location).ends_with(file_path)
+}
+
+/// Splits lint output into diagnostics: paragraphs such as rustc's, or single
+/// lines for tools that print one `path:line:column: message` per line.
+fn blocks<'a>(output: &'a str, file_path: &str) -> Vec<Block<'a>> {
+    let location_re = Regex::new(r"([^\s:]+):(\d+)(?::\d+)?").unwrap();
+    let lines_in = |text: &str| -> Vec<usize> {
+        location_re
+            .captures_iter(text)
+            .filter(|captures| same_file(&captures[1], file_path))
+            .filter_map(|captures| captures[2].parse().ok())
+            .collect()
+    };
+
+    let mut blocks = Vec::new();
+    for paragraph in output.split("\n\n").map(|paragraph| paragraph.trim_matches(['\n', '\r'])) {
+        if paragraph.trim().is_empty() {
+            continue;
+        }
+        let one_per_line = paragraph.lines().count() > 1 && paragraph.lines().all(|line| location_re.is_match(line));
+        if one_per_line {
+            blocks.extend(paragraph.lines().map(|line| Block { text: line, lines: lines_in(line) }));
+        } else {
+            blocks.push(Block { text: paragraph, lines: lines_in(paragraph) });
+        }
+    }
+    blocks
+}
+
+/// Returns the diagnostics in `output` about the edited lines of `file_path`.
+/// Falls back to the ones about the file, then to the whole output, when none
+/// point at the edit.
+pub fn relevant(output: &str, file_path: &str, edited_lines: (usize, usize)) -> String {
+    let blocks = blocks(output, file_path);
+    let (first, last) = (edited_lines.0.saturating_sub(LINE_MARGIN), edited_lines.1 + LINE_MARGIN);
+
+    let about_edit: Vec<&str> = blocks
+        .iter()
+        .filter(|block| block.lines.iter().any(|line| (first..=last).contains(line)))
+        .map(|block| block.text)
+        .collect();
+    let about_file: Vec<&str> = blocks.iter().filter(|block| !block.lines.is_empty()).map(|block| block.text).collect();
+
+    let selected = if !about_edit.is_empty() {
+        about_edit.join("\n\n")
+    } else if !about_file.is_empty() {
+        about_file.join("\n\n")
+    } else {
+        output.trim().to_string()
+    };
+
+    truncate(&selected, MAX_DIAGNOSTICS_LEN)
+}
+
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.len() <= max_len {
+        return text.to_string();
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n... (truncated)", &text[..end])
+}
@@ -0,0 +1,106 @@
+//! The preview of an edit printed by `--dry-run` and `--output diff|json`.
+
+use serde::Serialize;
+use similar::TextDiff;
+
+/// Output formats of `--output`.
+pub const FORMATS: &[&str] = &["diff", "json"];
+
+/// A range of whole lines. Lines are 1-based and inclusive, so an empty range
+/// (an insertion point) has `end_line == start_line - 1`. Byte ranges are
+/// 0-based and exclude `end_byte`.
+#[derive(Serialize)]
+pub struct Range {
+    pub start_line: usize,
+    pub end_line: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+/// An edit as printed by `--output json`: the lines it replaces in the
+/// original file and the lines that replace them in the updated one.
+#[derive(Serialize)]
+pub struct Change {
+    pub file_path: String,
+    pub mode: String,
+    pub structure_name: String,
+    pub old_range: Range,
+    pub new_range: Range,
+    pub old_code: String,
+    pub new_code: String,
+    pub diff: String,
+}
+
+/// Renders a unified diff with `a/` and `b/` prefixes, as `git diff` does.
+/// Returns an empty string when nothing changed.
+pub fn unified_diff(file_path: &str, original: &str, updated: &str) -> String {
+    if original == updated {
+        return String::new();
+    }
+    TextDiff::from_lines(original, updated)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{}", file_path), &format!("b/{}", file_path))
+        .to_string()
+}
+
+/// Describes the edit from `original` to `updated`, widened to whole lines.
+pub fn change(file_path: &str, mode: &str, structure_name: &str, original: &str, updated: &str) -> Change {
+    let (old_range, new_range) = changed_ranges(original, updated);
+    Change {
+        file_path: file_path.to_string(),
+        mode: mode.to_string(),
+        structure_name: structure_name.to_string(),
+        old_code: original[old_range.start_byte..old_range.end_byte].to_string(),
+        new_code: updated[new_range.start_byte..new_range.end_byte].to_string(),
+        old_range,
+        new_range,
+        diff: unified_diff(file_path, original, updated),
+    }
+}
+
+/// The length in bytes of the longest common prefix of `a` and `b`, ending on
+/// a character boundary so it can be used to slice either text.
+pub fn common_prefix(a: &str, b: &str) -> usize {
+    let mut prefix = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    while !a.is_char_boundary(prefix) || !b.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    prefix
+}
+
+/// The length in bytes of the longest common suffix of `a` and `b`, starting
+/// on a character boundary so it can be used to slice either text.
+pub fn common_suffix(a: &str, b: &str) -> usize {
+    let mut suffix = a.bytes().rev().zip(b.bytes().rev()).take_while(|(x, y)| x == y).count();
+    while !a.is_char_boundary(a.len() - suffix) || !b.is_char_boundary(b.len() - suffix) {
+        suffix -= 1;
+    }
+    suffix
+}
+
+/// The lines of `original` and `updated` that differ, found by trimming the
+/// lines the two have in common at the start and at the end.
+fn changed_ranges(original: &str, updated: &str) -> (Range, Range) {
+    let common_prefix = common_prefix(original, updated);
+    let prefix = original[..common_prefix].rfind('\n').map_or(0, |newline| newline + 1);
+
+    let common_suffix = common_suffix(&original[prefix..], &updated[prefix..]);
+    // Keep the suffix to whole lines: when it starts in the middle of a line of
+    // either text, the rest of that line is part of the change.
+    let line_start = |text: &str, at: usize| at == prefix || text.as_bytes()[at - 1] == b'\n';
+    let suffix_start = original.len() - common_suffix;
+    let suffix = if line_start(original, suffix_start) && line_start(updated, updated.len() - common_suffix) {
+        common_suffix
+    } else {
+        original[suffix_start..].find('\n').map_or(0, |newline| common_suffix - newline - 1)
+    };
+
+    let range = |text: &str| {
+        let (start_byte, end_byte) = (prefix, text.len() - suffix);
+        let start_line = text[..start_byte].matches('\n').count() + 1;
+        let lines = text[start_byte..end_byte].lines().count();
+        Range { start_line, end_line: start_line + lines - 1, start_byte, end_byte }
+    };
+    (range(original), range(updated))
+}
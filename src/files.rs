@@ -0,0 +1,87 @@
+//! Crash-safe writes and the lock that keeps two runs from editing the same
+//! file at once.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Replaces the content of `path` without ever leaving it half-written: the
+/// content goes to a temporary file in the same directory, which is synced and
+/// renamed over `path`. The permissions of an existing file are kept, and a
+/// symbolic link is followed rather than replaced.
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref();
+    let path = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = dir.join(format!(".{}.rfcu-{}.tmp", file_name, std::process::id()));
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_ref())?;
+        if let Ok(metadata) = fs::metadata(&path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+
+    // Sync the directory too, so the rename itself survives a crash.
+    if let Ok(dir) = File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// An advisory lock on a lock file, released when dropped or when the process
+/// exits.
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Locks `lock_path` on behalf of `target`. When another process holds the
+    /// lock, waits for it if `wait` is set, and fails right away otherwise.
+    pub fn acquire(lock_path: &Path, target: &str, wait: bool) -> io::Result<FileLock> {
+        if let Some(dir) = lock_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(lock_path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => "another rfcu run".to_string(),
+                    pid => format!("another rfcu run (pid {})", pid),
+                };
+                if !wait {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("{} is being edited by {}; try again when it finishes, or pass --wait", target, holder),
+                    ));
+                }
+                eprintln!("Waiting for {} to finish editing {}...", holder, target);
+                file.lock()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        Ok(FileLock { _file: file })
+    }
+}
@@ -0,0 +1,142 @@
+//! The git commands run once an edit passed validation: staging, committing
+//! and committing on a separate branch.
+
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs git and returns its output, or an error with git's own message when
+/// it fails.
+fn git(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "git {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs git with `args` followed by `--` and `file_paths`.
+fn git_on(args: &[&str], file_paths: &[&str]) -> io::Result<String> {
+    let mut args = args.to_vec();
+    args.push("--");
+    args.extend_from_slice(file_paths);
+    git(&args)
+}
+
+/// The files with staged changes, other than `file_paths`.
+pub fn staged_elsewhere(file_paths: &[&str]) -> io::Result<Vec<String>> {
+    let excludes: Vec<String> = file_paths.iter().map(|file_path| format!(":(exclude){}", file_path)).collect();
+    let mut pathspecs = vec![":/"];
+    pathspecs.extend(excludes.iter().map(String::as_str));
+    let staged = git_on(&["diff", "--cached", "--name-only"], &pathspecs)?;
+    Ok(staged.lines().map(str::to_string).collect())
+}
+
+pub fn stage_changes(file_paths: &[&str]) -> io::Result<()> {
+    eprintln!("Adding changes to git...");
+    git_on(&["add"], file_paths)?;
+    Ok(())
+}
+
+/// Commits the edited files on their own, leaving anything else staged as it
+/// is.
+pub fn commit_changes(file_paths: &[&str], message: &str) -> io::Result<()> {
+    stage_changes(file_paths)?;
+    eprintln!("Committing changes to git...");
+    if let Err(e) = git_on(&["commit", "-q", "-m", message], file_paths) {
+        // Leave the index as it was before `git add`.
+        let _ = git_on(&["reset", "-q"], file_paths);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// The changes to `file_paths` since HEAD, staged or not, as `git diff` prints
+/// them.
+pub fn diff(file_paths: &[&str]) -> io::Result<String> {
+    git_on(&["diff", "HEAD"], file_paths)
+}
+
+/// Whether `file_path` differs from HEAD, in the index or in the working tree.
+pub fn has_uncommitted_changes(file_path: &str) -> io::Result<bool> {
+    Ok(!git(&["status", "--porcelain", "--", file_path])?.trim().is_empty())
+}
+
+/// Checks that `branch` is a valid name for a branch that does not exist yet.
+pub fn check_new_branch(branch: &str) -> Result<(), String> {
+    if git(&["check-ref-format", "--branch", branch]).is_err() {
+        return Err(format!("'{}' is not a valid branch name", branch));
+    }
+    if git(&["rev-parse", "--verify", "-q", &format!("refs/heads/{}", branch)]).is_ok() {
+        return Err(format!("the branch {} already exists", branch));
+    }
+    Ok(())
+}
+
+/// The name of the branch of a run: `rfcu/<mode>/<structure>-<timestamp>`,
+/// or the file name instead of the structure for whole-file modes.
+pub fn branch_name(mode: &str, structure_name: &str, file_path: &str) -> String {
+    let subject = if structure_name.is_empty() {
+        Path::new(file_path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+    } else {
+        structure_name.to_string()
+    };
+    format!("rfcu/{}/{}-{}", slug(mode), slug(&subject), chrono::Utc::now().format("%Y%m%d-%H%M%S"))
+}
+
+/// Keeps ASCII letters, digits, `_` and `-`, and turns every other run of
+/// characters into a single `-`.
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// The checked out branch, or the commit of a detached HEAD.
+fn current_head() -> io::Result<String> {
+    match git(&["symbolic-ref", "--short", "-q", "HEAD"]) {
+        Ok(branch) if !branch.trim().is_empty() => Ok(branch.trim().to_string()),
+        _ => git(&["rev-parse", "HEAD"]).map(|commit| commit.trim().to_string()),
+    }
+}
+
+/// Creates a branch from HEAD and checks it out, so the edit is applied,
+/// validated and committed there. Returns the branch or commit to come back
+/// to with [`leave_branch`].
+pub fn switch_to_new_branch(branch: &str) -> io::Result<String> {
+    let previous = current_head()?;
+    eprintln!("Creating branch {}...", branch);
+    git(&["switch", "-q", "-c", branch])?;
+    Ok(previous)
+}
+
+/// Whether `branch` has commits that `base` does not.
+pub fn is_ahead(base: &str, branch: &str) -> io::Result<bool> {
+    Ok(git(&["rev-list", "--count", &format!("{}..{}", base, branch)])?.trim() != "0")
+}
+
+/// Checks out `previous` again, leaving the new branch with its commit, or
+/// deleting it when the edit was not committed.
+pub fn leave_branch(previous: &str, branch: &str, committed: bool) -> io::Result<()> {
+    eprintln!("Switching back to {}...", previous);
+    git(&["checkout", "-q", previous])?;
+    if !committed {
+        eprintln!("Deleting branch {}, which received no commit.", branch);
+        git(&["branch", "-q", "-D", branch])?;
+    }
+    Ok(())
+}
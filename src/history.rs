@@ -0,0 +1,255 @@
+//! The journal of runs in `.rfcu/history/`, one JSON file per run, used by
+//! `rfcu history`, `rfcu show` and `rfcu undo`.
+//!
+//! A run is recorded with the original content before the file is first
+//! written, so the file can be restored even if RFCU is killed half-way.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+
+use crate::files::{self, FileLock};
+
+/// The directory of the journal, relative to the repository root.
+const HISTORY_DIR: &str = ".rfcu/history";
+
+/// The directory of the lock files of the files being edited.
+const LOCKS_DIR: &str = ".rfcu/locks";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Running, or interrupted before it finished.
+    Started,
+    /// An attempt passed validation and was written.
+    Applied,
+    /// No attempt passed validation, or the run stopped on an error.
+    Failed,
+    /// Nothing was accepted in the interactive review.
+    Aborted,
+    /// Reverted by `rfcu undo`.
+    Undone,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Started => "started",
+            Status::Applied => "applied",
+            Status::Failed => "failed",
+            Status::Aborted => "aborted",
+            Status::Undone => "undone",
+        }
+    }
+}
+
+/// One request sent to the backend during a run.
+#[derive(Serialize, Deserialize)]
+pub struct Exchange {
+    pub prompt: String,
+    pub response: String,
+    /// The validation stage that rejected the answer, if any.
+    pub failed_stage: Option<String>,
+}
+
+/// A run as recorded in the journal.
+#[derive(Serialize, Deserialize)]
+pub struct Run {
+    pub id: String,
+    /// UTC, in RFC 3339 format.
+    pub timestamp: String,
+    pub status: Status,
+    /// The edited file, relative to the repository root when it is inside it.
+    pub file_path: String,
+    pub mode: String,
+    pub structure_name: String,
+    pub user_request: String,
+    pub exchanges: Vec<Exchange>,
+    /// The content of the file before the run.
+    pub before: String,
+    /// The content written by the run, once an attempt passed validation.
+    pub after: Option<String>,
+}
+
+/// The journal of the repository the current directory is in, or of the
+/// current directory outside a repository.
+pub struct Journal {
+    root: PathBuf,
+}
+
+impl Journal {
+    pub fn open() -> Journal {
+        let toplevel = Command::new("git")
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+        Journal { root: toplevel.unwrap_or_else(|| PathBuf::from(".")) }
+    }
+
+    /// The repository root, where the `.rfcu` directory is.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.root.join(HISTORY_DIR)
+    }
+
+    /// Starts recording a run on `file_path` and saves it with its original
+    /// content.
+    pub fn start(&self, file_path: &str, mode: &str, user_request: &str, before: &str) -> io::Result<Run> {
+        let now = chrono::Utc::now();
+        let run = Run {
+            id: format!("{}-{}", now.format("%Y%m%d-%H%M%S"), std::process::id()),
+            timestamp: now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            status: Status::Started,
+            file_path: self.relative_path(file_path),
+            mode: mode.to_string(),
+            structure_name: String::new(),
+            user_request: user_request.to_string(),
+            exchanges: Vec::new(),
+            before: before.to_string(),
+            after: None,
+        };
+        self.save(&run)?;
+        Ok(run)
+    }
+
+    pub fn save(&self, run: &Run) -> io::Result<()> {
+        let dir = self.dir();
+        create_ignored_dir(&dir)?;
+        let json = serde_json::to_string_pretty(run).map_err(io::Error::other)?;
+        files::write_atomic(dir.join(format!("{}.json", run.id)), json)
+    }
+
+    /// Locks `file_path` against other runs, for as long as the returned lock
+    /// is held.
+    pub fn lock(&self, file_path: &str, wait: bool) -> io::Result<FileLock> {
+        let dir = self.root.join(LOCKS_DIR);
+        create_ignored_dir(&dir)?;
+        let lock_name = self.relative_path(file_path).replace(['/', '\\'], "%");
+        FileLock::acquire(&dir.join(format!("{}.lock", lock_name)), file_path, wait)
+    }
+
+    pub fn load(&self, id: &str) -> io::Result<Run> {
+        let path = self.dir().join(format!("{}.json", id));
+        let json = fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("no run {} in {}: {}", id, self.dir().display(), e)))?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// Every recorded run, oldest first.
+    pub fn runs(&self) -> io::Result<Vec<Run>> {
+        let mut ids: Vec<String> = match fs::read_dir(self.dir()) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.strip_suffix(".json")).map(str::to_string))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        ids.sort();
+        ids.iter().map(|id| self.load(id)).collect()
+    }
+
+    /// The path of the run's file, to read or write it from the current
+    /// directory.
+    pub fn resolve(&self, run: &Run) -> PathBuf {
+        self.root.join(&run.file_path)
+    }
+
+    pub fn relative_path(&self, file_path: &str) -> String {
+        let absolute = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+        let root = fs::canonicalize(&self.root).unwrap_or_else(|_| self.root.clone());
+        absolute.strip_prefix(&root).unwrap_or(&absolute).to_string_lossy().into_owned()
+    }
+}
+
+/// The journal entry of the current run.
+pub struct Recording {
+    journal: Journal,
+    pub run: Run,
+}
+
+impl Recording {
+    /// Records the start of a run on `file_path` with its original content.
+    pub fn start(file_path: &str, mode: &str, user_request: &str, before: &str) -> io::Result<Recording> {
+        let journal = Journal::open();
+        let run = journal.start(file_path, mode, user_request, before)?;
+        eprintln!("Recording the run in {}", journal.dir().join(format!("{}.json", run.id)).display());
+        Ok(Recording { journal, run })
+    }
+
+    /// Saves the run. A failure is reported but does not stop the run, since
+    /// the original content was saved when it started.
+    pub fn save(&self) {
+        if let Err(e) = self.journal.save(&self.run) {
+            eprintln!("Failed to update the journal: {}", e);
+        }
+    }
+
+    pub fn finish(&mut self, status: Status) {
+        self.run.status = status;
+        self.save();
+    }
+}
+
+/// Creates a directory of RFCU state that `git status` does not show.
+pub fn create_ignored_dir(dir: &Path) -> io::Result<()> {
+    if !dir.is_dir() {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(".gitignore"), "*\n")?;
+    }
+    Ok(())
+}
+
+/// Restores the content of `file_path` from before `run`. Unless `force` is
+/// set, refuses when the file was changed after the run.
+pub fn undo(journal: &Journal, run: &mut Run, force: bool) -> io::Result<PathBuf> {
+    let path = journal.resolve(run);
+    match run.status {
+        Status::Undone => return Err(io::Error::other(format!("run {} was already undone", run.id))),
+        Status::Aborted => return Err(io::Error::other(format!("run {} did not change {}", run.id, run.file_path))),
+        _ => {}
+    }
+    let _lock = journal.lock(&path.to_string_lossy(), false)?;
+
+    let current = fs::read_to_string(&path)?;
+    if current == run.before {
+        return Err(io::Error::other(format!("{} already has its content from before run {}", run.file_path, run.id)));
+    }
+    let changed_since = match &run.after {
+        Some(after) => current != *after,
+        None => true,
+    };
+    if changed_since && !force {
+        return Err(io::Error::other(format!(
+            "{} was changed after run {}; use --force to restore it anyway",
+            run.file_path, run.id
+        )));
+    }
+
+    files::write_atomic(&path, &run.before)?;
+    run.status = Status::Undone;
+    journal.save(run)?;
+    Ok(path)
+}
+
+/// The index of the most recent run `rfcu undo` can revert.
+pub fn last_undoable(runs: &[Run]) -> Option<usize> {
+    runs.iter().rposition(|run| matches!(run.status, Status::Applied | Status::Started))
+}
+
+/// Shortens a text to its first line, for `rfcu history`.
+pub fn summary(text: &str) -> String {
+    let first_line = text.lines().next().unwrap_or_default();
+    let mut summary: String = first_line.chars().take(50).collect();
+    if summary.len() < text.trim_end().len() {
+        summary.push_str("...");
+    }
+    summary
+}
@@ -0,0 +1,247 @@
+//! `rfcu jobs run`: the recurring edits declared in `rfcu-jobs.toml`.
+//!
+//! Every `[jobs.<name>]` table describes an `rfcu run`. What each job already
+//! did is kept in `.rfcu/jobs/state.json`, saved as soon as a file is edited,
+//! so an interrupted run resumes where it stopped and a structure whose code
+//! has not changed since its last successful run is not edited again. Changing
+//! the table of a job starts it over.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{fnv1a, FNV_OFFSET_BASIS};
+use crate::config;
+use crate::files;
+use crate::history::{self, Journal};
+use crate::project::{self, Memory, Options};
+
+/// The job file read when `--jobs-file` is not given.
+pub const DEFAULT_JOBS_FILE: &str = "rfcu-jobs.toml";
+
+/// Where the state of the jobs is kept, under the repository root.
+const STATE_DIR: &str = ".rfcu/jobs";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobsFile {
+    #[serde(default)]
+    jobs: BTreeMap<String, Job>,
+}
+
+/// A `[jobs.<name>]` table, with the arguments of `rfcu run`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    mode: String,
+    /// The directory to walk, relative to the job file.
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default)]
+    glob: Vec<String>,
+    select: Option<String>,
+    /// The user request, read from stdin by `rfcu run`.
+    request: String,
+    profile: Option<String>,
+    #[serde(default = "default_commits")]
+    commits: String,
+    #[serde(default)]
+    combined: bool,
+    /// How many files are edited at the same time, `max_concurrency` by
+    /// default.
+    jobs: Option<usize>,
+}
+
+fn default_path() -> String {
+    ".".to_string()
+}
+
+fn default_commits() -> String {
+    "one".to_string()
+}
+
+impl Job {
+    /// A hash of the table, to tell when a job was changed since its state
+    /// was saved.
+    fn fingerprint(&self) -> String {
+        let json = serde_json::to_string(self).expect("A job is always serializable");
+        format!("{:016x}", fnv1a(json.as_bytes(), FNV_OFFSET_BASIS))
+    }
+}
+
+/// What every job did, saved in `.rfcu/jobs/state.json`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    jobs: BTreeMap<String, JobState>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct JobState {
+    fingerprint: String,
+    /// For every file, relative to the repository root, the hash of the code
+    /// of each structure after its last successful run. Whole-file modes use
+    /// an empty structure name.
+    items: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn hash(code: &str) -> String {
+    format!("{:016x}", fnv1a(code.as_bytes(), FNV_OFFSET_BASIS))
+}
+
+/// The state of one job, shared with the workers of `rfcu run`.
+struct JobMemory<'a> {
+    journal: &'a Journal,
+    state_path: PathBuf,
+    name: String,
+    state: Mutex<State>,
+}
+
+impl JobMemory<'_> {
+    fn save(&self, state: &State) -> io::Result<()> {
+        if let Some(dir) = self.state_path.parent() {
+            history::create_ignored_dir(dir)?;
+        }
+        let json = serde_json::to_string_pretty(state).map_err(io::Error::other)?;
+        files::write_atomic(&self.state_path, json)
+    }
+}
+
+impl Memory for JobMemory<'_> {
+    fn is_unchanged(&self, file_path: &str, structure: &str, code: &str) -> bool {
+        let state = self.state.lock().expect("The job state lock is poisoned");
+        state.jobs[&self.name]
+            .items
+            .get(&self.journal.relative_path(file_path))
+            .and_then(|structures| structures.get(structure))
+            .is_some_and(|saved| *saved == hash(code))
+    }
+
+    fn record(&self, file_path: &str, structures: &[(String, String)]) {
+        let mut state = self.state.lock().expect("The job state lock is poisoned");
+        let items = state
+            .jobs
+            .get_mut(&self.name)
+            .expect("The job has a state")
+            .items
+            .entry(self.journal.relative_path(file_path))
+            .or_default();
+        for (structure, code) in structures {
+            items.insert(structure.clone(), hash(code));
+        }
+        if let Err(e) = self.save(&state) {
+            eprintln!("Failed to save the job state in {}: {}", self.state_path.display(), e);
+        }
+    }
+}
+
+/// Runs the job called `name`, or every job of the file, and returns the exit
+/// status.
+pub fn run(matches: &clap::ArgMatches, config_flag: Option<&str>, profile_flag: Option<&str>) -> i32 {
+    let jobs_file = matches.get_one::<String>("jobs_file").expect("Jobs file has a default value");
+    let jobs = match read_jobs(Path::new(jobs_file)) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let selected: Vec<&String> = match matches.get_one::<String>("name") {
+        Some(name) if jobs.contains_key(name) => vec![name],
+        Some(name) => {
+            eprintln!("No job named '{}' in {}", name, jobs_file);
+            eprintln!("Available jobs: {}", jobs.keys().cloned().collect::<Vec<_>>().join(", "));
+            return 1;
+        }
+        None => jobs.keys().collect(),
+    };
+    if selected.is_empty() {
+        eprintln!("No jobs in {}", jobs_file);
+        return 1;
+    }
+
+    let journal = Journal::open();
+    let state_path = journal.root().join(STATE_DIR).join("state.json");
+    let mut state = match fs::read_to_string(&state_path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            eprintln!("Ignoring the invalid job state in {}: {}", state_path.display(), e);
+            State::default()
+        }),
+        Err(_) => State::default(),
+    };
+
+    let base_dir = Path::new(jobs_file).parent().unwrap_or(Path::new(""));
+    let mut status = 0;
+    for name in selected {
+        let job = &jobs[name];
+        let fingerprint = job.fingerprint();
+        let job_state = state.jobs.entry(name.clone()).or_default();
+        if job_state.fingerprint != fingerprint {
+            if !job_state.fingerprint.is_empty() {
+                eprintln!("The job {} changed since its last run, starting it over.", name);
+            }
+            *job_state = JobState { fingerprint, items: BTreeMap::new() };
+        }
+
+        eprintln!("Running the job {}...", name);
+        let memory = JobMemory { journal: &journal, state_path: state_path.clone(), name: name.clone(), state: Mutex::new(state) };
+        status = status.max(run_job(job, base_dir, &memory, matches, config_flag, profile_flag));
+        state = memory.state.into_inner().expect("The job state lock is poisoned");
+    }
+    status
+}
+
+fn read_jobs(jobs_file: &Path) -> Result<BTreeMap<String, Job>, String> {
+    let content = fs::read_to_string(jobs_file).map_err(|e| format!("Cannot read {}: {}", jobs_file.display(), e))?;
+    let jobs: JobsFile = toml::from_str(&content).map_err(|e| format!("Invalid job file {}: {}", jobs_file.display(), e))?;
+    for (name, job) in &jobs.jobs {
+        if !project::COMMIT_MODES.contains(&job.commits.as_str()) {
+            return Err(format!(
+                "Invalid commits '{}' in the job {}, expected one of {}",
+                job.commits,
+                name,
+                project::COMMIT_MODES.join(", ")
+            ));
+        }
+    }
+    Ok(jobs.jobs)
+}
+
+fn run_job(
+    job: &Job,
+    base_dir: &Path,
+    memory: &JobMemory,
+    matches: &clap::ArgMatches,
+    config_flag: Option<&str>,
+    profile_flag: Option<&str>,
+) -> i32 {
+    let root = base_dir.join(&job.path).to_string_lossy().into_owned();
+    let root = if root.is_empty() { ".".to_string() } else { root };
+    let profile = job.profile.as_deref().or(profile_flag);
+    let settings = match config::load_settings(config_flag, Some(&root), profile, Some(&job.mode)) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            return 1;
+        }
+    };
+
+    let options = Options {
+        mode_name: &job.mode,
+        root: &root,
+        globs: job.glob.clone(),
+        select: job.select.as_deref(),
+        user_request: job.request.trim(),
+        jobs: job.jobs.unwrap_or(settings.max_concurrency),
+        per_file: job.commits == "per-file",
+        combined: job.combined,
+        dry_run: matches.get_flag("dry_run"),
+        no_commit: matches.get_flag("no_commit"),
+        force_commit: matches.get_flag("force_commit"),
+        config_flag,
+        profile_flag: profile,
+    };
+    project::run_with(&settings, &options, Some(memory))
+}
@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use tree_sitter::Language;
+
+extern "C" {
+    fn tree_sitter_rust() -> Language;
+    fn tree_sitter_python() -> Language;
+    fn tree_sitter_ruby() -> Language;
+    fn tree_sitter_javascript() -> Language;
+    fn tree_sitter_typescript() -> Language;
+    fn tree_sitter_tsx() -> Language;
+    fn tree_sitter_bash() -> Language;
+    fn tree_sitter_json() -> Language;
+}
+
+/// Appended to every default template, so a retry sees why the previous
+/// attempt was rejected.
+const RETRY_HINT: &str =
+    "{#if previous_errors}\n\nYour previous answer was rejected with these errors. Fix them in this answer:\n{previous_errors}{/if}";
+
+/// How a language documents its items.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DocStyle {
+    /// Comments placed above the item: a block such as `/** ... */`, or a run
+    /// of lines starting with `line_prefix`.
+    Above {
+        block: Option<(&'static str, &'static str)>,
+        line_prefix: Option<&'static str>,
+    },
+    /// A string literal as the first statement of the item's body, as in Python.
+    Docstring,
+}
+
+/// Everything RFCU needs to know about a language.
+pub struct LanguageSpec {
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    grammar: unsafe extern "C" fn() -> Language,
+    /// Node kinds that can be targeted with `--structure_name`. They have a
+    /// `name` field, except Rust's `impl_item` and JSON's `pair`, see
+    /// [`crate::structure::segment`].
+    pub structure_kinds: &'static [&'static str],
+    /// Node kinds of comments, used to find the documentation at the top of a file.
+    pub comment_kinds: &'static [&'static str],
+    pub doc_style: DocStyle,
+    /// Tag used on fenced code blocks in prompts and responses.
+    pub code_fence: &'static str,
+    /// How the default templates describe documentation in this language.
+    doc_hint: &'static str,
+    /// How the default templates describe tests in this language.
+    test_hint: &'static str,
+}
+
+impl LanguageSpec {
+    pub fn grammar(&self) -> Language {
+        unsafe { (self.grammar)() }
+    }
+
+    pub fn is_structure_kind(&self, kind: &str) -> bool {
+        self.structure_kinds.contains(&kind)
+    }
+
+    /// The template used for a built-in mode when `[requests]` does not set one.
+    pub fn default_template(&self, mode: &str) -> Option<String> {
+        let language = self.name;
+        let fence = self.code_fence;
+        let template = match mode {
+            "improvement" => format!(
+                "{{#if user_request}}{{user_request}}\n{{/if}}Please improve the following {} item '{{structure_name}}' without adding new functionalities. Only make improvements to the existing code:\n\n```{}\n{{structure_code}}\n```\n\nYou are part of a pipeline. Only output the changed code enclosed within triple backticks.",
+                language, fence
+            ),
+            "whole_file" => format!(
+                "{{#if user_request}}{{user_request}}\n{{/if}}Please improve the following {} code without adding new functionalities. Only make improvements to the existing code:\n\n```{}\n{{source_code}}\n```\n\nYou are part of a pipeline. Only output the changed code enclosed within triple backticks.",
+                language, fence
+            ),
+            "add_functionality" => format!(
+                "Please add the following functionality: {{user_request}} to this {} code base:\n\n```{}\n{{source_code}}\n```\n\nYou are part of a pipeline. Only output the new code enclosed within triple backticks. Never output existing functions or other syntax.",
+                language, fence
+            ),
+            "add_tests_function" => format!(
+                "Please add unit tests, written as {}, for the following {} item '{{structure_name}}':\n\n```{}\n{{structure_code}}\n```\n\nYou are part of a pipeline. Only output the new unit tests enclosed within triple backticks. Name the tests after '{{structure_name}}'.",
+                self.test_hint, language, fence
+            ),
+            "documentation_whole_file" => format!(
+                "{{#if user_request}}{{user_request}}\n{{/if}}Please generate documentation for the following {} file, written as {}, to be placed at the top of the file:\n\n```{}\n{{source_code}}\n```\n\nOnly output the new documentation enclosed within triple backticks. Do not output any code.",
+                language, self.doc_hint, fence
+            ),
+            "documentation_structure" => format!(
+                "{{#if user_request}}{{user_request}}\n{{/if}}Please generate documentation for the following {} item '{{structure_name}}', written as {}:\n\n```{}\n{{structure_code}}\n```\n\nOnly output the new documentation enclosed within triple backticks. Never output the code itself.",
+                language, self.doc_hint, fence
+            ),
+            _ => return None,
+        };
+        Some(template + RETRY_HINT)
+    }
+}
+
+/// The supported languages. Markdown is left out although its grammar is a
+/// dependency: `tree-sitter-markdown` 0.7 is built against tree-sitter 0.19,
+/// whose C library clashes with the one of tree-sitter 0.22 when both are
+/// linked, so the grammar cannot be loaded until it is replaced by a crate for
+/// the current tree-sitter.
+pub static LANGUAGES: &[LanguageSpec] = &[
+    LanguageSpec {
+        name: "rust",
+        extensions: &["rs"],
+        grammar: tree_sitter_rust,
+        structure_kinds: &[
+            "function_item",
+            "mod_item",
+            "struct_item",
+            "enum_item",
+            "union_item",
+            "trait_item",
+            "impl_item",
+            "const_item",
+            "static_item",
+            "type_item",
+            "macro_definition",
+        ],
+        comment_kinds: &["block_comment", "line_comment"],
+        doc_style: DocStyle::Above { block: Some(("/**", "*/")), line_prefix: Some("///") },
+        code_fence: "rust",
+        doc_hint: "Rust doc comments (`///`)",
+        test_hint: "`#[test]` functions",
+    },
+    LanguageSpec {
+        name: "python",
+        extensions: &["py", "pyi"],
+        grammar: tree_sitter_python,
+        structure_kinds: &["function_definition", "class_definition"],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Docstring,
+        code_fence: "python",
+        doc_hint: "a Python docstring including the triple quotes",
+        test_hint: "pytest test functions",
+    },
+    LanguageSpec {
+        name: "ruby",
+        extensions: &["rb"],
+        grammar: tree_sitter_ruby,
+        structure_kinds: &["method", "singleton_method", "class", "module"],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Above { block: None, line_prefix: Some("#") },
+        code_fence: "ruby",
+        doc_hint: "YARD comments (`#`)",
+        test_hint: "RSpec examples",
+    },
+    LanguageSpec {
+        name: "javascript",
+        extensions: &["js", "mjs", "cjs", "jsx"],
+        grammar: tree_sitter_javascript,
+        structure_kinds: &["function_declaration", "generator_function_declaration", "class_declaration", "method_definition"],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Above { block: Some(("/**", "*/")), line_prefix: None },
+        code_fence: "javascript",
+        doc_hint: "JSDoc comments (`/** */`)",
+        test_hint: "Jest tests",
+    },
+    LanguageSpec {
+        name: "typescript",
+        extensions: &["ts", "mts", "cts"],
+        grammar: tree_sitter_typescript,
+        structure_kinds: &[
+            "function_declaration",
+            "generator_function_declaration",
+            "class_declaration",
+            "abstract_class_declaration",
+            "method_definition",
+            "interface_declaration",
+            "type_alias_declaration",
+            "enum_declaration",
+            "internal_module",
+        ],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Above { block: Some(("/**", "*/")), line_prefix: None },
+        code_fence: "typescript",
+        doc_hint: "TSDoc comments (`/** */`)",
+        test_hint: "Jest tests",
+    },
+    LanguageSpec {
+        name: "tsx",
+        extensions: &["tsx"],
+        grammar: tree_sitter_tsx,
+        structure_kinds: &[
+            "function_declaration",
+            "generator_function_declaration",
+            "class_declaration",
+            "abstract_class_declaration",
+            "method_definition",
+            "interface_declaration",
+            "type_alias_declaration",
+            "enum_declaration",
+            "internal_module",
+        ],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Above { block: Some(("/**", "*/")), line_prefix: None },
+        code_fence: "tsx",
+        doc_hint: "TSDoc comments (`/** */`)",
+        test_hint: "Jest tests",
+    },
+    LanguageSpec {
+        name: "bash",
+        extensions: &["sh", "bash"],
+        grammar: tree_sitter_bash,
+        structure_kinds: &["function_definition"],
+        comment_kinds: &["comment"],
+        doc_style: DocStyle::Above { block: None, line_prefix: Some("#") },
+        code_fence: "bash",
+        doc_hint: "comments (`#`)",
+        test_hint: "bats tests",
+    },
+    LanguageSpec {
+        name: "json",
+        extensions: &["json"],
+        grammar: tree_sitter_json,
+        structure_kinds: &["pair"],
+        comment_kinds: &[],
+        doc_style: DocStyle::Above { block: None, line_prefix: None },
+        code_fence: "json",
+        doc_hint: "a top-level \"description\" field",
+        test_hint: "JSON Schema examples",
+    },
+];
+
+/// Looks a language up by name.
+pub fn by_name(name: &str) -> Option<&'static LanguageSpec> {
+    LANGUAGES.iter().find(|spec| spec.name == name)
+}
+
+/// Picks the language for `file_path`. A `language` other than `auto` in the
+/// configuration wins; otherwise the file extension is looked up in the
+/// configured `[language_extensions]` and then in the built-in registry.
+pub fn detect(
+    configured: Option<&str>,
+    extension_overrides: &BTreeMap<String, String>,
+    file_path: &str,
+) -> Result<&'static LanguageSpec, String> {
+    if let Some(name) = configured.filter(|name| *name != "auto") {
+        return by_name(name).ok_or_else(|| format!("Unsupported language: {}", name));
+    }
+
+    let extension = Path::new(file_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+
+    if let Some(name) = extension_overrides.get(extension) {
+        return by_name(name).ok_or_else(|| format!("Unsupported language for .{} files: {}", extension, name));
+    }
+
+    LANGUAGES
+        .iter()
+        .find(|spec| spec.extensions.contains(&extension))
+        .ok_or_else(|| format!("Cannot detect the language of {}. Set `language` in the configuration", file_path))
+}
@@ -1,65 +1,157 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::fs::File;
-use std::io::{self, Read, Write};
-use std::process::{Command, Stdio};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use clap::Arg;
-use serde::Deserialize;
-use tree_sitter::{Parser, Language, Node};
+use tree_sitter::{Parser, Node};
 use regex::Regex;
 
-extern "C" { fn tree_sitter_rust() -> Language; }
-
-#[derive(Deserialize)]
-struct Settings {
-    flowname: String,
-    commit_message_flow: String,
-    documentation_flow: String,
-    language: String,
-    requests: Requests,
-    lint_command: Option<String>,
-    max_retries: usize,
-}
-
-#[derive(Deserialize)]
-struct Requests {
-    improvement: String,
-    whole_file: String,
-    add_functionality: String,
-    add_tests_function: String,
-    documentation_whole_file: String,
-    documentation_structure: String,
-}
+mod backend;
+mod commit_message;
+mod config;
+mod config_check;
+mod diagnostics;
+mod diff;
+mod files;
+mod git;
+mod history;
+mod jobs;
+mod language;
+mod outline;
+mod project;
+mod review;
+mod structure;
+mod template;
+mod throttle;
+mod validation;
+
+use backend::{CompletionBackend, CompletionRequest};
+use config::{BranchStrategy, CommitPolicy, Insertion, Mode, Scope, Settings};
+use language::{DocStyle, LanguageSpec};
 
 fn main() -> io::Result<()> {
-    eprintln!("Reading configuration file...");
-    let config_content = fs::read_to_string("/Users/n/.rfcu/config.toml").expect("Failed to read config file");
-    let settings: Settings = toml::from_str(&config_content).expect("Failed to parse config file");
-
-    let matches = clap::Command::new("RFCU")
+    let mut command = clap::Command::new("RFCU")
         .version("1.0")
         .author("Nick <nick@njf.io>")
         .about("Rust Fluent Code Utility")
+        .subcommand_negates_reqs(true)
+        .arg(
+            Arg::new("config")
+                .help("Path to the user configuration file (overrides RFCU_CONFIG and ~/.rfcu/config.toml)")
+                .long("config")
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("profile")
+                .help("The configuration profile to use, from a [profiles.<name>] table")
+                .long("profile")
+                .global(true)
+                .required(false),
+        )
         .arg(
             Arg::new("file_path")
                 .help("The path to the source code file")
-                .required(true)
                 .global(true)
-                .long("file_path")
-                .required_unless_present("get_structure"),
+                .long("file_path"),
         )
         .arg(
             Arg::new("mode")
-                .help("The mode of operation")
+                .help("The mode of operation: a built-in mode or one declared in a [modes.<name>] table")
                 .long("mode")
-                .value_parser(["improvement", "add_functionality", "add_tests_function", "documentation_whole_file", "documentation_structure", "whole_file"])
-                .required(false),
+                .required(true),
         )
         .arg(
             Arg::new("structure_name")
-                .help("The name of the structure to modify (optional)")
+                .help("The name or qualified path of the structure to modify, e.g. `new`, `Config::new` or `impl Display for Foo` (optional). Repeat it or separate names with commas to edit several structures in one run")
                 .long("structure_name")
+                .action(clap::ArgAction::Append)
                 .required(false),
         )
+        .arg(
+            Arg::new("all_kind")
+                .help("Edit every structure of this tree-sitter kind, e.g. `function_item`, in one run")
+                .long("all-kind")
+                .value_name("KIND")
+                .conflicts_with_all(["structure_name", "line", "range", "byte_offset"]),
+        )
+        .arg(
+            Arg::new("combined")
+                .help("Send one prompt for all the selected structures instead of one prompt per structure")
+                .long("combined")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("line")
+                .help("Select the smallest structure enclosing this line (1-based) instead of --structure_name")
+                .long("line")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["structure_name", "range", "byte_offset"]),
+        )
+        .arg(
+            Arg::new("range")
+                .help("Select the smallest structure enclosing the lines FIRST:LAST instead of --structure_name")
+                .long("range")
+                .value_name("FIRST:LAST")
+                .conflicts_with_all(["structure_name", "byte_offset"]),
+        )
+        .arg(
+            Arg::new("byte_offset")
+                .help("Select the smallest structure enclosing this byte offset instead of --structure_name")
+                .long("byte-offset")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with("structure_name"),
+        )
+        .arg(
+            Arg::new("no_commit")
+                .help("Leave the edit unstaged, whatever the commit setting says")
+                .long("no-commit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force_commit")
+                .help("Commit the edited file even when other changes are already staged")
+                .long("force-commit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("branch")
+                .help("Commit the edit on this new branch and leave the checked out branch as it was")
+                .long("branch")
+                .value_name("NAME")
+                .conflicts_with("no_commit"),
+        )
+        .arg(
+            Arg::new("wait")
+                .help("Wait for another run editing the same file to finish instead of failing")
+                .long("wait")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interactive")
+                .help("Review the proposed change hunk by hunk before it is validated and applied")
+                .long("interactive")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .help("Print the edit as a unified diff instead of writing the file, the journal or a commit")
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("output")
+                .help("Print the edit to stdout as a unified diff or as JSON with the old and new ranges")
+                .long("output")
+                .value_parser(clap::builder::PossibleValuesParser::new(diff::FORMATS)),
+        )
+        .arg(
+            Arg::new("output_file")
+                .help("Write the updated code to this file instead of --file_path, without a journal entry or a commit")
+                .long("output-file"),
+        )
         .subcommand(
             clap::Command::new("get_structure")
                 .about("Retrieve the names of the specified structures from the source code")
@@ -67,9 +159,186 @@ fn main() -> io::Result<()> {
                     Arg::new("file_path")
                         .help("The path to the source code file")
                         .required(false),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("Output format: bare names, or an outline with kinds, paths, locations, visibility, docs, tests and signatures")
+                        .long("format")
+                        .value_parser(clap::builder::PossibleValuesParser::new(outline::FORMATS))
+                        .default_value("names"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("run")
+                .about("Edit the structures selected across a directory tree, a few files at a time")
+                .arg(
+                    Arg::new("mode")
+                        .help("The mode of operation: a built-in mode or one declared in a [modes.<name>] table")
+                        .long("mode")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("path")
+                        .help("The directory to walk; files ignored by .gitignore are left out")
+                        .long("path")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("glob")
+                        .help("Only edit the files matching this glob, relative to --path, e.g. `**/*.rs` (repeatable)")
+                        .long("glob")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("select")
+                        .help("The structures to edit, e.g. `undocumented pub fn`: any of pub, private, documented, undocumented, tested, untested, fn and structure kinds")
+                        .long("select"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("How many files are edited at the same time (default: max_concurrency)")
+                        .long("jobs")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("commits")
+                        .help("Commit all the edited files at once, or each file on its own")
+                        .long("commits")
+                        .value_parser(clap::builder::PossibleValuesParser::new(project::COMMIT_MODES))
+                        .default_value("one"),
+                )
+                .arg(
+                    Arg::new("combined")
+                        .help("Send one prompt per file for all its selected structures")
+                        .long("combined")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no_commit")
+                        .help("Leave the edits unstaged, whatever the commit setting says")
+                        .long("no-commit")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("force_commit")
+                        .help("Commit the edited files even when other changes are already staged")
+                        .long("force-commit")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .help("Print the edits as unified diffs instead of writing the files")
+                        .long("dry-run")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("jobs")
+                .about("Run the edits declared in a job file")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("run")
+                        .about("Run a job, or every job, skipping the structures unchanged since their last successful run")
+                        .arg(Arg::new("name").help("The job to run, a [jobs.<name>] table (default: every job)"))
+                        .arg(
+                            Arg::new("jobs_file")
+                                .help("The job file; paths in it are relative to its directory")
+                                .long("jobs-file")
+                                .default_value(jobs::DEFAULT_JOBS_FILE),
+                        )
+                        .arg(
+                            Arg::new("no_commit")
+                                .help("Leave the edits unstaged, whatever the commit setting says")
+                                .long("no-commit")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("force_commit")
+                                .help("Commit the edited files even when other changes are already staged")
+                                .long("force-commit")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("dry_run")
+                                .help("Print the edits as unified diffs instead of writing the files")
+                                .long("dry-run")
+                                .action(clap::ArgAction::SetTrue),
+                        ),
                 ),
         )
-        .get_matches();
+        .subcommand(clap::Command::new("history").about("List the runs recorded in .rfcu/history"))
+        .subcommand(
+            clap::Command::new("show")
+                .about("Show the prompts, responses and diff of a recorded run")
+                .arg(Arg::new("run_id").help("The id of the run, as listed by `rfcu history`").required(true)),
+        )
+        .subcommand(
+            clap::Command::new("undo")
+                .about("Restore the file edited by a run to its content from before the run")
+                .arg(Arg::new("run_id").help("The id of the run (default: the last applied run)"))
+                .arg(
+                    Arg::new("force")
+                        .help("Restore the file even if it was changed after the run")
+                        .long("force")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("check")
+                        .about("Validate every configuration layer, the request templates and the external commands"),
+                ),
+        );
+    let matches = command.get_matches_mut();
+    match matches.subcommand() {
+        Some(("get_structure", get_structure_matches)) if get_structure_matches.get_one::<String>("file_path").is_none() => {
+            missing_file_path(command.find_subcommand_mut("get_structure").expect("get_structure is a subcommand"))
+        }
+        None if matches.get_one::<String>("file_path").is_none() => missing_file_path(&mut command),
+        _ => {}
+    }
+
+    let config_flag = matches.get_one::<String>("config").map(String::as_str);
+
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        if config_matches.subcommand_matches("check").is_some() {
+            let file_path = matches.get_one::<String>("file_path").map(String::as_str).unwrap_or(".");
+            std::process::exit(run_config_check(config_flag, file_path));
+        }
+    }
+
+    if let Some((name, history_matches)) = matches.subcommand() {
+        if matches!(name, "history" | "show" | "undo") {
+            std::process::exit(run_history_command(name, history_matches));
+        }
+    }
+
+    let profile_flag = matches.get_one::<String>("profile").map(String::as_str);
+    if let Some(jobs_matches) = matches.subcommand_matches("jobs") {
+        if let Some(jobs_run_matches) = jobs_matches.subcommand_matches("run") {
+            std::process::exit(jobs::run(jobs_run_matches, config_flag, profile_flag));
+        }
+    }
+
+    let run_matches = matches.subcommand_matches("run");
+    let target_path = matches
+        .subcommand_matches("get_structure")
+        .and_then(|m| m.get_one::<String>("file_path"))
+        .or_else(|| run_matches.and_then(|m| m.get_one::<String>("path")))
+        .or_else(|| matches.get_one::<String>("file_path"))
+        .map(String::as_str);
+    let mode = run_matches.unwrap_or(&matches).get_one::<String>("mode").map(String::as_str);
+    let settings: Settings = config::load_settings(config_flag, target_path, profile_flag, mode).unwrap_or_else(|e| {
+        eprintln!("Failed to load configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    if let Some(run_matches) = run_matches {
+        std::process::exit(project::run(&settings, run_matches, config_flag, profile_flag));
+    }
 
     if let Some(get_structure_matches) = matches.subcommand_matches("get_structure") {
         let file_path = get_structure_matches.get_one::<String>("file_path").expect("File path is required");
@@ -77,17 +346,15 @@ fn main() -> io::Result<()> {
         eprintln!("Reading source code from file: {}", file_path);
         let source_code = fs::read_to_string(file_path).expect("Failed to read source file");
 
-        eprintln!("Initializing parser and setting language: {}", settings.language);
-        let mut parser = Parser::new();
-        let language = match settings.language.as_str() {
-            "rust" => unsafe { tree_sitter_rust() },
-            _ => {
-                eprintln!("Unsupported language: {}", settings.language);
+        let spec = language::detect(settings.language.as_deref(), &settings.language_extensions, file_path)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
                 std::process::exit(1);
-            }
-        };
+            });
 
-        if let Err(e) = parser.set_language(&language) {
+        eprintln!("Initializing parser and setting language: {}", spec.name);
+        let mut parser = Parser::new();
+        if let Err(e) = parser.set_language(&spec.grammar()) {
             eprintln!("Error setting language: {:?}", e);
             std::process::exit(1);
         }
@@ -96,714 +363,1253 @@ fn main() -> io::Result<()> {
         let tree = parser.parse(&source_code, None).expect("Error parsing source code");
         let root_node = tree.root_node();
 
-        let mut structures = Vec::new();
-        get_structures(&root_node, source_code.as_bytes(), &mut structures);
+        let format = get_structure_matches.get_one::<String>("format").expect("Format has a default value");
+        if format == "names" {
+            let mut structures = Vec::new();
+            get_structures(&root_node, source_code.as_bytes(), spec, &mut structures);
+
+            eprintln!("Structures found in the source code:");
+            for structure in structures {
+                println!("{}", structure);
+            }
+            return Ok(());
+        }
 
-        eprintln!("Structures found in the source code:");
-        for structure in structures {
-            println!("{}", structure);
+        let module_path = structure::module_path(file_path, spec);
+        let items = outline::outline(root_node, &source_code, spec, &module_path);
+        match format.as_str() {
+            "json" => println!("{}", serde_json::to_string_pretty(&items).expect("Failed to serialize the outline")),
+            "tsv" => print!("{}", outline::to_tsv(&items)),
+            _ => print!("{}", outline::to_tree(&items)),
         }
         Ok(())
     } else {
-        let mode = matches.get_one::<String>("mode").expect("Mode is required");
+        let mode_name = matches.get_one::<String>("mode").expect("Mode is required");
         let file_path = matches.get_one::<String>("file_path").expect("File path is required");
-        let default_structure_name = String::new();
-        let structure_name = matches.get_one::<String>("structure_name").unwrap_or(&default_structure_name);
-        let backup_file_path = format!("{}_before_revision", file_path);
+        let interactive = matches.get_flag("interactive");
+        let dry_run = matches.get_flag("dry_run");
+        let output_file = matches.get_one::<String>("output_file");
+        let output = matches.get_one::<String>("output").map(String::as_str).or(dry_run.then_some("diff"));
+        // Only a run that edits --file_path in place is journaled and committed.
+        let in_place = !dry_run && output_file.is_none();
+
+        let mode = settings.mode(mode_name).unwrap_or_else(|| {
+            eprintln!("Invalid mode: {}", mode_name);
+            eprintln!("Available modes: {}", settings.mode_names().join(", "));
+            std::process::exit(1);
+        });
 
-        eprintln!("mode: {}", mode);
+        eprintln!("mode: {}", mode.name);
+        eprintln!("scope: {}", mode.scope.name());
+        eprintln!("insertion: {}", mode.insertion.name());
         eprintln!("file_path: {}", file_path);
-        eprintln!("structure_name: {}", structure_name);
+
+        // Held until the process exits, so no other run edits the file meanwhile.
+        let _lock = if in_place {
+            match history::Journal::open().lock(file_path, matches.get_flag("wait")) {
+                Ok(lock) => Some(lock),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+
+        let commit_policy = if matches.get_flag("no_commit") { CommitPolicy::Never } else { settings.commit };
+        eprintln!("commit: {}", commit_policy.name());
+        if in_place && commit_policy != CommitPolicy::Never {
+            // Check the index before any request is sent, rather than after.
+            match git::staged_elsewhere(&[file_path]) {
+                Ok(staged) if !staged.is_empty() && commit_policy == CommitPolicy::Always && !matches.get_flag("force_commit") => {
+                    eprintln!("Other changes are already staged: {}", staged.join(", "));
+                    eprintln!("Commit or unstage them first, or pass --force-commit to commit the edited file on its own anyway.");
+                    std::process::exit(1);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Cannot read the git index: {}", e);
+                    eprintln!("Set commit = \"never\" or pass --no-commit to edit files outside a git repository.");
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        let branch = matches.get_one::<String>("branch");
+        // With `--no-commit`, branch_strategy is ignored and the edit stays on
+        // the checked out branch: the workers of `rfcu run` are started so, as
+        // `rfcu run` creates the branch and commits their edits itself.
+        let on_new_branch =
+            branch.is_some() || (settings.branch_strategy == BranchStrategy::New && !matches.get_flag("no_commit"));
+        if in_place && on_new_branch {
+            if commit_policy != CommitPolicy::Always {
+                eprintln!("A new branch needs commit = \"always\"; the commit setting is \"{}\".", commit_policy.name());
+                std::process::exit(1);
+            }
+            // The file is edited on the new branch, then checked out again from
+            // the previous one, so it must have no other change.
+            match git::has_uncommitted_changes(file_path) {
+                Ok(false) => {}
+                Ok(true) => {
+                    eprintln!("{} has uncommitted changes; commit or stash them before editing it on a new branch.", file_path);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Cannot read the status of {}: {}", file_path, e);
+                    std::process::exit(1);
+                }
+            }
+            if let Some(branch) = branch {
+                if let Err(e) = git::check_new_branch(branch) {
+                    eprintln!("Cannot create the branch: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
 
         eprintln!("Reading source code from file: {}", file_path);
         let source_code = fs::read_to_string(file_path).expect("Failed to read source file");
 
-        eprintln!("Creating backup file: {}", backup_file_path);
-        fs::write(&backup_file_path, &source_code).expect("Failed to create backup file");
+        let selectors = match structure_selectors(&matches, &source_code) {
+            Ok(selectors) => selectors,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        for selector in &selectors {
+            match selector {
+                structure::Selector::Path(path) => eprintln!("structure_name: {}", path),
+                structure::Selector::Enclosing { start_byte, end_byte } => {
+                    eprintln!("structure enclosing bytes: {} - {}", start_byte, end_byte)
+                }
+            }
+        }
+        let all_kind = matches.get_one::<String>("all_kind").map(String::as_str);
+        if let Some(kind) = all_kind {
+            eprintln!("all structures of kind: {}", kind);
+        }
+        let combined = matches.get_flag("combined");
+        if selectors.is_empty() && all_kind.is_none() && mode.scope == Scope::Structure {
+            eprintln!("The {} mode requires --structure_name, --all-kind, --line, --range or --byte-offset", mode.name);
+            std::process::exit(1);
+        }
+        if (selectors.len() > 1 || all_kind.is_some()) && mode.scope == Scope::WholeFile {
+            eprintln!("The {} mode edits the whole file and cannot take several structures", mode.name);
+            std::process::exit(1);
+        }
 
         eprintln!("Reading user input from stdin...");
         let mut stdin_content = String::new();
         io::stdin().read_to_string(&mut stdin_content)?;
-        let mut user_request = stdin_content.trim().to_string();
+        let user_request = stdin_content.trim().to_string();
         eprintln!("User request: {}", user_request);
 
-        eprintln!("Initializing parser and setting language: {}", settings.language);
-        let mut parser = Parser::new();
-        let language = match settings.language.as_str() {
-            "rust" => unsafe { tree_sitter_rust() },
-            _ => {
-                eprintln!("Unsupported language: {}", settings.language);
-                restore_backup(file_path, &backup_file_path);
+        let mut recording = if in_place {
+            match history::Recording::start(file_path, &mode.name, &user_request, &source_code) {
+                Ok(recording) => Some(recording),
+                Err(e) => {
+                    eprintln!("Failed to record the run in the journal: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+
+        let spec = match language::detect(settings.language.as_deref(), &settings.language_extensions, file_path) {
+            Ok(spec) => spec,
+            Err(e) => {
+                eprintln!("{}", e);
+                fail_run(file_path, recording.as_mut());
                 std::process::exit(1);
             }
         };
 
-        if let Err(e) = parser.set_language(&language) {
+        let mode_template = match mode.template_for(spec) {
+            Some(mode_template) => mode_template,
+            None => {
+                eprintln!("The {} mode has no template", mode.name);
+                fail_run(file_path, recording.as_mut());
+                std::process::exit(1);
+            }
+        };
+
+        eprintln!("Initializing parser and setting language: {}", spec.name);
+        let mut parser = Parser::new();
+        if let Err(e) = parser.set_language(&spec.grammar()) {
             eprintln!("Error setting language: {:?}", e);
-            restore_backup(file_path, &backup_file_path);
+            fail_run(file_path, recording.as_mut());
             std::process::exit(1);
         }
 
-        let mut retries = 0;
-        while retries < settings.max_retries {
-            retries += 1;
-            eprintln!("Attempt {} of {}", retries, settings.max_retries);
+        let backend = throttle::wrap(backend::from_config(&settings.backend), &settings);
 
-            let request;
-            let start_byte;
-            let end_byte;
+        let mut terminal = if interactive {
+            match review::Terminal::open() {
+                Ok(terminal) => Some(terminal),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    fail_run(file_path, recording.as_mut());
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
 
-            if mode == "improvement" || mode == "add_tests_function" || mode == "documentation_structure" {
-                eprintln!("Parsing the source code...");
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
-                eprintln!("Root node: {}", root_node.kind());
-                eprintln!("Structure name: {}", structure_name);
+        let mut validation_stages = settings.validation_stages();
+        if !in_place {
+            let (parse_stages, skipped): (Vec<_>, Vec<_>) =
+                validation_stages.into_iter().partition(|stage| stage.stage == config::StageKind::Parse);
+            if !skipped.is_empty() {
+                let names: Vec<&str> = skipped.iter().map(|stage| stage.name()).collect();
+                eprintln!("Skipping the validation stages that need the edit on disk: {}", names.join(", "));
+            }
+            validation_stages = parse_stages;
+        }
+        // An edit on a new branch is applied, validated and committed there,
+        // then the previous branch is checked out again.
+        let new_branch = if in_place && on_new_branch {
+            let branch = match branch {
+                Some(branch) => branch.clone(),
+                None => {
+                    let structure_name = parser
+                        .parse(&source_code, None)
+                        .and_then(|tree| {
+                            find_targets(mode.scope, &tree.root_node(), &source_code, file_path, &selectors, all_kind, spec).ok()
+                        })
+                        .map(|targets| targets.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>().join(", "))
+                        .unwrap_or_default();
+                    git::branch_name(&mode.name, &structure_name, file_path)
+                }
+            };
+            match git::switch_to_new_branch(&branch) {
+                Ok(previous) => Some((previous, branch)),
+                Err(e) => {
+                    eprintln!("Cannot create the branch {}: {}", branch, e);
+                    fail_run(file_path, recording.as_mut());
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        let leave_branch = |committed: bool| {
+            if let Some((previous, branch)) = &new_branch {
+                if let Err(e) = git::leave_branch(previous, branch, committed) {
+                    eprintln!("Failed to switch back to {}: {}", previous, e);
+                }
+            }
+        };
 
-                let structure_range = find_structure(&root_node, structure_name, source_code.as_bytes());
-                (start_byte, end_byte) = structure_range.unwrap();
+        let edit = Edit {
+            settings: &settings,
+            mode: &mode,
+            file_path,
+            source_code: &source_code,
+            selectors: &selectors,
+            all_kind,
+            spec,
+            mode_template: &mode_template,
+            combined,
+            user_request: &user_request,
+            backend: backend.as_ref(),
+            validation_stages: &validation_stages,
+        };
+        let Validated { updated_code, structure_name, report, attempts: retries } =
+            match edit_until_valid(&edit, &mut parser, terminal.as_mut(), recording.as_mut()) {
+                Ok(validated) => validated,
+                Err(EditError::Quit) => {
+                    eprintln!("No hunk was applied. The file was left unchanged.");
+                    if let Some(recording) = &mut recording {
+                        recording.finish(history::Status::Aborted);
+                    }
+                    leave_branch(false);
+                    return Ok(());
+                }
+                Err(EditError::Failed(e)) => {
+                    eprintln!("{}", e);
+                    fail_run(file_path, recording.as_mut());
+                    leave_branch(false);
+                    std::process::exit(1);
+                }
+            };
 
-                // Find the start byte of the attributes and code above the function
-                let properties_start_byte = find_properties_start_byte(&source_code, start_byte);
+        match output {
+            Some("json") => {
+                let change = diff::change(file_path, &mode.name, &structure_name, &source_code, &updated_code);
+                println!("{}", serde_json::to_string_pretty(&change).expect("Failed to serialize the change"));
+            }
+            Some(_) => print!("{}", diff::unified_diff(file_path, &source_code, &updated_code)),
+            None => {}
+        }
 
-                let original_structure = &source_code[properties_start_byte..end_byte];
-                //eprintln!("Original structure found: {}", original_structure);
+        if let Some(output_file) = output_file {
+            eprintln!("Writing the updated code to: {}", output_file);
+            files::write_atomic(output_file, updated_code.as_bytes())?;
+        }
 
-                //eprintln!("Preparing the request...");
-                request = match mode.as_str() {
-                    "improvement" => settings.requests.improvement.replace("{structure_code}", original_structure).replace("{user_request}", &user_request).replace("{structure_name}", &structure_name),
-                    "add_tests_function" => settings.requests.add_tests_function.replace("{structure_code}", original_structure),
-                    "documentation_structure" => settings.requests.documentation_structure.replace("{structure_code}", original_structure).replace("{user_request}", &user_request).replace("{structure_name}", &structure_name),
-                    _ => unreachable!(),
+        let mut recording = match recording {
+            Some(recording) => recording,
+            None => {
+                eprintln!("Left {} and git untouched.", file_path);
+                return Ok(());
+            }
+        };
+
+        let committed = match commit_policy {
+            CommitPolicy::Never => {
+                eprintln!("Leaving the changes to {} unstaged.", file_path);
+                Ok(())
+            }
+            CommitPolicy::Stage => git::stage_changes(&[file_path]).map(|()| eprintln!("Changes staged successfully.")),
+            CommitPolicy::Always => {
+                let diff = git::diff(&[file_path])
+                    .unwrap_or_else(|_| diff::unified_diff(&recording.run.file_path, &source_code, &updated_code));
+                let validation = match retries {
+                    1 => report.summary(),
+                    _ => format!("{}, on attempt {} of {}", report.summary(), retries, settings.max_retries),
                 };
-            } else if mode == "whole_file" || mode == "documentation_whole_file"  {
-                eprintln!("Preparing the whole file request...");
-                request = match mode.as_str() {
-                    "whole_file" => settings.requests.whole_file.replace("{source_code}", &source_code).replace("{user_request}", &user_request),
-                    "documentation_whole_file" => settings.requests.documentation_whole_file.replace("{source_code}", &source_code).replace("{user_request}", &user_request),
-                    _ => unreachable!(),
+                let details = commit_message::Details {
+                    file_path: &recording.run.file_path,
+                    mode: &mode.name,
+                    structure_name: &structure_name,
+                    user_request: &user_request,
+                    diff: &diff,
+                    validation: &validation,
                 };
-                start_byte = 0;
-                end_byte = source_code.len();
-            } else if mode == "add_functionality" {
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
-                eprintln!("Root node: {}", root_node.kind());
-                eprintln!("Structure name: {}", structure_name);
-                eprintln!("Preparing the request to add functionality...");
-                let structure_range = find_structure(&root_node, "main", source_code.as_bytes());
-                (start_byte, end_byte) = structure_range.unwrap();
-
-                // Find the start byte of the attributes and code above the function
-                let properties_start_byte = find_properties_start_byte(&source_code, start_byte);
-
-                let original_structure = &source_code[properties_start_byte..end_byte];
-                request = settings.requests.add_functionality.replace("{source_code}", &source_code).replace("{user_request}", &user_request);
-            } else {
-                eprintln!("Invalid mode: {}", mode);
-                restore_backup(file_path, &backup_file_path);
-                std::process::exit(1);
+                let commit_message = commit_message::generate(
+                    backend.as_ref(),
+                    &settings.commit_message_flow,
+                    settings.commit_style,
+                    settings.commit_template.as_deref(),
+                    settings.prompt_dir.as_deref(),
+                    &details,
+                );
+                match &new_branch {
+                    Some((_, branch)) => git::commit_changes(&[file_path], &commit_message).map(|()| {
+                        eprintln!("Changes committed on branch {}.", branch);
+                        eprintln!("Merge them with: git merge {}", branch);
+                        eprintln!("Or open a pull request with: git push -u origin {} && gh pr create --head {}", branch, branch);
+                    }),
+                    None => git::commit_changes(&[file_path], &commit_message).map(|()| eprintln!("Changes committed successfully.")),
+                }
             }
+        };
+        if let Err(e) = committed {
+            eprintln!("Failed to {} the changes: {}", if commit_policy == CommitPolicy::Stage { "stage" } else { "commit" }, e);
+            fail_run(file_path, Some(&mut recording));
+            leave_branch(false);
+            std::process::exit(1);
+        }
+        leave_branch(true);
 
-            eprintln!("Sending the request to fluentcli for improvement...");
-            let improved_structure = match improve_structure_with_fluentcli(&settings.flowname, &request, &user_request, file_path) {
-                Ok(structure) => structure,
-                Err(e) => {
-                    eprintln!("Error improving structure: {:?}", e);
-                    restore_backup(file_path, &backup_file_path);
-                    std::process::exit(1);
-                }
-            };
+        recording.finish(history::Status::Applied);
+        eprintln!("Run {} recorded. Revert it with: rfcu undo {}", recording.run.id, recording.run.id);
 
-            eprintln!("Improved structure received: {}", improved_structure);
-
-            let updated_code = if mode == "improvement"  {
-                eprintln!("Updating the source code with the improved structure...");
-                let mut updated_code = String::new();
-                let properties_start_byte = find_properties_start_byte(&source_code, start_byte);
-                updated_code.push_str(&source_code[..properties_start_byte]);
-                updated_code.push_str(&improved_structure);
-                updated_code.push_str(&source_code[end_byte..]);
-                updated_code
-            } else if mode == "get_structure" {
-                eprintln!("Reading source code from file: {}", file_path);
-                let source_code = fs::read_to_string(file_path).expect("Failed to read source file");
-
-                eprintln!("Initializing parser and setting language: {}", settings.language);
-                let mut parser = Parser::new();
-                let language = match settings.language.as_str() {
-                    "rust" => unsafe { tree_sitter_rust() },
-                    _ => {
-                        eprintln!("Unsupported language: {}", settings.language);
-                        std::process::exit(1);
-                    }
-                };
+        Ok(())
+    }
+}
 
-                if let Err(e) = parser.set_language(&language) {
-                    eprintln!("Error setting language: {:?}", e);
-                    std::process::exit(1);
-                }
+/// What the attempts of an edit are made from.
+struct Edit<'a> {
+    settings: &'a Settings,
+    mode: &'a Mode,
+    file_path: &'a str,
+    source_code: &'a str,
+    selectors: &'a [structure::Selector],
+    all_kind: Option<&'a str>,
+    spec: &'static LanguageSpec,
+    mode_template: &'a str,
+    combined: bool,
+    user_request: &'a str,
+    backend: &'a dyn CompletionBackend,
+    validation_stages: &'a [config::ValidationStage],
+}
 
-                eprintln!("Parsing the source code...");
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
+/// An attempt that passed validation.
+struct Validated {
+    updated_code: String,
+    structure_name: String,
+    report: validation::Report,
+    /// The number of attempts it took.
+    attempts: usize,
+}
 
-                let mut structures = Vec::new();
-                get_structures(&root_node, source_code.as_bytes(), &mut structures);
+/// Why an edit stopped without an attempt passing validation.
+enum EditError {
+    /// An error, or no attempt passed validation. The file may have been
+    /// written and must be restored.
+    Failed(String),
+    /// The interactive review applied nothing.
+    Quit,
+}
 
-                eprintln!("Structures found in the source code:");
-                for structure in structures {
-                    println!("{}", structure);
-                }
-                break;
-            } else if mode == "add_functionality" {
-                eprintln!("Adding new functionality to the script...");
-                let request = settings.requests.add_functionality.replace("{source_code}", &source_code);
-
-                eprintln!("Sending the request to fluentcli for improvement...");
-                let improved_structure = match improve_structure_with_fluentcli(&settings.flowname, &request, &user_request, file_path) {
-                    Ok(structure) => structure,
-                    Err(e) => {
-                        eprintln!("Error improving structure: {:?}", e);
-                        restore_backup(file_path, &backup_file_path);
-                        std::process::exit(1);
-                    }
-                };
+/// Sends the requests of the edit, splices the answers into the file and
+/// validates the result, retrying up to `max_retries` times. A failed attempt
+/// is restored before the next one; the caller restores the file and records
+/// the run when this returns an error.
+fn edit_until_valid(
+    edit: &Edit,
+    parser: &mut Parser,
+    mut terminal: Option<&mut review::Terminal>,
+    mut recording: Option<&mut history::Recording>,
+) -> Result<Validated, EditError> {
+    let Edit { settings, mode, file_path, source_code, spec, .. } = *edit;
+    let lock_edits = || throttle::lock_edits().map_err(|e| EditError::Failed(format!("Error locking the edits: {}", e)));
+    let mut previous_errors = String::new();
+    let mut retries = 0;
+    'attempts: while retries < settings.max_retries {
+        retries += 1;
+        eprintln!("Attempt {} of {}", retries, settings.max_retries);
 
-                eprintln!("New functionality received: {}", improved_structure);
-
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
-                let main_fn_range = find_main_function(&root_node, source_code.as_bytes());
-
-                if let Some((_, main_end)) = main_fn_range {
-                    eprintln!("main function found. Inserting new functionality after main...");
-                    let mut updated_code = source_code[..main_end].to_string();
-                    eprintln!("\n\n\n\n\n\nupdated_code: {}\n\n\n\n\n", updated_code);
-                    updated_code.push_str(&format!("\n\n{}\n\n", improved_structure));
-                    updated_code.push_str(&source_code[main_end..]);
-                    eprintln!("Updated code:\n{}", updated_code);
-                    updated_code
-                } else {
-                    eprintln!("main function not found. Appending new functionality at the end...");
-                    let mut updated_code = source_code.to_string();
-                    updated_code.push_str(&format!("\n\n{}\n\n", improved_structure));
-                    eprintln!("Updated code:\n{}", updated_code);
-                    updated_code
-                }
-            } else if mode == "documentation_structure" {
-                eprintln!("Replacing or inserting the documentation in the structure...");
-                let doc_comment = improved_structure.trim();
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
-                let struct_range = find_struct_node(&root_node, structure_name, source_code.as_bytes());
-                if struct_range.is_none() {
-                    eprintln!("Structure not found in the source code.");
-                    restore_backup(file_path, &backup_file_path);
-                    std::process::exit(1);
-                }
-                let (struct_start, struct_end) = struct_range.unwrap();
-                eprintln!("Struct found at byte range: {} - {}", struct_start, struct_end);
-                let mut updated_code = source_code.to_string();
-
-                // Find the documentation range above the structure
-                let (doc_start, doc_end) = find_documentation_range(&source_code, struct_start);
-                eprintln!("Documentation range: {} - {}", doc_start, doc_end);
-
-                if doc_start != doc_end {
-                    eprintln!("Replacing existing documentation...");
-                    let new_code = format!(
-                        "{}{}{}",
-                        &source_code[..doc_start],
-                        doc_comment,
-                        &source_code[doc_end..]
-                    );
-                    updated_code = new_code;
-                } else {
-                    eprintln!("Inserting new documentation...");
-                    updated_code.insert_str(struct_start, &format!("\n{}\n", doc_comment));
-                }
-                eprintln!("Updated code:\n{}", updated_code);
-                updated_code
-            } else if mode == "documentation_whole_file" {
-                eprintln!("Replacing the documentation in the whole file...");
-                let mut updated_code = String::new();
-
-                // Find the start and end of the existing documentation using tree-sitter
-                let (doc_start, doc_end) = find_doc_range_tree_sitter(&source_code, &language);
-
-                // Overwrite the existing documentation with the new documentation
-                updated_code.push_str(&source_code[..doc_start]);
-                updated_code.push_str(&improved_structure);
-                updated_code.push('\n');
-                updated_code.push_str(&source_code[doc_end..]);
-                updated_code
-            } else if mode == "add_tests_function" {
-                eprintln!("Adding test functions...");
-                let test_functions = improved_structure.trim();
-                let tree = parser.parse(&source_code, None).expect("Error parsing source code");
-                let root_node = tree.root_node();
-                let struct_range = find_structure(&root_node, structure_name, source_code.as_bytes());
-                if struct_range.is_none() {
-                    eprintln!("Target function not found in the source code.");
-                    restore_backup(file_path, &backup_file_path);
-                    std::process::exit(1);
-                }
-                let (struct_start, struct_end) = struct_range.unwrap();
-                eprintln!("Target function found at byte range: {} - {}", struct_start, struct_end);
-                eprintln!("Target function found at line range: {} - {}",
-                          source_code[..struct_start].lines().count(),
-                          source_code[..struct_end].lines().count()
-                );
-                let mut updated_code = source_code.to_string();
+        eprintln!("Parsing the source code...");
+        let tree = parser.parse(source_code, None).ok_or_else(|| EditError::Failed("Error parsing source code".to_string()))?;
+        let root_node = tree.root_node();
 
-                // Insert the test functions below the target function
-                eprintln!("Inserting test functions at byte: {}", struct_end);
-                eprintln!("Inserting test functions at line: {}", source_code[..struct_end].lines().count());
-                updated_code.insert_str(struct_end, &format!("\n{}\n", test_functions));
+        let targets = find_targets(mode.scope, &root_node, source_code, file_path, edit.selectors, edit.all_kind, spec)
+            .map_err(EditError::Failed)?;
+        for ((start_byte, end_byte), _) in &targets {
+            eprintln!("Target range: {} - {}", start_byte, end_byte);
+        }
+        let structure_name = targets.iter().map(|(_, name)| name.as_str()).collect::<Vec<_>>().join(", ");
+        if let Some(recording) = recording.as_deref_mut() {
+            recording.run.structure_name = structure_name.clone();
+        }
 
-                eprintln!("Updated code:\n{}", updated_code);
-                updated_code
+        eprintln!("Preparing the request...");
+        let mut requests = Vec::new();
+        for (target_range, target_name) in &targets {
+            let mut context = template::Context::new(settings.prompt_dir.as_ref().map(PathBuf::from));
+            context.insert("structure_code", &source_code[target_range.0..target_range.1]);
+            context.insert("source_code", source_code);
+            context.insert("user_request", edit.user_request);
+            context.insert("structure_name", target_name.as_str());
+            context.insert("file_path", file_path);
+            context.insert("language", spec.name);
+            context.insert("crate_name", find_crate_name(file_path));
+            context.insert("previous_errors", previous_errors.as_str());
+            add_surrounding_items(&mut context, &root_node, source_code, *target_range, mode.scope, spec);
+
+            let request = template::render(edit.mode_template, &context)
+                .map_err(|e| EditError::Failed(format!("Error rendering the template of the {} mode: {}", mode.name, e)))?;
+            requests.push(request);
+        }
+        if edit.combined && requests.len() > 1 {
+            requests = vec![combined_request(&targets, &requests)];
+        }
+
+        let mut instructions = String::new();
+        let mut edit_lock = None;
+        let attempt = loop {
+            let named_requests: Vec<(&str, String)> = requests
+                .iter()
+                .zip(&targets)
+                .map(|(request, (_, target_name))| {
+                    let name = if requests.len() == 1 { &structure_name } else { target_name };
+                    (name.as_str(), format!("{}{}", request, instructions))
+                })
+                .collect();
+            let responses = send_requests(
+                edit.backend,
+                mode,
+                &named_requests,
+                edit.user_request,
+                file_path,
+                settings.max_concurrency,
+                recording.as_deref_mut(),
+            )
+            .map_err(|e| EditError::Failed(format!("Error improving structure: {}", e)))?;
+            let improved_structures = if responses.len() == targets.len() {
+                responses.iter().map(|response| extract_code(response)).collect()
             } else {
-                improved_structure
+                match code_blocks(&responses[0], targets.len()) {
+                    Some(blocks) => blocks,
+                    None => {
+                        eprintln!("The answer does not have one code block per structure. Retrying...");
+                        previous_errors = format!(
+                            "The previous answer did not have exactly {} code blocks, one per request and in the same order.",
+                            targets.len()
+                        );
+                        if let Some(recording) = recording.as_deref_mut() {
+                            if let Some(exchange) = recording.run.exchanges.last_mut() {
+                                exchange.failed_stage = Some("response".to_string());
+                            }
+                            recording.save();
+                        }
+                        continue 'attempts;
+                    }
+                }
+            };
+
+            // Only the requests run concurrently: from here on, one run at a
+            // time splices, writes and validates its edit, until the file is
+            // restored or the edit passed. A review takes the lock once the
+            // changes are applied, so nobody waits on the user.
+            if terminal.is_none() {
+                edit_lock = Some(lock_edits()?);
+            }
+            // Splice from the bottom up, so the byte ranges of the targets
+            // above each edit stay valid.
+            let mut attempt = source_code.to_string();
+            for ((target_range, _), improved_structure) in targets.iter().zip(&improved_structures).rev() {
+                eprintln!("Improved structure received: {}", improved_structure);
+                attempt = splice_response(mode, &attempt, &root_node, *target_range, improved_structure, spec);
+            }
+            let terminal = match terminal.as_deref_mut() {
+                Some(terminal) => terminal,
+                None => break attempt,
             };
-            eprintln!("Writing the updated code to the original file...");
-            fs::write(file_path, updated_code.as_bytes())?;
-            eprintln!("Updated code written to the original file successfully.");
 
-            if let Some(lint_command) = &settings.lint_command {
-                eprintln!("Linting the updated code...");
-                if lint_code(lint_command).expect("Failed to lint the code") {
-                    eprintln!("Linting succeeded.");
-                    break;
-                } else {
-                    eprintln!("Linting failed. Restoring backup and retrying...");
-                    restore_backup(file_path, &backup_file_path);
+            match review::review(terminal, file_path, source_code, &attempt) {
+                Ok(review::Decision::Apply(code)) => break code,
+                Ok(review::Decision::Regenerate(instruction)) => {
+                    eprintln!("Asking for a new answer with the instruction: {}", instruction);
+                    instructions.push_str(&format!("\n\nAdditional instruction: {}", instruction));
                 }
-            } else {
-                break;
+                Ok(review::Decision::Quit) => return Err(EditError::Quit),
+                Err(e) => return Err(EditError::Failed(format!("Error reviewing the changes: {}", e))),
             }
-        }
-        let commit_message = generate_commit_message(file_path, mode, &settings.commit_message_flow)
-            .unwrap_or_else(|_| "Automated changes made by RFCU".to_string());
+        };
 
-        if let Err(e) = commit_changes(file_path, &commit_message) {
-            eprintln!("Failed to commit changes: {:?}", e);
-            restore_backup(file_path, &backup_file_path);
-            std::process::exit(1);
+        let _edit_lock = match edit_lock {
+            Some(edit_lock) => edit_lock,
+            None => lock_edits()?,
+        };
+        let target = validation::Target {
+            file_path,
+            original_code: source_code,
+            structure_name: &structure_name,
+            edited_lines: diagnostics::changed_lines(source_code, &attempt),
+            spec,
+        };
+        let report = validation::run(edit.validation_stages, &target, &attempt, || {
+            let recording = match recording.as_deref_mut() {
+                Some(recording) => recording,
+                None => return Ok(()),
+            };
+            // Record the new content first, so that `rfcu undo` recognizes it
+            // if the run is interrupted.
+            recording.run.after = Some(attempt.clone());
+            recording.save();
+            eprintln!("Writing the updated code to the original file...");
+            files::write_atomic(file_path, attempt.as_bytes())?;
+            eprintln!("Updated code written to the original file successfully.");
+            Ok(())
+        });
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                if let Some(recording) = recording.as_deref_mut() {
+                    restore_original(file_path, &recording.run.before);
+                }
+                return Err(EditError::Failed(format!("Error validating the edit: {}", e)));
+            }
+        };
+        report.print();
+
+        match report.failure() {
+            None => return Ok(Validated { updated_code: attempt, structure_name, report, attempts: retries }),
+            Some(failure) => {
+                eprintln!("Validation failed at the {} stage ({}). Restoring the file and retrying...", failure.name, failure.status.name());
+                previous_errors = failure.diagnostics.clone();
+                if let Some(recording) = recording.as_deref_mut() {
+                    restore_original(file_path, &recording.run.before);
+                    recording.run.after = None;
+                    if let Some(exchange) = recording.run.exchanges.last_mut() {
+                        exchange.failed_stage = Some(failure.name.clone());
+                    }
+                    recording.save();
+                }
+            }
         }
-
-        eprintln!("Changes committed successfully.");
-
-        eprintln!("Cleaning up the backup file...");
-        fs::remove_file(&backup_file_path)?;// ... (rest of your existing code goes here)
-
-        Ok(())
     }
+    Err(EditError::Failed(format!("No attempt passed validation after {} attempt(s). The file was left unchanged.", retries)))
 }
 
-fn retrieve_structures<'a>(node: &'a Node<'a>, source_code: &'a [u8], structures: &mut Vec<String>) {
-    let cursor = &mut node.walk();
-    for child in node.children(cursor) {
-        if node.kind() == "function_item"
-            || node.kind() == "mod_item"
-            || node.kind() == "struct_item"
-            || node.kind() == "enum_item"
-            || node.kind() == "trait_item"
-            || node.kind() == "impl_item"
-        {
-            let name_node = child.child_by_field_name("name");
-            if let Some(name_node) = name_node {
-                let name = std::str::from_utf8(name_node.utf8_text(source_code).unwrap().as_ref()).unwrap();
-                structures.push(format!("{}: {}", child.kind(), name));
+/// Stops with a usage error, as clap does for the other missing arguments.
+/// `--file_path` cannot be marked as required: it is global, so that
+/// `get_structure` and `config check` take it too.
+fn missing_file_path(command: &mut clap::Command) -> ! {
+    command
+        .error(clap::error::ErrorKind::MissingRequiredArgument, "the following required argument was not provided: --file_path <file_path>")
+        .exit()
+}
+
+/// Runs `rfcu history`, `rfcu show` or `rfcu undo` and returns the exit status.
+fn run_history_command(name: &str, matches: &clap::ArgMatches) -> i32 {
+    let journal = history::Journal::open();
+    let mut runs = match journal.runs() {
+        Ok(runs) => runs,
+        Err(e) => {
+            eprintln!("Failed to read the journal: {}", e);
+            return 1;
+        }
+    };
+    let run_id = matches.try_get_one::<String>("run_id").ok().flatten();
+    let selected = match run_id {
+        Some(run_id) => runs.iter().position(|run| run.id == *run_id),
+        None if name == "undo" => history::last_undoable(&runs),
+        None => None,
+    };
+
+    match name {
+        "history" => {
+            if runs.is_empty() {
+                eprintln!("No runs recorded yet.");
+            }
+            for run in &runs {
+                println!(
+                    "{}  {}  {:<7}  {}  {}  {}  {}",
+                    run.id,
+                    run.timestamp,
+                    run.status.name(),
+                    run.mode,
+                    run.file_path,
+                    if run.structure_name.is_empty() { "-" } else { &run.structure_name },
+                    history::summary(&run.user_request)
+                );
+            }
+            0
+        }
+        "show" => match selected.map(|index| &runs[index]) {
+            Some(run) => {
+                println!("run: {}", run.id);
+                println!("timestamp: {}", run.timestamp);
+                println!("status: {}", run.status.name());
+                println!("mode: {}", run.mode);
+                println!("file_path: {}", run.file_path);
+                println!("structure_name: {}", run.structure_name);
+                println!("user_request: {}", run.user_request);
+                for (index, exchange) in run.exchanges.iter().enumerate() {
+                    println!("\n=== Request {} ===\n{}", index + 1, exchange.prompt);
+                    println!("\n=== Response {} ===\n{}", index + 1, exchange.response);
+                    if let Some(failed_stage) = &exchange.failed_stage {
+                        println!("\nRejected by the {} validation stage.", failed_stage);
+                    }
+                }
+                if let Some(after) = &run.after {
+                    println!("\n=== Diff ===");
+                    print!("{}", diff::unified_diff(&run.file_path, &run.before, after));
+                }
+                0
+            }
+            None => {
+                eprintln!("No run {} in the journal. See `rfcu history`.", run_id.map_or("", String::as_str));
+                1
+            }
+        },
+        _ => {
+            let run = match selected {
+                Some(index) => &mut runs[index],
+                None => {
+                    match run_id {
+                        Some(run_id) => eprintln!("No run {} in the journal. See `rfcu history`.", run_id),
+                        None => eprintln!("No applied run to undo."),
+                    }
+                    return 1;
+                }
+            };
+            match history::undo(&journal, run, matches.get_flag("force")) {
+                Ok(path) => {
+                    eprintln!("Restored {} to its content from before run {}.", path.display(), run.id);
+                    eprintln!("The change is not committed; review it with `git diff`.");
+                    0
+                }
+                Err(e) => {
+                    eprintln!("Cannot undo run {}: {}", run.id, e);
+                    1
+                }
             }
         }
-        retrieve_structures(&child, source_code, structures);
     }
 }
 
-fn find_structure<'a>(node: &'a Node<'a>, structure_name: &str, source_code: &'a [u8]) -> Option<(usize, usize)> {
-    let mut cursor = node.walk();
-    //eprintln!("Searching for structure in node: {}", node.kind());
-    //eprintln!("structure_name: {}", structure_name);
-    //eprintln!("Searching for structure: {}", structure_name);
-    //eprintln!("Current node name: {}", node.kind());
+/// Returns the byte range a mode works on. For the `structure` scope this is the
+/// named structure including its attributes or decorators, for `whole_file` the
+/// entire file, and for `append` the named structure, `main`, or the empty range
+/// at the end of the file, in that order. Also returns the name of the selected
+/// structure: the path as given, or the qualified path of the structure found
+/// at a position.
+fn find_target_range(
+    scope: Scope,
+    root_node: &Node,
+    source_code: &str,
+    file_path: &str,
+    selector: Option<&structure::Selector>,
+    spec: &LanguageSpec,
+) -> Result<((usize, usize), String), String> {
+    let module_path = structure::module_path(file_path, spec);
+    let structure_range = |selector: &structure::Selector| {
+        structure::find(*root_node, selector, source_code.as_bytes(), spec, &module_path).map(|item| {
+            let name = match selector {
+                structure::Selector::Path(path) => path.clone(),
+                structure::Selector::Enclosing { .. } => item.qualified_name(),
+            };
+            ((structure::structure_start_byte(&item.node, spec), item.node.end_byte()), name)
+        })
+    };
 
-    if node.kind() == "function_item"
-        || node.kind() == "mod_item"
-        || node.kind() == "struct_item"
-        || node.kind() == "enum_item"
-        || node.kind() == "trait_item"
-        || node.kind() == "impl_item"
-    {
-        if let Some(name_node) = node.child_by_field_name("name") {
-            //eprintln!("Found name node: {}", name_node.kind());
-            let name = name_node.utf8_text(source_code).unwrap();
-            //eprintln!("Found structure: {}", name);
-            if name == structure_name {
-                eprintln!("Structure found!");
-                return Some((node.start_byte(), node.end_byte()));
+    match (scope, selector) {
+        (Scope::WholeFile, _) => Ok(((0, source_code.len()), String::new())),
+        (_, Some(selector)) => structure_range(selector),
+        (Scope::Structure, None) => Err("No structure selected".to_string()),
+        (Scope::Append, None) => match find_main_function(root_node, source_code.as_bytes(), spec) {
+            Some(range) => {
+                eprintln!("main function found. Inserting new functionality after main...");
+                Ok((range, String::new()))
             }
-        }
+            None => {
+                eprintln!("main function not found. Appending new functionality at the end...");
+                Ok(((source_code.len(), source_code.len()), String::new()))
+            }
+        },
     }
+}
 
-    for child in node.children(&mut cursor) {
-        //eprintln!("Traversing child node: {}", child.kind());
-        if let Some(found) = find_structure(&child, structure_name, source_code) {
-            eprintln!("Structure found in child node!");
-            return Some(found);
+/// The byte range a run edits and the name of its structure.
+type TargetRange = ((usize, usize), String);
+
+/// Returns the byte ranges and names of the targets of a run, in source order:
+/// one per selector, every structure of kind `all_kind`, or the single target
+/// of a mode run without a selector. Structures nested in another structure
+/// of the same kind are left to the outer one; other overlaps are errors.
+fn find_targets(
+    scope: Scope,
+    root_node: &Node,
+    source_code: &str,
+    file_path: &str,
+    selectors: &[structure::Selector],
+    all_kind: Option<&str>,
+    spec: &LanguageSpec,
+) -> Result<Vec<TargetRange>, String> {
+    let mut targets: Vec<TargetRange> = Vec::new();
+    if let Some(kind) = all_kind {
+        if !spec.is_structure_kind(kind) {
+            return Err(format!(
+                "'{}' is not a kind of structure in {} files, expected one of: {}",
+                kind,
+                spec.name,
+                spec.structure_kinds.join(", ")
+            ));
+        }
+        let module_path = structure::module_path(file_path, spec);
+        for item in structure::items(*root_node, source_code.as_bytes(), spec, &module_path) {
+            if item.node.kind() != kind || targets.last().is_some_and(|((_, end_byte), _)| item.node.end_byte() <= *end_byte) {
+                continue;
+            }
+            targets.push(((structure::structure_start_byte(&item.node, spec), item.node.end_byte()), item.qualified_name()));
+        }
+        if targets.is_empty() {
+            return Err(format!("No {} structure in {}", kind, file_path));
+        }
+    } else if selectors.is_empty() {
+        targets.push(find_target_range(scope, root_node, source_code, file_path, None, spec)?);
+    } else {
+        for selector in selectors {
+            targets.push(find_target_range(scope, root_node, source_code, file_path, Some(selector), spec)?);
         }
     }
 
-    //aeprintln!("Structure not found in current subtree.");
-    None
+    targets.sort_by_key(|(range, _)| *range);
+    targets.dedup_by_key(|(range, _)| *range);
+    if let Some(pair) = targets.windows(2).find(|pair| pair[1].0 .0 < pair[0].0 .1) {
+        return Err(format!("{} and {} overlap, select only one of them", pair[0].1, pair[1].1));
+    }
+    Ok(targets)
 }
 
-fn find_first_code_line_after_docs(source_code: &str) -> usize {
-    for (i, line) in source_code.lines().enumerate() {
-        if !line.trim().starts_with("*") && !line.trim().starts_with("/") {
-            return source_code.lines().take(i).map(|l| l.len() + 1).sum();
-        }
+/// Builds the structure selectors from `--structure_name`, `--line`, `--range`
+/// or `--byte-offset`, converting lines to byte ranges of `source_code`. Only
+/// `--structure_name` selects more than one structure.
+fn structure_selectors(matches: &clap::ArgMatches, source_code: &str) -> Result<Vec<structure::Selector>, String> {
+    let paths: Vec<String> = matches
+        .get_many::<String>("structure_name")
+        .into_iter()
+        .flatten()
+        .flat_map(|list| structure::split_list(list))
+        .collect();
+    if !paths.is_empty() {
+        return Ok(paths.into_iter().map(structure::Selector::Path).collect());
     }
-    0
-}
 
-fn improve_structure_with_fluentcli(flowname: &str, request: &str, user_request: &str, source_code_path: &str) -> io::Result<String> {
-    eprintln!("Starting fluentcli with flowname: {}, request: {}", flowname, request);
-    let mut child = Command::new("fluent")
-        .arg(flowname)
-        .arg(request)
-        .arg("--additional-context-file")
-        .arg(source_code_path)
-        .arg("-p")  // Parse the code blocks
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to start fluent");
-
-    eprintln!("Writing user request to fluentcli stdin...");
-    {
-        let stdin = child.stdin.as_mut().expect("Failed to open stdin");
-        stdin.write_all(user_request.as_bytes()).expect("Failed to write to stdin");
-    }
-
-    let output = child.wait_with_output().expect("Failed to read stdout");
-
-    let response = String::from_utf8(output.stdout).expect("Invalid UTF-8 output");
-    eprintln!("Response from fluentcli:\n\n\n\n {}", response);
-
-    // Extract the code block from the response
-    let code_block_re = Regex::new(r"```(?:rust|python|ruby|javascript|typescript|bash|sh)?\n(.*?)```").unwrap();
-    let improved_structure = if let Some(captures) = code_block_re.captures(&response) {
-        captures.get(1).map_or("", |m| m.as_str()).to_string()
+    let lines = if let Some(&line) = matches.get_one::<usize>("line") {
+        Some((line, line))
+    } else if let Some(range) = matches.get_one::<String>("range") {
+        let parsed = range
+            .split_once(':')
+            .and_then(|(first, last)| Some((first.trim().parse().ok()?, last.trim().parse().ok()?)));
+        match parsed {
+            Some(lines) => Some(lines),
+            None => return Err(format!("Invalid --range '{}', expected FIRST:LAST line numbers", range)),
+        }
     } else {
-        response  // In case the response is not in the expected format
+        None
     };
 
-    Ok(improved_structure.trim().to_string())
-}
-
-fn lint_code(lint_command: &str) -> io::Result<bool> {
-    eprintln!("Executing lint command: {}", lint_command);
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(lint_command)
-        .output()
-        .expect("Failed to execute lint command");
+    if let Some((first, last)) = lines {
+        let (start_byte, end_byte) = structure::line_range(source_code, first, last)
+            .ok_or_else(|| format!("Lines {} to {} are not in the file ({} lines)", first, last, source_code.lines().count()))?;
+        eprintln!("Lines {} to {} are bytes {} - {}", first, last, start_byte, end_byte);
+        return Ok(vec![structure::Selector::Enclosing { start_byte, end_byte }]);
+    }
 
-    Ok(output.status.success())
+    match matches.get_one::<usize>("byte_offset") {
+        Some(&offset) if offset > source_code.len() => {
+            Err(format!("Byte offset {} is past the end of the file ({} bytes)", offset, source_code.len()))
+        }
+        Some(&offset) => Ok(vec![structure::Selector::Enclosing { start_byte: offset, end_byte: offset }]),
+        None => Ok(Vec::new()),
+    }
 }
 
-fn commit_changes(file_path: &str, message: &str) -> io::Result<()> {
-    eprintln!("Adding changes to git...");
-    Command::new("git")
-        .args(&["add", file_path])
-        .output()
-        .expect("Failed to add changes to git");
-
-    eprintln!("Committing changes to git...");
-    Command::new("git")
-        .args(&["commit", "-m", message])
-        .output()
-        .expect("Failed to commit changes");
+/// Finds the structure node a target range was computed from.
+fn find_target_node<'tree>(root_node: &Node<'tree>, target_range: (usize, usize), spec: &LanguageSpec) -> Option<Node<'tree>> {
+    let (start_byte, end_byte) = target_range;
+    if start_byte == end_byte || (start_byte == 0 && end_byte == root_node.end_byte()) {
+        return None;
+    }
 
-    Ok(())
+    let mut node = root_node.descendant_for_byte_range(end_byte - 1, end_byte);
+    while let Some(current) = node {
+        if current.end_byte() == end_byte && spec.is_structure_kind(current.kind()) {
+            return Some(current);
+        }
+        node = current.parent();
+    }
+    None
 }
 
-fn restore_backup(file_path: &str, backup_file_path: &str) {
-    eprintln!("Restoring backup file: {}", backup_file_path);
-    if let Err(e) = fs::copy(backup_file_path, file_path) {
-        eprintln!("Failed to restore backup file: {:?}", e);
-    } else {
-        eprintln!("Backup restored successfully.");
+/// Returns the package name from the `Cargo.toml` closest to `file_path`, or an
+/// empty string when there is none.
+fn find_crate_name(file_path: &str) -> String {
+    let file_path = fs::canonicalize(file_path).unwrap_or_else(|_| PathBuf::from(file_path));
+    for dir in file_path.ancestors().skip(1) {
+        let manifest_path = dir.join("Cargo.toml");
+        if let Ok(manifest) = fs::read_to_string(&manifest_path) {
+            if let Ok(manifest) = manifest.parse::<toml::Value>() {
+                if let Some(name) = manifest.get("package").and_then(|p| p.get("name")).and_then(|n| n.as_str()) {
+                    return name.to_string();
+                }
+            }
+        }
     }
+    String::new()
 }
 
-fn generate_commit_message(file_path: &str, mode: &str, flowname: &str) -> io::Result<String> {
-    eprintln!("Generating detailed commit message using FluentCLI...");
-    let request = format!("Generate a commit message for the changes made in {} mode to the file {} on a single line, it should be succinct.", mode, file_path);
-
-    let mut child = Command::new("fluent")
-        .arg(flowname)
-        .arg(request)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to start fluent");
+/// Adds the type of the `impl` block or class around the target (`impl_type`)
+/// and the signatures of the items next to it (`siblings` and `sibling_signatures`).
+fn add_surrounding_items(
+    context: &mut template::Context,
+    root_node: &Node,
+    source_code: &str,
+    target_range: (usize, usize),
+    scope: Scope,
+    spec: &LanguageSpec,
+) {
+    let mut impl_type = String::new();
+    let mut siblings = Vec::new();
+
+    let target = if scope == Scope::WholeFile {
+        None
+    } else {
+        find_target_node(root_node, target_range, spec)
+    };
 
-    let output = child.wait_with_output().expect("Failed to read stdout");
+    if let Some(target) = target {
+        let mut ancestor = target.parent();
+        while let Some(current) = ancestor {
+            let type_field = match current.kind() {
+                "impl_item" => Some("type"),
+                kind if kind.contains("class") || kind.contains("interface") => Some("name"),
+                _ => None,
+            };
+            if let Some(type_node) = type_field.and_then(|field| current.child_by_field_name(field)) {
+                impl_type = type_node.utf8_text(source_code.as_bytes()).unwrap_or_default().to_string();
+                break;
+            }
+            ancestor = current.parent();
+        }
 
-    let response = String::from_utf8(output.stdout).expect("Invalid UTF-8 output");
-    eprintln!("Response from fluentcli: {}", response);
+        if let Some(parent) = target.parent() {
+            let mut cursor = parent.walk();
+            for sibling in parent.named_children(&mut cursor) {
+                if sibling.id() == target.id() || !spec.is_structure_kind(sibling.kind()) {
+                    continue;
+                }
+                let name = match structure::segment(&sibling, source_code.as_bytes(), spec) {
+                    Some(segment) => segment.name,
+                    None => continue,
+                };
+                let mut item = BTreeMap::new();
+                item.insert("name".to_string(), template::Value::Text(name));
+                item.insert("kind".to_string(), template::Value::Text(sibling.kind().to_string()));
+                item.insert("signature".to_string(), template::Value::Text(structure::item_signature(&sibling, source_code)));
+                siblings.push(item);
+            }
+        }
+    }
 
-    Ok(response.trim().to_string())
+    let sibling_signatures = siblings
+        .iter()
+        .filter_map(|item| match item.get("signature") {
+            Some(template::Value::Text(signature)) => Some(signature.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    context.insert("impl_type", impl_type);
+    context.insert("sibling_signatures", sibling_signatures);
+    context.insert("siblings", template::Value::List(siblings));
 }
 
-fn find_struct_node<'a>(node: &'a Node<'a>, struct_name: &str, source_code: &'a [u8]) -> Option<(usize, usize)> {
-    //eprintln!("Searching for struct: {}", struct_name);
-    //eprintln!("Current node kind: {}", node.kind());
-
-    if node.kind() == "function_item"
-        || node.kind() == "mod_item"
-        || node.kind() == "struct_item"
-        || node.kind() == "enum_item"
-        || node.kind() == "trait_item"
-        || node.kind() == "impl_item" {
-        if let Some(name_node) = node.child_by_field_name("name") {
-            let name = name_node.utf8_text(source_code).unwrap();
-            //eprintln!("Found function: {}", name);
-            if name == struct_name {
-                eprintln!("Struct found!");
-                return Some((node.start_byte(), node.end_byte()));
+/// Puts the LLM response back into the source code according to the mode's insertion strategy.
+fn splice_response(
+    mode: &Mode,
+    source_code: &str,
+    root_node: &Node,
+    target_range: (usize, usize),
+    response: &str,
+    spec: &LanguageSpec,
+) -> String {
+    let (start_byte, end_byte) = target_range;
+    let line_start = source_code[..start_byte].rfind('\n').map_or(0, |i| i + 1);
+    let indent: String = source_code[line_start..start_byte].chars().take_while(|c| c.is_whitespace()).collect();
+    let raw_response = response.trim();
+    let response = reindent(response.trim_matches('\n'), &indent);
+    let response = response.as_str();
+
+    match mode.insertion {
+        Insertion::Replace => {
+            eprintln!("Replacing the target with the response...");
+            format!("{}{}{}", &source_code[..start_byte], response, &source_code[end_byte..])
+        }
+        Insertion::InsertBefore => {
+            eprintln!("Inserting the response before the target at byte: {}", start_byte);
+            format!("{}{}\n\n{}{}", &source_code[..start_byte], response, indent, &source_code[start_byte..])
+        }
+        Insertion::InsertAfter => {
+            eprintln!("Inserting the response after the target at byte: {}", end_byte);
+            eprintln!("Inserting the response at line: {}", source_code[..end_byte].lines().count());
+            let separator = if source_code[end_byte..].starts_with('\n') { "" } else { "\n" };
+            format!("{}\n\n{}{}{}{}", &source_code[..end_byte], indent, response, separator, &source_code[end_byte..])
+        }
+        Insertion::AppendToCfgTest if spec.name == "rust" => insert_test_functions(source_code, response),
+        Insertion::AppendToCfgTest => {
+            eprintln!("Appending the response at the end of the file...");
+            format!("{}\n\n{}\n", source_code.trim_end(), response)
+        }
+        Insertion::ReplaceDocs if mode.scope == Scope::WholeFile => {
+            eprintln!("Replacing the documentation in the whole file...");
+            let (doc_start, doc_end) = find_doc_range_tree_sitter(root_node, spec);
+            format!("{}{}\n{}", &source_code[..doc_start], response, &source_code[doc_end..])
+        }
+        Insertion::ReplaceDocs if spec.doc_style == DocStyle::Docstring => {
+            eprintln!("Replacing or inserting the docstring in the structure...");
+            match find_target_node(root_node, target_range, spec) {
+                Some(node) => replace_docstring(source_code, &node, raw_response),
+                None => source_code.to_string(),
             }
         }
-    }
-
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        if let Some(struct_range) = find_struct_node(&child, struct_name, source_code) {
-            return Some(struct_range);
+        Insertion::ReplaceDocs => {
+            eprintln!("Replacing or inserting the documentation in the structure...");
+            let (doc_start, doc_end) = structure::find_documentation_range(source_code, start_byte, spec.doc_style);
+            eprintln!("Documentation range: {} - {}", doc_start, doc_end);
+
+            if doc_start != doc_end {
+                eprintln!("Replacing existing documentation...");
+                format!("{}{}{}", &source_code[..doc_start], response, &source_code[doc_end..])
+            } else {
+                eprintln!("Inserting new documentation...");
+                format!("{}{}\n{}{}", &source_code[..start_byte], response, indent, &source_code[start_byte..])
+            }
         }
     }
-
-    //eprintln!("Struct not found in current subtree.");
-    None
 }
 
+/// Removes the indentation shared by every line of `code` and indents all lines
+/// but the first with `indent`, so the code lines up when spliced in after
+/// existing indentation.
+fn reindent(code: &str, indent: &str) -> String {
+    let common_indent = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    code.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line.get(common_indent..).unwrap_or_else(|| line.trim_start());
+            if i == 0 || line.is_empty() {
+                line.to_string()
+            } else {
+                format!("{}{}", indent, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
+/// Replaces the docstring at the top of a Python body, or inserts one.
+fn replace_docstring(source_code: &str, node: &Node, docstring: &str) -> String {
+    let body = match node.child_by_field_name("body") {
+        Some(body) => body,
+        None => return source_code.to_string(),
+    };
 
-fn find_doc_node<'a>(node: &Node<'a>) -> Option<Node<'a>> {
-    let mut cursor = node.walk();
-    let mut prev_node: Option<Node<'a>> = None;
+    let line_start = source_code[..body.start_byte()].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &source_code[line_start..body.start_byte()];
+    let docstring = reindent(docstring, indent);
 
-    for child in node.children(&mut cursor) {
-        if child.kind() == "block_comment" {
-            prev_node = Some(child);
-        } else if child.kind() == "function_item" {
-            return prev_node;
+    match structure::find_docstring(node) {
+        Some(statement) => {
+            eprintln!("Replacing existing docstring...");
+            format!("{}{}{}", &source_code[..statement.start_byte()], docstring, &source_code[statement.end_byte()..])
+        }
+        None => {
+            eprintln!("Inserting new docstring...");
+            format!("{}{}\n{}{}", &source_code[..body.start_byte()], docstring, indent, &source_code[body.start_byte()..])
         }
     }
-
-    None
 }
 
-fn find_doc_range<'a>(node: &Node<'a>, source_code: &'a [u8]) -> Option<(usize, usize)> {
-    let mut cursor = node.walk();
-    let mut doc_start = None;
-    let mut doc_end = None;
+fn run_config_check(config_flag: Option<&str>, file_path: &str) -> i32 {
+    let diagnostics = config_check::check_config(config_flag, Some(file_path));
 
-    for child in node.children(&mut cursor) {
-        if child.kind() == "block_comment" {
-            if doc_start.is_none() {
-                doc_start = Some(child.start_byte());
+    let mut errors = 0;
+    for diagnostic in &diagnostics {
+        let label = match diagnostic.severity {
+            config_check::Severity::Error => {
+                errors += 1;
+                "error"
             }
-            doc_end = Some(child.end_byte());
-        } else if child.kind() == "function_item" {
-            break;
-        }
+            config_check::Severity::Warning => "warning",
+        };
+        println!("{}: {}: {}", label, diagnostic.location, diagnostic.message);
     }
 
-    if let (Some(start), Some(end)) = (doc_start, doc_end) {
-        Some((start, end))
+    if errors > 0 {
+        eprintln!("Configuration check failed with {} error(s) and {} warning(s).", errors, diagnostics.len() - errors);
+        1
     } else {
-        None
+        eprintln!("Configuration is valid ({} warning(s)).", diagnostics.len());
+        0
     }
 }
 
-fn find_doc_end(source_code: &str) -> usize {
-    let mut doc_end = 0;
-    for (i, line) in source_code.lines().enumerate() {
-        if line.trim().starts_with("//!") || line.trim().starts_with("/*!") {
-            doc_end = source_code.lines().take(i + 1).map(|l| l.len() + 1).sum();
-        } else if line.trim().starts_with("//") || line.trim().starts_with("/*") {
-            continue;
-        } else {
-            break;
-        }
-    }
-    doc_end
-}
-
-
-
+/// Sends the requests of the targets, `max_concurrency` at a time, and
+/// returns the answers in the order of the requests, so the edits are applied
+/// in that order whichever answer comes first. Fails when any request failed.
+fn send_requests(
+    backend: &dyn CompletionBackend,
+    mode: &Mode,
+    requests: &[(&str, String)],
+    user_request: &str,
+    file_path: &str,
+    max_concurrency: usize,
+    mut recording: Option<&mut history::Recording>,
+) -> io::Result<Vec<String>> {
+    let send = |(structure_name, request): &(&str, String)| {
+        eprintln!("Sending the request for {} to {} using flow: {}", structure_name, backend.name(), mode.flow);
+        backend.complete(&CompletionRequest {
+            mode: &mode.name,
+            structure: structure_name,
+            flow: &mode.flow,
+            prompt: request,
+            user_request,
+            context_file: Some(file_path),
+            code_only: true,
+        })
+    };
 
-fn find_struct_start<'a>(node: &Node<'a>, source_code: &'a [u8]) -> usize {
-    let mut cursor = node.walk();
-    for child in node.children(&mut cursor) {
-        if child.kind() == "identifier" {
-            return node.start_byte();
+    let workers = max_concurrency.clamp(1, requests.len().max(1));
+    let mut answers: Vec<(usize, io::Result<String>)> = if workers == 1 {
+        requests.iter().map(send).enumerate().collect()
+    } else {
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut answers = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::SeqCst);
+                            match requests.get(index) {
+                                Some(request) => answers.push((index, send(request))),
+                                None => return answers,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().expect("A request worker panicked")).collect()
+        })
+    };
+    answers.sort_by_key(|(index, _)| *index);
+
+    let mut responses = Vec::new();
+    for ((_, answer), (_, request)) in answers.into_iter().zip(requests) {
+        let response = answer?;
+        eprintln!("Response from {}:\n\n\n\n {}", backend.name(), response);
+        if let Some(recording) = recording.as_deref_mut() {
+            recording.run.exchanges.push(history::Exchange {
+                prompt: request.clone(),
+                response: response.clone(),
+                failed_stage: None,
+            });
+            recording.save();
         }
+        responses.push(response);
     }
-    node.start_byte()
+    Ok(responses)
 }
 
-fn find_doc_start(source_code: &str, struct_start: usize) -> Option<usize> {
-    let mut doc_start = struct_start;
-    let lines: Vec<&str> = source_code[..struct_start].lines().collect();
-
-    for line in lines.iter().rev() {
-        if line.trim().starts_with("/**") {
-            doc_start = source_code[..struct_start].find(line).unwrap();
-            break;
-        } else if !line.trim().is_empty() {
-            break;
-        }
+/// Joins the requests of several structures into one that asks for a code
+/// block per structure, in order.
+fn combined_request(targets: &[TargetRange], requests: &[String]) -> String {
+    let mut combined = format!(
+        "The {} requests below each concern one structure. Answer with exactly {} code blocks, one per request and in the same order.\n",
+        requests.len(),
+        requests.len()
+    );
+    for (index, ((_, name), request)) in targets.iter().zip(requests).enumerate() {
+        combined.push_str(&format!("\n## Request {}: {}\n\n{}\n", index + 1, name, request));
     }
+    combined
+}
 
-    if doc_start < struct_start {
-        Some(doc_start)
+/// Code blocks in a response to a request, with any language tag. `(?s)` lets
+/// a block span several lines.
+const CODE_BLOCK_PATTERN: &str = r"(?s)```[A-Za-z0-9_+-]*\r?\n(.*?)```";
+
+/// Returns the code blocks of a combined response, or `None` unless there are
+/// exactly `count` of them.
+fn code_blocks(response: &str, count: usize) -> Option<Vec<String>> {
+    let code_block_re = Regex::new(CODE_BLOCK_PATTERN).unwrap();
+    let blocks: Vec<String> = code_block_re
+        .captures_iter(response)
+        .map(|captures| captures.get(1).map_or("", |m| m.as_str()).trim().to_string())
+        .collect();
+    if blocks.len() == count {
+        Some(blocks)
     } else {
         None
     }
 }
 
-// Update the find_doc_node function
-fn update_documentation(source_code: &str, struct_range: (usize, usize), improved_structure: &str) -> String {
-    let doc_comment = format!("/**\n{}\n*/", improved_structure.trim());
-    let (struct_start, struct_end) = struct_range;
-    let (doc_start, doc_end) = find_documentation_range(source_code, struct_start);
+/// Returns the first code block of a response, or the whole response when it
+/// has none.
+fn extract_code(response: &str) -> String {
+    let code_block_re = Regex::new(CODE_BLOCK_PATTERN).unwrap();
+    let improved_structure = match code_block_re.captures(response) {
+        Some(captures) => captures.get(1).map_or("", |m| m.as_str()),
+        None => response, // In case the response is not in the expected format
+    };
 
-    let mut updated_code = source_code.to_string();
-    updated_code.replace_range(doc_start..doc_end, &doc_comment);
-    updated_code
+    improved_structure.trim().to_string()
 }
 
-fn find_documentation_range(source_code: &str, struct_start: usize) -> (usize, usize) {
-    let mut doc_start = struct_start;
-    let mut doc_end = struct_start;
-
-    // Split the source code into lines and iterate backwards from the struct start line
-    let lines: Vec<&str> = source_code[..struct_start].lines().collect();
-    eprintln!("Total lines before struct: {}", lines.len());
-    for i in (0..lines.len()).rev() {
-        let line = lines[i].trim();
-        eprintln!("Checking line {} for doc start: {}", i, line);
-        if line.starts_with("/**") {
-            // Found the start of the documentation block
-            doc_start = source_code[..source_code[..struct_start].rfind(line).unwrap()].len();
-            eprintln!("Doc start found at line {}, byte {}", i, doc_start);
-            break;
-        } else if line.starts_with("*/") || line.starts_with("*") {
-            continue;
-        } else if !line.is_empty() {
-            break;
-        }
+/// Writes the original content back to the file when it was changed.
+fn restore_original(file_path: &str, original: &str) {
+    if fs::read_to_string(file_path).is_ok_and(|current| current == original) {
+        return;
     }
-
-    if doc_start < struct_start {
-        // Iterate from the found doc_start to struct_start to find the end of the documentation block
-        let doc_lines: Vec<&str> = source_code[doc_start..struct_start].lines().collect();
-        eprintln!("Total lines in doc block: {}", doc_lines.len());
-        for (i, line) in doc_lines.iter().enumerate() {
-            eprintln!("Checking doc line {} for doc end: {}", i, line);
-            if line.trim().ends_with("*/") {
-                // Found the end of the documentation block
-                doc_end = doc_start + source_code[doc_start..].find(line).unwrap() + line.len();
-                eprintln!("Doc end found at byte {}", doc_end);
-                break;
-            }
-        }
+    eprintln!("Restoring the original content of {}", file_path);
+    if let Err(e) = files::write_atomic(file_path, original) {
+        eprintln!("Failed to restore the original content: {:?}", e);
+    } else {
+        eprintln!("Original content restored successfully.");
     }
+}
 
-    if doc_start == struct_start || doc_end == struct_start {
-        eprintln!("No documentation block found.");
-        return (struct_start, struct_start);
+/// Restores the file of a run that stopped on an error and records it as
+/// failed. Runs that do not edit the file in place have nothing to restore.
+fn fail_run(file_path: &str, recording: Option<&mut history::Recording>) {
+    if let Some(recording) = recording {
+        restore_original(file_path, &recording.run.before);
+        recording.run.after = None;
+        recording.finish(history::Status::Failed);
     }
-
-    (doc_start, doc_end)
 }
 
-
-fn find_doc_range_tree_sitter(source_code: &str, language: &Language) -> (usize, usize) {
-    let mut parser = Parser::new();
-    parser.set_language(language).expect("Error setting language");
-    let tree = parser.parse(source_code, None).expect("Error parsing source code");
-    let root_node = tree.root_node();
-
-    let mut doc_start = std::usize::MAX;
+/// Finds the documentation at the top of the file: the leading comments, or a
+/// module docstring for Python.
+fn find_doc_range_tree_sitter(root_node: &Node, spec: &LanguageSpec) -> (usize, usize) {
+    let mut doc_start = usize::MAX;
     let mut doc_end = 0;
     let mut cursor = root_node.walk();
 
     eprintln!("Searching for documentation range using tree-sitter...");
 
     for node in root_node.children(&mut cursor) {
-        if node.kind() == "block_comment" {
-            doc_start = node.start_byte();
+        let is_docstring = spec.doc_style == DocStyle::Docstring
+            && node.kind() == "expression_statement"
+            && node.named_child(0).is_some_and(|expression| expression.kind() == "string");
+        if spec.comment_kinds.contains(&node.kind()) || is_docstring {
+            doc_start = doc_start.min(node.start_byte());
             doc_end = node.end_byte();
-            break;
-        } else if !node.is_extra() {
+            if is_docstring {
+                break;
+            }
+        } else {
             break;
         }
     }
 
-    if doc_start == std::usize::MAX {
+    if doc_start == usize::MAX {
         eprintln!("No documentation block found.");
         (0, 0)
     } else {
         eprintln!("Documentation range:");
         eprintln!("  Start byte: {}", doc_start);
         eprintln!("  End byte: {}", doc_end);
-        eprintln!("  Start line: {}", source_code[..doc_start].lines().count());
-        eprintln!("  End line: {}", source_code[..doc_end].lines().count());
         (doc_start, doc_end)
     }
 }
 
+fn get_structures<'a>(node: &'a Node<'a>, source_code: &'a [u8], spec: &LanguageSpec, structures: &mut Vec<String>) {
+    let mut cursor = node.walk();
+
+    if let Some(segment) = structure::segment(node, source_code, spec) {
+        structures.push(segment.name);
+    }
+
+    for child in node.children(&mut cursor) {
+        get_structures(&child, source_code, spec, structures);
+    }
+}
 
-fn find_insert_position<'a>(node: Node<'a>, source_code: &'a [u8]) -> usize {
+fn find_main_function<'a>(node: &'a Node<'a>, source_code: &'a [u8], spec: &LanguageSpec) -> Option<(usize, usize)> {
     let mut cursor = node.walk();
-    let mut prev_node: Option<Node<'a>> = None;
+
     for child in node.children(&mut cursor) {
-        if child.kind() == "identifier" {
-            if let Some(prev) = prev_node {
-                if is_terminating_node(&prev) {
-                    return prev.end_byte();
+        if spec.is_structure_kind(child.kind()) {
+            if let Some(name_node) = child.child_by_field_name("name") {
+                let name = name_node.utf8_text(source_code).unwrap();
+                if name == "main" {
+                    return Some((child.start_byte(), child.end_byte()));
                 }
             }
-            return node.start_byte();
         }
-        prev_node = Some(child);
-    }
-    if let Some(prev) = prev_node {
-        if is_terminating_node(&prev) {
-            return prev.end_byte();
+
+        if let Some(range) = find_main_function(&child, source_code, spec) {
+            return Some(range);
         }
     }
-    node.start_byte()
-}
 
-fn is_terminating_node(node: &Node) -> bool {
-    match node.kind() {
-        "]" | ")" | "}" | ";" => true,
-        _ => false,
-    }
+    None
 }
 
 fn insert_test_functions(source_code: &str, test_functions: &str) -> String {
     let mut updated_code = source_code.to_string();
 
     // Check if a #[cfg(test)] block already exists
-    if let Some((test_block_start, test_block_end)) = find_cfg_test_block(&source_code) {
+    if let Some((test_block_start, test_block_end)) = find_cfg_test_block(source_code) {
         let start_line = source_code[..test_block_start].lines().count();
         let end_line = source_code[..test_block_end].lines().count();
         eprintln!("Existing #[cfg(test)] block found at byte range: {} - {}", test_block_start, test_block_end);
@@ -819,7 +1625,7 @@ fn insert_test_functions(source_code: &str, test_functions: &str) -> String {
         eprintln!("Creating a new #[cfg(test)] block and inserting test functions...");
 
         // Find the last function in the source code
-        if let Some((last_fn_start, last_fn_end)) = find_last_function(&source_code) {
+        if let Some((last_fn_start, last_fn_end)) = find_last_function(source_code) {
             let start_line = source_code[..last_fn_start].lines().count();
             let end_line = source_code[..last_fn_end].lines().count();
             eprintln!("Last function found at byte range: {} - {}", last_fn_start, last_fn_end);
@@ -845,7 +1651,7 @@ fn insert_test_functions(source_code: &str, test_functions: &str) -> String {
 
 fn find_cfg_test_block(source_code: &str) -> Option<(usize, usize)> {
     let mut parser = Parser::new();
-    let language = unsafe { tree_sitter_rust() };
+    let language = language::by_name("rust").expect("Rust is a built-in language").grammar();
     parser.set_language(&language).expect("Error setting language");
     let tree = parser.parse(source_code, None).expect("Error parsing source code");
     let root_node = tree.root_node();
@@ -872,13 +1678,11 @@ fn find_cfg_test_block(source_code: &str) -> Option<(usize, usize)> {
 
     eprintln!("No #[cfg(test)] block found");
     None
-
-
 }
 
 fn find_last_function(source_code: &str) -> Option<(usize, usize)> {
     let mut parser = Parser::new();
-    let language = unsafe { tree_sitter_rust() };
+    let language = language::by_name("rust").expect("Rust is a built-in language").grammar();
     parser.set_language(&language).expect("Error setting language");
     let tree = parser.parse(source_code, None).expect("Error parsing source code");
     let root_node = tree.root_node();
@@ -900,85 +1704,3 @@ fn find_last_function(source_code: &str) -> Option<(usize, usize)> {
 
     last_fn_range
 }
-
-fn find_properties_start_byte(source_code: &str, start_byte: usize) -> usize {
-    let mut properties_start_byte = start_byte;
-    let mut found_closing_brace = false;
-
-    for i in (0..start_byte).rev() {
-        if source_code.as_bytes()[i] == b'}' {
-            found_closing_brace = true;
-        } else if source_code.as_bytes()[i] == b'#' && source_code.as_bytes()[i + 1] == b'[' {
-            if found_closing_brace {
-                break;
-            } else {
-                properties_start_byte = i;
-                break;
-            }
-        }
-    }
-
-    properties_start_byte
-}
-
-fn find_doc_end_tree_sitter(source_code: &str, language: &Language) -> usize {
-    let mut parser = Parser::new();
-    parser.set_language(language).expect("Error setting language");
-    let tree = parser.parse(source_code, None).expect("Error parsing source code");
-    let root_node = tree.root_node();
-
-    let mut doc_end = 0;
-    let mut cursor = root_node.walk();
-    for node in root_node.children(&mut cursor) {
-        if node.kind() == "comment" {
-            doc_end = node.end_byte();
-        } else {
-            break;
-        }
-    }
-    doc_end
-}
-
-
-fn get_structures<'a>(node: &'a Node<'a>, source_code: &'a [u8], structures: &mut Vec<String>) {
-    let mut cursor = node.walk();
-
-    if node.kind() == "function_item"
-        || node.kind() == "mod_item"
-        || node.kind() == "struct_item"
-        || node.kind() == "enum_item"
-        || node.kind() == "trait_item"
-        || node.kind() == "impl_item"
-    {
-        if let Some(name_node) = node.child_by_field_name("name") {
-            let name = name_node.utf8_text(source_code).unwrap();
-            structures.push(format!("{}", name));
-        }
-    }
-
-    for child in node.children(&mut cursor) {
-        get_structures(&child, source_code, structures);
-    }
-}
-
-
-fn find_main_function<'a>(node: &'a Node<'a>, source_code: &'a [u8]) -> Option<(usize, usize)> {
-    let mut cursor = node.walk();
-
-    for child in node.children(&mut cursor) {
-        if child.kind() == "function_item" {
-            if let Some(name_node) = child.child_by_field_name("name") {
-                let name = name_node.utf8_text(source_code).unwrap();
-                if name == "main" {
-                    return Some((child.start_byte(), child.end_byte()));
-                }
-            }
-        }
-
-        if let Some(range) = find_main_function(&child, source_code) {
-            return Some(range);
-        }
-    }
-
-    None
-}
\ No newline at end of file
@@ -0,0 +1,180 @@
+//! The outline printed by `get_structure --format json|tsv|tree`.
+
+use regex::Regex;
+use serde::Serialize;
+use tree_sitter::Node;
+
+use crate::language::{DocStyle, LanguageSpec};
+use crate::structure;
+
+/// Output formats of `get_structure`.
+pub const FORMATS: &[&str] = &["names", "json", "tsv", "tree"];
+
+/// One structure of the outline. Lines and columns are 1-based, byte ranges
+/// are 0-based and exclude `end_byte`.
+#[derive(Serialize)]
+pub struct OutlineItem {
+    pub kind: String,
+    pub name: String,
+    pub path: String,
+    pub depth: usize,
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub visibility: String,
+    pub has_docs: bool,
+    pub has_tests: bool,
+    pub signature: String,
+}
+
+/// Builds the outline of every named structure in the file.
+pub fn outline(root_node: Node, source_code: &str, spec: &LanguageSpec, prefix: &[String]) -> Vec<OutlineItem> {
+    let items = structure::items(root_node, source_code.as_bytes(), spec, prefix);
+    let test_functions: Vec<Node> = items.iter().map(|item| item.node).filter(|node| is_test_function(node, source_code, spec)).collect();
+
+    items
+        .iter()
+        .map(|item| {
+            let node = item.node;
+            let name = item.path.last().map(|segment| segment.name.clone()).unwrap_or_default();
+            let start = node.start_position();
+            let end = node.end_position();
+            OutlineItem {
+                kind: node.kind().to_string(),
+                path: item.qualified_name(),
+                depth: item.path.len() - prefix.len() - 1,
+                start_line: start.row + 1,
+                start_column: start.column + 1,
+                end_line: end.row + 1,
+                end_column: end.column + 1,
+                start_byte: node.start_byte(),
+                end_byte: node.end_byte(),
+                visibility: visibility(&node, &name, source_code, spec),
+                has_docs: has_docs(&node, source_code, spec),
+                has_tests: has_tests(&node, &name, &test_functions, source_code),
+                signature: structure::item_signature(&node, source_code),
+                name,
+            }
+        })
+        .collect()
+}
+
+/// The declared visibility: the `pub` modifier in Rust, the accessibility
+/// modifier in TypeScript, and a leading underscore in Python and Ruby.
+fn visibility(node: &Node, name: &str, source_code: &str, spec: &LanguageSpec) -> String {
+    let mut cursor = node.walk();
+    let modifier = node
+        .children(&mut cursor)
+        .find(|child| matches!(child.kind(), "visibility_modifier" | "accessibility_modifier"))
+        .and_then(|modifier| modifier.utf8_text(source_code.as_bytes()).ok())
+        .map(str::to_string);
+
+    match modifier {
+        Some(modifier) => modifier,
+        None if spec.name == "rust" => "private".to_string(),
+        None if name.starts_with('_') => "private".to_string(),
+        None => "public".to_string(),
+    }
+}
+
+fn has_docs(node: &Node, source_code: &str, spec: &LanguageSpec) -> bool {
+    match spec.doc_style {
+        DocStyle::Docstring => structure::find_docstring(node).is_some(),
+        doc_style => {
+            let start_byte = structure::structure_start_byte(node, spec);
+            let (doc_start, doc_end) = structure::find_documentation_range(source_code, start_byte, doc_style);
+            doc_start != doc_end
+        }
+    }
+}
+
+/// Whether a structure is a test: a function marked with a `test` attribute in
+/// Rust, or one whose name starts with `test` elsewhere.
+fn is_test_function(node: &Node, source_code: &str, spec: &LanguageSpec) -> bool {
+    if !node.kind().contains("function") && !node.kind().contains("method") {
+        return false;
+    }
+
+    if spec.name == "rust" {
+        let mut sibling = node.prev_named_sibling();
+        while let Some(attribute) = sibling.filter(|sibling| sibling.kind() == "attribute_item") {
+            if attribute.utf8_text(source_code.as_bytes()).is_ok_and(is_test_attribute) {
+                return true;
+            }
+            sibling = attribute.prev_named_sibling();
+        }
+        return false;
+    }
+
+    node.child_by_field_name("name")
+        .and_then(|name| name.utf8_text(source_code.as_bytes()).ok())
+        .is_some_and(|name| name.starts_with("test"))
+}
+
+/// Whether an attribute is `#[test]`, a test attribute of another crate such
+/// as `#[tokio::test]`, or `#[cfg(test)]`.
+fn is_test_attribute(text: &str) -> bool {
+    let attribute: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let attribute = match attribute.strip_prefix("#[").and_then(|attribute| attribute.strip_suffix(']')) {
+        Some(attribute) => attribute,
+        None => return false,
+    };
+    let path = attribute.split('(').next().unwrap_or_default();
+    path == "test" || path.ends_with("::test") || attribute == "cfg(test)"
+}
+
+/// Whether a test function in the file mentions the structure by name.
+fn has_tests(node: &Node, name: &str, test_functions: &[Node], source_code: &str) -> bool {
+    let name_re = match Regex::new(&format!(r"\b{}\b", regex::escape(name))) {
+        Ok(name_re) => name_re,
+        Err(_) => return false,
+    };
+    test_functions
+        .iter()
+        .filter(|test| test.id() != node.id())
+        .any(|test| name_re.is_match(&source_code[test.start_byte()..test.end_byte()]))
+}
+
+/// Renders the outline as tab-separated values with a header line.
+pub fn to_tsv(items: &[OutlineItem]) -> String {
+    let mut output = String::from(
+        "kind\tpath\tstart_line\tstart_column\tend_line\tend_column\tstart_byte\tend_byte\tvisibility\thas_docs\thas_tests\tsignature\n",
+    );
+    for item in items {
+        output.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            item.kind,
+            item.path,
+            item.start_line,
+            item.start_column,
+            item.end_line,
+            item.end_column,
+            item.start_byte,
+            item.end_byte,
+            item.visibility,
+            item.has_docs,
+            item.has_tests,
+            item.signature.replace('\t', " "),
+        ));
+    }
+    output
+}
+
+/// Renders the outline as an indented tree.
+pub fn to_tree(items: &[OutlineItem]) -> String {
+    let mut output = String::new();
+    for item in items {
+        output.push_str(&format!(
+            "{}{} ({}, lines {}-{})\n",
+            "  ".repeat(item.depth),
+            item.name,
+            item.kind,
+            item.start_line,
+            item.end_line
+        ));
+    }
+    output
+}
@@ -0,0 +1,637 @@
+//! `rfcu run`: edits the structures selected across a directory tree.
+//!
+//! The tree is walked honoring `.gitignore`, and the structures of every file
+//! are matched against `--select` using the outline of `get_structure`. Each
+//! file is then edited by its own `rfcu` process, a few at a time, with all of
+//! its selected structures in one run. Git is left to this process: the edits
+//! are committed once every file is done, in one commit or one per file.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tree_sitter::Parser;
+
+use crate::backend;
+use crate::commit_message;
+use crate::config::{BranchStrategy, CommitPolicy, Mode, Scope, Settings};
+use crate::git;
+use crate::language::{self, LANGUAGES};
+use crate::outline::{self, OutlineItem};
+use crate::structure;
+use crate::throttle;
+
+/// Values of `--commits`.
+pub const COMMIT_MODES: &[&str] = &["one", "per-file"];
+
+/// One condition of `--select`. An item is selected when it meets all of them.
+enum Term {
+    /// A tree-sitter kind, or its first word: `struct` matches `struct_item`.
+    Kind(String),
+    /// Functions and methods of any language.
+    Function,
+    Public,
+    Private,
+    Documented(bool),
+    Tested(bool),
+}
+
+impl Term {
+    fn matches(&self, item: &OutlineItem) -> bool {
+        match self {
+            Term::Kind(kind) => item.kind == *kind || item.kind.starts_with(&format!("{}_", kind)),
+            Term::Function => item.kind.contains("function") || item.kind.contains("method"),
+            Term::Public => !matches!(item.visibility.as_str(), "private" | "protected"),
+            Term::Private => matches!(item.visibility.as_str(), "private" | "protected"),
+            Term::Documented(documented) => item.has_docs == *documented,
+            Term::Tested(tested) => item.has_tests == *tested,
+        }
+    }
+}
+
+/// Parses `--select`, such as `undocumented pub fn`.
+fn parse_selection(selection: &str) -> Result<Vec<Term>, String> {
+    let terms: Vec<Term> = selection
+        .split_whitespace()
+        .map(|word| match word {
+            "pub" | "public" => Ok(Term::Public),
+            "private" => Ok(Term::Private),
+            "documented" => Ok(Term::Documented(true)),
+            "undocumented" => Ok(Term::Documented(false)),
+            "tested" => Ok(Term::Tested(true)),
+            "untested" => Ok(Term::Tested(false)),
+            "fn" | "function" | "method" => Ok(Term::Function),
+            kind if LANGUAGES.iter().flat_map(|spec| spec.structure_kinds).any(|known| {
+                *known == kind || known.starts_with(&format!("{}_", kind))
+            }) => Ok(Term::Kind(kind.to_string())),
+            unknown => Err(format!(
+                "Unknown term '{}' in --select, expected pub, private, documented, undocumented, tested, untested, fn \
+                 or a kind of structure such as struct or function_item",
+                unknown
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+    if terms.is_empty() {
+        return Err("--select is empty".to_string());
+    }
+    Ok(terms)
+}
+
+/// What `rfcu run` edits and how, from its arguments or from a job file.
+pub struct Options<'a> {
+    pub mode_name: &'a str,
+    pub root: &'a str,
+    pub globs: Vec<String>,
+    pub select: Option<&'a str>,
+    pub user_request: &'a str,
+    /// How many files are edited at the same time.
+    pub jobs: usize,
+    /// One commit per file rather than one for every file.
+    pub per_file: bool,
+    pub combined: bool,
+    pub dry_run: bool,
+    pub no_commit: bool,
+    pub force_commit: bool,
+    pub config_flag: Option<&'a str>,
+    pub profile_flag: Option<&'a str>,
+}
+
+/// Remembers the structures edited successfully, so that later runs can skip
+/// them. Whole-file modes use an empty structure name and the whole file.
+pub trait Memory: Sync {
+    fn is_unchanged(&self, file_path: &str, structure: &str, code: &str) -> bool;
+    fn record(&self, file_path: &str, structures: &[(String, String)]);
+}
+
+/// A file to edit and the structures selected in it.
+struct Job {
+    file_path: String,
+    spec: &'static language::LanguageSpec,
+    structures: Vec<String>,
+    original: String,
+}
+
+enum Outcome {
+    Edited,
+    Unchanged,
+    Failed(String),
+    Skipped(String),
+}
+
+/// Runs `rfcu run` and returns the exit status.
+pub fn run(settings: &Settings, matches: &clap::ArgMatches, config_flag: Option<&str>, profile_flag: Option<&str>) -> i32 {
+    eprintln!("Reading user input from stdin...");
+    let mut user_request = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut user_request) {
+        eprintln!("Failed to read stdin: {}", e);
+        return 1;
+    }
+
+    let options = Options {
+        mode_name: matches.get_one::<String>("mode").expect("Mode is required"),
+        root: matches.get_one::<String>("path").expect("Path has a default value"),
+        globs: matches.get_many::<String>("glob").into_iter().flatten().cloned().collect(),
+        select: matches.get_one::<String>("select").map(String::as_str),
+        user_request: user_request.trim(),
+        jobs: matches.get_one::<usize>("jobs").copied().unwrap_or(settings.max_concurrency),
+        per_file: matches.get_one::<String>("commits").is_some_and(|commits| commits == "per-file"),
+        combined: matches.get_flag("combined"),
+        dry_run: matches.get_flag("dry_run"),
+        no_commit: matches.get_flag("no_commit"),
+        force_commit: matches.get_flag("force_commit"),
+        config_flag,
+        profile_flag,
+    };
+    run_with(settings, &options, None)
+}
+
+/// Edits the structures selected by `options` and returns the exit status.
+/// With a `memory`, structures unchanged since their last successful run are
+/// skipped, and every file edited successfully is recorded as soon as it is
+/// done.
+pub fn run_with(settings: &Settings, options: &Options, memory: Option<&dyn Memory>) -> i32 {
+    let mode = match settings.mode(options.mode_name) {
+        Some(mode) => mode,
+        None => {
+            eprintln!("Invalid mode: {}", options.mode_name);
+            eprintln!("Available modes: {}", settings.mode_names().join(", "));
+            return 1;
+        }
+    };
+    let root = options.root;
+    let commit_policy = if options.no_commit || options.dry_run { CommitPolicy::Never } else { settings.commit };
+
+    let selection = match (options.select, mode.scope) {
+        (Some(_), Scope::WholeFile) => {
+            eprintln!("The {} mode edits whole files and does not take --select", mode.name);
+            return 1;
+        }
+        (Some(selection), _) => match parse_selection(selection) {
+            Ok(terms) => Some(terms),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        },
+        (None, Scope::Structure) => {
+            eprintln!("The {} mode requires --select", mode.name);
+            return 1;
+        }
+        (None, _) => None,
+    };
+
+    let mut globs = GlobSetBuilder::new();
+    for pattern in &options.globs {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                globs.add(glob);
+            }
+            Err(e) => {
+                eprintln!("Invalid --glob '{}': {}", pattern, e);
+                return 1;
+            }
+        }
+    }
+    let globs = match globs.build() {
+        Ok(globs) => globs,
+        Err(e) => {
+            eprintln!("Invalid --glob: {}", e);
+            return 1;
+        }
+    };
+
+    if commit_policy == CommitPolicy::Always && !options.force_commit {
+        match git::staged_elsewhere(&[]) {
+            Ok(staged) if !staged.is_empty() => {
+                eprintln!("Other changes are already staged: {}", staged.join(", "));
+                eprintln!("Commit or unstage them first, or pass --force-commit to commit the edited files on their own anyway.");
+                return 1;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Cannot read the git index: {}", e);
+                eprintln!("Set commit = \"never\" or pass --no-commit to edit files outside a git repository.");
+                return 1;
+            }
+        }
+    }
+
+    eprintln!("Collecting the {} targets under {}...", mode.name, root);
+    let (work, mut outcomes) = collect_jobs(settings, root, &globs, selection.as_deref(), memory);
+    let structure_count: usize = work.iter().map(|job| job.structures.len()).sum();
+    eprintln!("{} file(s) to edit, {} structure(s) selected.", work.len(), structure_count);
+
+    // The workers run with --no-commit, which leaves their edits on the
+    // checked out branch, so the new branch is created once, here.
+    let new_branch = if settings.branch_strategy == BranchStrategy::New && !options.dry_run && !options.no_commit && !work.is_empty() {
+        match switch_to_new_branch(&mode, commit_policy, root, &work) {
+            Ok(new_branch) => Some(new_branch),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut child_args: Vec<String> = Vec::new();
+    for (flag, value) in [("--config", options.config_flag), ("--profile", options.profile_flag)] {
+        if let Some(value) = value {
+            child_args.extend([flag.to_string(), value.to_string()]);
+        }
+    }
+    child_args.extend(["--mode".to_string(), mode.name.clone(), "--no-commit".to_string()]);
+    if options.combined {
+        child_args.push("--combined".to_string());
+    }
+    if options.dry_run {
+        child_args.push("--dry-run".to_string());
+    }
+
+    let memory = memory.filter(|_| !options.dry_run);
+    let results = edit_files(&work, options.jobs, &child_args, options.user_request, memory);
+    let edited: Vec<&Job> = work
+        .iter()
+        .zip(&results)
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Edited))
+        .map(|(job, _)| job)
+        .collect();
+    outcomes.extend(work.iter().map(|job| job.file_path.clone()).zip(results));
+
+    let mut status = 0;
+    if !edited.is_empty() {
+        if let Err(e) = commit(settings, &mode, commit_policy, options.per_file, root, &edited, options.user_request) {
+            eprintln!("Failed to {} the changes: {}", if commit_policy == CommitPolicy::Stage { "stage" } else { "commit" }, e);
+            status = 1;
+        }
+    }
+    if let Some((previous, branch)) = &new_branch {
+        // With one commit per file, some may be on the branch even though
+        // another failed; the branch is kept when in doubt.
+        let committed = git::is_ahead(previous, branch).unwrap_or(true);
+        if committed {
+            eprintln!("Changes committed on branch {}.", branch);
+            eprintln!("Merge them with: git merge {}", branch);
+            eprintln!("Or open a pull request with: git push -u origin {} && gh pr create --head {}", branch, branch);
+        }
+        if let Err(e) = git::leave_branch(previous, branch, committed) {
+            eprintln!("Failed to switch back to {}: {}", previous, e);
+            status = 1;
+        }
+    }
+
+    print_summary(&mut outcomes);
+    if outcomes.iter().any(|(_, outcome)| matches!(outcome, Outcome::Failed(_))) {
+        status = 1;
+    }
+    status
+}
+
+/// Checks that the files to edit can go through a new branch, then creates it
+/// and checks it out. Returns the branch or commit to come back to and the
+/// new branch.
+fn switch_to_new_branch(mode: &Mode, commit_policy: CommitPolicy, root: &str, work: &[Job]) -> Result<(String, String), String> {
+    if commit_policy != CommitPolicy::Always {
+        return Err(format!("A new branch needs commit = \"always\"; the commit setting is \"{}\".", commit_policy.name()));
+    }
+    // The files are edited on the new branch, then checked out again from the
+    // previous one, so they must have no other change.
+    for job in work {
+        match git::has_uncommitted_changes(&job.file_path) {
+            Ok(false) => {}
+            Ok(true) => {
+                return Err(format!(
+                    "{} has uncommitted changes; commit or stash them before editing it on a new branch.",
+                    job.file_path
+                ))
+            }
+            Err(e) => return Err(format!("Cannot read the status of {}: {}", job.file_path, e)),
+        }
+    }
+    // Named after the directory, as "." has no name of its own.
+    let root_path = fs::canonicalize(root).map(|path| path.to_string_lossy().into_owned()).unwrap_or_else(|_| root.to_string());
+    let branch = git::branch_name(&mode.name, "", &root_path);
+    match git::switch_to_new_branch(&branch) {
+        Ok(previous) => Ok((previous, branch)),
+        Err(e) => Err(format!("Cannot create the branch {}: {}", branch, e)),
+    }
+}
+
+/// Walks `root` and returns the files with selected structures, along with
+/// the files that matched `--glob` but were skipped.
+fn collect_jobs(
+    settings: &Settings,
+    root: &str,
+    globs: &GlobSet,
+    selection: Option<&[Term]>,
+    memory: Option<&dyn Memory>,
+) -> (Vec<Job>, Vec<(String, Outcome)>) {
+    let mut work = Vec::new();
+    let mut skipped = Vec::new();
+    let walker = ignore::WalkBuilder::new(root).require_git(false).sort_by_file_name(|a, b| a.cmp(b)).build();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Skipping an entry: {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        if !globs.is_empty() && !globs.is_match(relative) {
+            continue;
+        }
+        let file_path = path.to_string_lossy().into_owned();
+
+        let spec = match language::detect(settings.language.as_deref(), &settings.language_extensions, &file_path) {
+            Ok(spec) => spec,
+            // Without a glob every file is a candidate, so only report the
+            // files that were asked for.
+            Err(e) if !globs.is_empty() => {
+                skipped.push((file_path, Outcome::Skipped(e)));
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let source_code = match fs::read_to_string(path) {
+            Ok(source_code) => source_code,
+            Err(e) => {
+                skipped.push((file_path, Outcome::Skipped(format!("cannot be read: {}", e))));
+                continue;
+            }
+        };
+
+        let selected = match selection {
+            Some(selection) => match select(&file_path, &source_code, spec, selection) {
+                Ok(selected) if selected.is_empty() => {
+                    skipped.push((file_path, Outcome::Skipped("no matching structures".to_string())));
+                    continue;
+                }
+                Ok(selected) => selected,
+                Err(e) => {
+                    skipped.push((file_path, Outcome::Skipped(e)));
+                    continue;
+                }
+            },
+            None => vec![(String::new(), source_code.clone())],
+        };
+        let selected_count = selected.len();
+        let structures: Vec<String> = selected
+            .into_iter()
+            .filter(|(structure, code)| !memory.is_some_and(|memory| memory.is_unchanged(&file_path, structure, code)))
+            .map(|(structure, _)| structure)
+            .collect();
+        if structures.is_empty() {
+            skipped.push((file_path, Outcome::Skipped("unchanged since its last successful run".to_string())));
+            continue;
+        }
+        if structures.len() < selected_count {
+            eprintln!(
+                "{}: skipping {} structure(s) unchanged since their last successful run",
+                file_path,
+                selected_count - structures.len()
+            );
+        }
+        let structures = structures.into_iter().filter(|structure| !structure.is_empty()).collect();
+        work.push(Job { file_path, spec, structures, original: source_code });
+    }
+    (work, skipped)
+}
+
+/// The qualified paths and the code of the structures of a file that meet
+/// every term. Structures nested in a selected one are left to it.
+///
+/// Structures are passed to `rfcu` by path, so those whose path is shared by
+/// another structure, such as two `impl Foo` blocks or a function defined
+/// once per `#[cfg]`, cannot be told apart and are left out with a message.
+/// Fails when every matching structure is left out.
+fn select(file_path: &str, source_code: &str, spec: &language::LanguageSpec, selection: &[Term]) -> Result<Vec<(String, String)>, String> {
+    let items = parse_outline(file_path, source_code, spec)?;
+    let mut structures = Vec::new();
+    let mut shared = Vec::new();
+    let mut selected_end = 0;
+    for item in &items {
+        if item.end_byte <= selected_end || !selection.iter().all(|term| term.matches(item)) {
+            continue;
+        }
+        let sharing = items.iter().filter(|other| other.path == item.path).count();
+        if sharing > 1 {
+            if !shared.contains(&item.path) {
+                eprintln!(
+                    "{}: skipping {}, whose path is shared by {} structures; edit them one at a time with --line",
+                    file_path, item.path, sharing
+                );
+                shared.push(item.path.clone());
+            }
+            continue;
+        }
+        selected_end = item.end_byte;
+        structures.push((item.path.clone(), source_code[item.start_byte..item.end_byte].to_string()));
+    }
+    if structures.is_empty() && !shared.is_empty() {
+        return Err(format!("{} cannot be told apart from other structures with the same path", shared.join(", ")));
+    }
+    Ok(structures)
+}
+
+fn parse_outline(file_path: &str, source_code: &str, spec: &language::LanguageSpec) -> Result<Vec<OutlineItem>, String> {
+    let mut parser = Parser::new();
+    parser.set_language(&spec.grammar()).map_err(|e| format!("cannot load the {} grammar: {:?}", spec.name, e))?;
+    let tree = parser.parse(source_code, None).ok_or_else(|| "cannot be parsed".to_string())?;
+    let module_path = structure::module_path(file_path, spec);
+    Ok(outline::outline(tree.root_node(), source_code, spec, &module_path))
+}
+
+/// Records the structures of a file edited successfully, with their code as
+/// edited, or the whole file for whole-file modes.
+fn remember(memory: &dyn Memory, job: &Job) {
+    let source_code = match fs::read_to_string(&job.file_path) {
+        Ok(source_code) => source_code,
+        Err(_) => return,
+    };
+    if job.structures.is_empty() {
+        memory.record(&job.file_path, &[(String::new(), source_code)]);
+        return;
+    }
+    let items = parse_outline(&job.file_path, &source_code, job.spec).unwrap_or_default();
+    let structures: Vec<(String, String)> = items
+        .into_iter()
+        .filter(|item| job.structures.contains(&item.path))
+        .map(|item| (item.path, source_code[item.start_byte..item.end_byte].to_string()))
+        .collect();
+    memory.record(&job.file_path, &structures);
+}
+
+/// Edits every file in its own `rfcu` process, `jobs` at a time, and returns
+/// the outcome of each.
+fn edit_files(work: &[Job], jobs: usize, child_args: &[String], user_request: &str, memory: Option<&dyn Memory>) -> Vec<Outcome> {
+    let executable = match env::current_exe() {
+        Ok(executable) => executable,
+        Err(e) => {
+            eprintln!("Cannot find the rfcu executable: {}", e);
+            return work.iter().map(|_| Outcome::Failed(e.to_string())).collect();
+        }
+    };
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(work.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, work.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let job = match work.get(index) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let outcome = edit_file(&executable, job, child_args, user_request);
+                if let Some(memory) = memory.filter(|_| matches!(outcome, Outcome::Edited | Outcome::Unchanged)) {
+                    remember(memory, job);
+                }
+                let label = match &outcome {
+                    Outcome::Edited => "edited",
+                    Outcome::Unchanged => "unchanged",
+                    Outcome::Failed(_) => "failed",
+                    Outcome::Skipped(_) => "skipped",
+                };
+                let done = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                eprintln!("[{}/{}] {}: {}", done, work.len(), job.file_path, label);
+                outcomes.lock().expect("A worker panicked")[index] = Some(outcome);
+            });
+        }
+    });
+
+    outcomes
+        .into_inner()
+        .expect("A worker panicked")
+        .into_iter()
+        .map(|outcome| outcome.unwrap_or_else(|| Outcome::Failed("not run".to_string())))
+        .collect()
+}
+
+fn edit_file(executable: &Path, job: &Job, child_args: &[String], user_request: &str) -> Outcome {
+    let mut command = Command::new(executable);
+    command.args(child_args).arg("--file_path").arg(&job.file_path);
+    if !job.structures.is_empty() {
+        command.arg("--structure_name").arg(job.structures.join(","));
+    }
+    let child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return Outcome::Failed(format!("failed to start rfcu: {}", e)),
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // A run that stops before reading its request is reported by its
+        // exit status instead.
+        let _ = stdin.write_all(user_request.as_bytes());
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    };
+
+    // Diffs of --dry-run, printed whole so they do not interleave.
+    if !output.stdout.is_empty() {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(&output.stdout);
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // The whole output of the failed run, printed at once so it does not
+        // interleave, as the summary only gives its last line.
+        let _ = write!(io::stderr().lock(), "Output of the failed run on {}:\n{}", job.file_path, stderr);
+        let reason = stderr.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("no output");
+        return Outcome::Failed(format!("{} ({})", reason.trim(), output.status));
+    }
+    match fs::read_to_string(&job.file_path) {
+        Ok(content) if content != job.original => Outcome::Edited,
+        _ => Outcome::Unchanged,
+    }
+}
+
+/// Stages or commits the edited files, in one commit or one per file.
+fn commit(
+    settings: &Settings,
+    mode: &Mode,
+    commit_policy: CommitPolicy,
+    per_file: bool,
+    root: &str,
+    edited: &[&Job],
+    user_request: &str,
+) -> io::Result<()> {
+    let file_paths: Vec<&str> = edited.iter().map(|job| job.file_path.as_str()).collect();
+    match commit_policy {
+        CommitPolicy::Never => {
+            eprintln!("Leaving the changes unstaged.");
+            return Ok(());
+        }
+        CommitPolicy::Stage => return git::stage_changes(&file_paths),
+        CommitPolicy::Always => {}
+    }
+
+    let backend = throttle::wrap(backend::from_config(&settings.backend), settings);
+    let groups: Vec<Vec<&Job>> = if per_file {
+        edited.iter().map(|job| vec![*job]).collect()
+    } else {
+        vec![edited.to_vec()]
+    };
+    for group in groups {
+        let file_paths: Vec<&str> = group.iter().map(|job| job.file_path.as_str()).collect();
+        let structures: Vec<&str> = group.iter().flat_map(|job| job.structures.iter().map(String::as_str)).collect();
+        let (file_path, structure_name) = match group.as_slice() {
+            [job] => (job.file_path.clone(), structures.join(", ")),
+            _ if structures.is_empty() => (format!("{} files under {}", group.len(), root), String::new()),
+            _ => (root.trim_end_matches('/').to_string(), format!("{} structures in {} files", structures.len(), group.len())),
+        };
+        let diff = git::diff(&file_paths)?;
+        let details = commit_message::Details {
+            file_path: &file_path,
+            mode: &mode.name,
+            structure_name: &structure_name,
+            user_request,
+            diff: &diff,
+            validation: "passed",
+        };
+        let message = commit_message::generate(
+            backend.as_ref(),
+            &settings.commit_message_flow,
+            settings.commit_style,
+            settings.commit_template.as_deref(),
+            settings.prompt_dir.as_deref(),
+            &details,
+        );
+        git::commit_changes(&file_paths, &message)?;
+        eprintln!("Committed {}.", file_paths.join(", "));
+    }
+    Ok(())
+}
+
+fn print_summary(outcomes: &mut [(String, Outcome)]) {
+    outcomes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let count = |wanted: fn(&Outcome) -> bool| outcomes.iter().filter(|(_, outcome)| wanted(outcome)).count();
+    eprintln!(
+        "Summary: {} edited, {} unchanged, {} failed, {} skipped",
+        count(|outcome| matches!(outcome, Outcome::Edited)),
+        count(|outcome| matches!(outcome, Outcome::Unchanged)),
+        count(|outcome| matches!(outcome, Outcome::Failed(_))),
+        count(|outcome| matches!(outcome, Outcome::Skipped(_)))
+    );
+    for (file_path, outcome) in outcomes.iter() {
+        match outcome {
+            Outcome::Edited => eprintln!("  edited     {}", file_path),
+            Outcome::Unchanged => eprintln!("  unchanged  {}", file_path),
+            Outcome::Failed(reason) => eprintln!("  failed     {}: {}", file_path, reason),
+            Outcome::Skipped(reason) => eprintln!("  skipped    {}: {}", file_path, reason),
+        }
+    }
+}
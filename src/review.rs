@@ -0,0 +1,241 @@
+//! The `--interactive` review of a proposed edit, one hunk at a time, modelled
+//! on `git add -p`.
+//!
+//! The user request is read from stdin, so answers are read from the terminal
+//! instead: `/dev/tty`, or the file named by `RFCU_TTY`.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use similar::{ChangeTag, TextDiff};
+
+/// Lines of context shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+const HELP: &str = "\
+y - apply this hunk
+n - do not apply this hunk
+a - apply this hunk and all later hunks
+d - do not apply this hunk or any of the later hunks
+e - edit the proposed lines of this hunk in $EDITOR
+r - ask for a new answer with an extra instruction
+q - quit; do not apply anything
+? - print help";
+
+/// The outcome of a review.
+pub enum Decision {
+    /// Apply this code: the original with the accepted (and edited) hunks.
+    Apply(String),
+    /// Send the request again with this extra instruction.
+    Regenerate(String),
+    /// Apply nothing.
+    Quit,
+}
+
+/// Where answers are read from.
+pub struct Terminal {
+    path: String,
+    reader: BufReader<File>,
+}
+
+impl Terminal {
+    pub fn open() -> io::Result<Terminal> {
+        let path = env::var("RFCU_TTY").unwrap_or_else(|_| "/dev/tty".to_string());
+        let file = File::open(&path).map_err(|e| {
+            io::Error::new(e.kind(), format!("--interactive needs a terminal, but {} cannot be opened: {}", path, e))
+        })?;
+        Ok(Terminal { path, reader: BufReader::new(file) })
+    }
+
+    /// Prints `question` and reads a trimmed answer. Returns `None` at the end
+    /// of the input.
+    fn ask(&mut self, question: &str) -> io::Result<Option<String>> {
+        eprint!("{}", question);
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if self.reader.read_line(&mut answer)? == 0 {
+            eprintln!();
+            return Ok(None);
+        }
+        Ok(Some(answer.trim().to_string()))
+    }
+}
+
+/// A group of nearby changes with their context. Line numbers are 0-based
+/// indices into the lines of the original and of the proposed code.
+struct Hunk {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize,
+    lines: Vec<(ChangeTag, String)>,
+    /// The lines replacing `old_start..old_end` when the hunk is accepted.
+    replacement: String,
+}
+
+/// Shows the hunks of the change from `original` to `proposed` and asks
+/// whether to apply each of them.
+pub fn review(terminal: &mut Terminal, file_path: &str, original: &str, proposed: &str) -> io::Result<Decision> {
+    let diff = TextDiff::from_lines(original, proposed);
+    let old_lines = diff.old_slices();
+    let new_lines = diff.new_slices();
+
+    let mut hunks: Vec<Hunk> = diff
+        .grouped_ops(CONTEXT_LINES)
+        .iter()
+        .map(|group| {
+            let (first, last) = (&group[0], &group[group.len() - 1]);
+            let (new_start, new_end) = (first.new_range().start, last.new_range().end);
+            Hunk {
+                old_start: first.old_range().start,
+                old_end: last.old_range().end,
+                new_start,
+                new_end,
+                lines: group
+                    .iter()
+                    .flat_map(|op| diff.iter_changes(op))
+                    .map(|change| (change.tag(), change.value().to_string()))
+                    .collect(),
+                replacement: new_lines[new_start..new_end].concat(),
+            }
+        })
+        .collect();
+
+    if hunks.is_empty() {
+        eprintln!("The answer does not change {}.", file_path);
+        return Ok(Decision::Quit);
+    }
+
+    let color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    eprintln!("{}", paint(color, "1", &format!("--- a/{}\n+++ b/{}", file_path, file_path)));
+
+    let count = hunks.len();
+    let mut accepted = vec![false; count];
+    let mut index = 0;
+    while index < count {
+        print_hunk(&hunks[index], color);
+        let answer = terminal.ask(&format!("({}/{}) Apply this hunk [y,n,a,d,e,r,q,?]? ", index + 1, count))?;
+        match answer.as_deref() {
+            Some("y") => accepted[index] = true,
+            Some("n") => {}
+            Some("a") => {
+                accepted[index..].fill(true);
+                break;
+            }
+            Some("d") => break,
+            Some("e") => {
+                match edit_hunk(file_path, &hunks[index].replacement, &terminal.path)? {
+                    Some(replacement) => {
+                        hunks[index].replacement = replacement;
+                        accepted[index] = true;
+                    }
+                    None => continue,
+                }
+            }
+            Some("r") => match terminal.ask("Extra instruction: ")? {
+                Some(instruction) if !instruction.is_empty() => return Ok(Decision::Regenerate(instruction)),
+                _ => continue,
+            },
+            Some("q") | None => return Ok(Decision::Quit),
+            _ => {
+                eprintln!("{}", HELP);
+                continue;
+            }
+        }
+        index += 1;
+    }
+
+    if !accepted.contains(&true) {
+        return Ok(Decision::Quit);
+    }
+
+    // Rebuild the file from the original, taking the replacement of every
+    // accepted hunk and the original lines of every rejected one.
+    let mut code = String::with_capacity(proposed.len());
+    let mut old_position = 0;
+    for (hunk, accepted) in hunks.iter().zip(accepted) {
+        code.push_str(&old_lines[old_position..hunk.old_start].concat());
+        if accepted {
+            code.push_str(&hunk.replacement);
+        } else {
+            code.push_str(&old_lines[hunk.old_start..hunk.old_end].concat());
+        }
+        old_position = hunk.old_end;
+    }
+    code.push_str(&old_lines[old_position..].concat());
+    Ok(Decision::Apply(code))
+}
+
+fn print_hunk(hunk: &Hunk, color: bool) {
+    eprintln!(
+        "{}",
+        paint(
+            color,
+            "36",
+            &format!(
+                "@@ -{},{} +{},{} @@",
+                hunk.old_start + 1,
+                hunk.old_end - hunk.old_start,
+                hunk.new_start + 1,
+                hunk.new_end - hunk.new_start
+            )
+        )
+    );
+    for (tag, line) in &hunk.lines {
+        let line = line.trim_end_matches(['\n', '\r']);
+        match tag {
+            ChangeTag::Delete => eprintln!("{}", paint(color, "31", &format!("-{}", line))),
+            ChangeTag::Insert => eprintln!("{}", paint(color, "32", &format!("+{}", line))),
+            ChangeTag::Equal => eprintln!(" {}", line),
+        }
+    }
+}
+
+/// Wraps `text` in an ANSI SGR sequence when `color` is set.
+fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Opens the proposed lines of a hunk in the editor and returns them as
+/// edited, or `None` when the editor fails.
+fn edit_hunk(file_path: &str, replacement: &str, terminal_path: &str) -> io::Result<Option<String>> {
+    let extension = Path::new(file_path).extension().and_then(|extension| extension.to_str()).unwrap_or("txt");
+    let hunk_path = env::temp_dir().join(format!("rfcu-hunk-{}.{}", std::process::id(), extension));
+    fs::write(&hunk_path, replacement)?;
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    eprintln!("Opening the hunk in {}...", editor);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&hunk_path)
+        .stdin(File::open(terminal_path).map(Stdio::from).unwrap_or_else(|_| Stdio::null()))
+        .status();
+
+    let edited = fs::read_to_string(&hunk_path);
+    let _ = fs::remove_file(&hunk_path);
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            eprintln!("The editor exited with {}; the hunk was not changed.", status);
+            return Ok(None);
+        }
+        Err(e) => {
+            eprintln!("Failed to start the editor: {}", e);
+            return Ok(None);
+        }
+    }
+
+    let mut edited = edited?;
+    if replacement.ends_with('\n') && !edited.ends_with('\n') {
+        edited.push('\n');
+    }
+    Ok(Some(edited))
+}
@@ -0,0 +1,428 @@
+//! Finding structures in a syntax tree by name or qualified path.
+//!
+//! Every named structure gets a path built from the structures it is nested
+//! in, such as `parser::Config::new` for a method in `impl Config` inside
+//! `mod parser`. Selectors are matched against the end of that path, so `new`,
+//! `Config::new` and `parser::Config::new` all select the method as long as no
+//! other structure matches too. An `impl` block is selected with its header,
+//! e.g. `impl Display for Foo`, with or without its generic arguments, and
+//! stands for its type when it contains the selected item.
+//!
+//! Editors select structures by position instead: the smallest named structure
+//! enclosing a line or byte range.
+//!
+//! The helpers at the end read what surrounds a structure: where its
+//! attributes or decorators start, its doc comment or docstring, and its
+//! signature.
+
+use std::path::Path;
+use tree_sitter::Node;
+
+use crate::language::{DocStyle, LanguageSpec};
+
+/// One step of a structure's path.
+#[derive(Clone)]
+pub struct Segment {
+    /// The name the structure is selected and displayed with.
+    pub name: String,
+    /// Other names that select the structure, such as the header of an `impl`
+    /// block without generic arguments.
+    pub aliases: Vec<String>,
+    /// Other names that match the structure when it contains the selected item,
+    /// such as the type of an `impl` block. The first one is displayed in the
+    /// paths of the items inside.
+    pub container_names: Vec<String>,
+}
+
+impl Segment {
+    fn named(name: &str) -> Self {
+        Segment { name: name.to_string(), aliases: Vec::new(), container_names: Vec::new() }
+    }
+}
+
+/// How the structure to work on is chosen.
+pub enum Selector {
+    /// A name or qualified path.
+    Path(String),
+    /// The smallest named structure enclosing a byte range.
+    Enclosing { start_byte: usize, end_byte: usize },
+}
+
+/// A named structure and the path leading to it.
+pub struct Item<'tree> {
+    pub node: Node<'tree>,
+    pub path: Vec<Segment>,
+}
+
+impl Item<'_> {
+    /// The path of the item, e.g. `parser::Config::new` or `<Foo as Display>::fmt`.
+    pub fn qualified_name(&self) -> String {
+        let (own, ancestors) = self.path.split_last().expect("an item has at least its own segment");
+        ancestors
+            .iter()
+            .map(|segment| segment.container_names.first().unwrap_or(&segment.name).as_str())
+            .chain(std::iter::once(own.name.as_str()))
+            .collect::<Vec<_>>()
+            .join("::")
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn field_text(node: &Node, field: &str, source_code: &[u8]) -> Option<String> {
+    node.child_by_field_name(field)
+        .and_then(|child| child.utf8_text(source_code).ok())
+        .map(normalize)
+}
+
+/// Removes generic arguments, so `Lexer<'a, T>` becomes `Lexer`.
+fn strip_generics(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '<' => depth += 1,
+            '>' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => {}
+        }
+    }
+    normalize(&stripped)
+}
+
+/// Returns the path segment of a structure node, or `None` when the node is
+/// not a structure or has no name. `impl` blocks have no name field and are
+/// named after their header: `impl Type` or `impl Trait for Type`. JSON pairs
+/// are named after their key, without the quotes.
+pub fn segment(node: &Node, source_code: &[u8], spec: &LanguageSpec) -> Option<Segment> {
+    if !spec.is_structure_kind(node.kind()) {
+        return None;
+    }
+
+    if node.kind() == "impl_item" {
+        let type_name = field_text(node, "type", source_code)?;
+        let base_type = strip_generics(&type_name);
+        let (name, alias, mut container_names) = match field_text(node, "trait", source_code) {
+            Some(trait_name) => (
+                format!("impl {} for {}", trait_name, type_name),
+                format!("impl {} for {}", strip_generics(&trait_name), base_type),
+                vec![format!("<{} as {}>", type_name, trait_name), type_name.clone()],
+            ),
+            None => (format!("impl {}", type_name), format!("impl {}", base_type), vec![type_name.clone()]),
+        };
+        if base_type != type_name {
+            container_names.push(base_type);
+        }
+        let aliases = if alias != name { vec![alias] } else { Vec::new() };
+        return Some(Segment { name, aliases, container_names });
+    }
+
+    if node.kind() == "pair" {
+        return field_text(node, "key", source_code).map(|key| Segment::named(key.trim_matches('"')));
+    }
+
+    field_text(node, "name", source_code).map(|name| Segment::named(&name))
+}
+
+/// Returns the module path of a Rust file from its location under `src/`:
+/// `src/parser/lexer.rs` is `parser::lexer`, while `src/lib.rs`, `src/main.rs`
+/// and files outside of `src/` are the crate root.
+pub fn module_path(file_path: &str, spec: &LanguageSpec) -> Vec<String> {
+    if spec.name != "rust" {
+        return Vec::new();
+    }
+
+    let path = Path::new(file_path);
+    let components: Vec<&str> = path.iter().filter_map(|component| component.to_str()).collect();
+    let relative = match components.iter().rposition(|component| *component == "src") {
+        Some(src) => &components[src + 1..],
+        None => return Vec::new(),
+    };
+
+    let mut modules: Vec<String> = relative.iter().map(|component| component.to_string()).collect();
+    if let Some(file_name) = modules.pop() {
+        let stem = file_name.trim_end_matches(".rs");
+        let is_root = modules.is_empty() && (stem == "lib" || stem == "main");
+        if stem != "mod" && !is_root {
+            modules.push(stem.to_string());
+        }
+    }
+    modules
+}
+
+/// Lists every named structure under `root_node` in source order, with paths
+/// starting at `prefix`.
+pub fn items<'tree>(root_node: Node<'tree>, source_code: &[u8], spec: &LanguageSpec, prefix: &[String]) -> Vec<Item<'tree>> {
+    let mut path: Vec<Segment> = prefix.iter().map(|name| Segment::named(name)).collect();
+    let mut items = Vec::new();
+    collect_items(root_node, source_code, spec, &mut path, &mut items);
+    items
+}
+
+fn collect_items<'tree>(
+    node: Node<'tree>,
+    source_code: &[u8],
+    spec: &LanguageSpec,
+    path: &mut Vec<Segment>,
+    items: &mut Vec<Item<'tree>>,
+) {
+    let segment = segment(&node, source_code, spec);
+    let is_named = segment.is_some();
+    if let Some(segment) = segment {
+        path.push(segment);
+        items.push(Item { node, path: path.clone() });
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect_items(child, source_code, spec, path, items);
+    }
+
+    if is_named {
+        path.pop();
+    }
+}
+
+/// Whether `selector` names the last segment of `path`, qualified by any
+/// number of the segments before it.
+fn matches(selector: &str, path: &[Segment]) -> bool {
+    match path.split_last() {
+        Some((own, ancestors)) => std::iter::once(&own.name)
+            .chain(&own.aliases)
+            .any(|name| match_suffix(selector, name, ancestors)),
+        None => false,
+    }
+}
+
+fn match_suffix(selector: &str, name: &str, ancestors: &[Segment]) -> bool {
+    if selector == name {
+        return true;
+    }
+    match selector.strip_suffix(name).and_then(|rest| rest.strip_suffix("::")) {
+        Some(rest) => match ancestors.split_last() {
+            Some((parent, ancestors)) => std::iter::once(&parent.name)
+                .chain(&parent.container_names)
+                .any(|parent_name| match_suffix(rest, parent_name, ancestors)),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+/// Splits a comma-separated list of selectors, keeping the commas inside
+/// generic arguments and parentheses, as in `impl From<(A, B)> for C`.
+pub fn split_list(list: &str) -> Vec<String> {
+    let mut selectors = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in list.chars() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                selectors.push(normalize(&current));
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    selectors.push(normalize(&current));
+    selectors.retain(|selector| !selector.is_empty());
+    selectors
+}
+
+/// Returns the byte range of lines `first` to `last` (1-based, inclusive)
+/// without their leading indentation and trailing whitespace, or `None` when
+/// the lines are not in the source code.
+pub fn line_range(source_code: &str, first: usize, last: usize) -> Option<(usize, usize)> {
+    if first == 0 || last < first {
+        return None;
+    }
+
+    let mut line_start = 0;
+    let mut range: Option<(usize, usize)> = None;
+    for (index, line) in source_code.split_inclusive('\n').enumerate() {
+        let number = index + 1;
+        if number == first {
+            range = Some((line_start + line.len() - line.trim_start().len(), 0));
+        }
+        if number == last {
+            return range.map(|(start, _)| (start, (line_start + line.trim_end().len()).max(start)));
+        }
+        line_start += line.len();
+    }
+    None
+}
+
+/// Finds the structure chosen by `selector`.
+pub fn find<'tree>(
+    root_node: Node<'tree>,
+    selector: &Selector,
+    source_code: &[u8],
+    spec: &LanguageSpec,
+    prefix: &[String],
+) -> Result<Item<'tree>, String> {
+    match selector {
+        Selector::Path(path) => find_by_path(root_node, path, source_code, spec, prefix),
+        Selector::Enclosing { start_byte, end_byte } => {
+            find_enclosing(root_node, (*start_byte, *end_byte), source_code, spec, prefix)
+        }
+    }
+}
+
+/// Finds the smallest named structure that contains the whole byte range.
+fn find_enclosing<'tree>(
+    root_node: Node<'tree>,
+    byte_range: (usize, usize),
+    source_code: &[u8],
+    spec: &LanguageSpec,
+    prefix: &[String],
+) -> Result<Item<'tree>, String> {
+    let (start_byte, end_byte) = byte_range;
+    items(root_node, source_code, spec, prefix)
+        .into_iter()
+        .filter(|item| item.node.start_byte() <= start_byte && end_byte <= item.node.end_byte())
+        .rev()
+        .min_by_key(|item| item.node.end_byte() - item.node.start_byte())
+        .inspect(|item| eprintln!("Structure found: {}", item.qualified_name()))
+        .ok_or_else(|| format!("No structure encloses the byte range {} - {}", start_byte, end_byte))
+}
+
+/// Finds the structure named by a path. Fails when nothing matches, and when
+/// several structures match, listing them so the path can be qualified.
+fn find_by_path<'tree>(
+    root_node: Node<'tree>,
+    selector: &str,
+    source_code: &[u8],
+    spec: &LanguageSpec,
+    prefix: &[String],
+) -> Result<Item<'tree>, String> {
+    let selector = normalize(selector);
+    let mut candidates: Vec<Item> = items(root_node, source_code, spec, prefix)
+        .into_iter()
+        .filter(|item| matches(&selector, &item.path))
+        .collect();
+
+    match candidates.len() {
+        0 => Err(format!("Structure not found in the source code: {}", selector)),
+        1 => {
+            let item = candidates.remove(0);
+            eprintln!("Structure found: {}", item.qualified_name());
+            Ok(item)
+        }
+        _ => {
+            let mut message = format!("Structure name '{}' is ambiguous. Use a qualified path such as one of:", selector);
+            for item in &candidates {
+                message.push_str(&format!(
+                    "\n  {} ({}, line {})",
+                    item.qualified_name(),
+                    item.node.kind(),
+                    item.node.start_position().row + 1
+                ));
+            }
+            Err(message)
+        }
+    }
+}
+
+/// Returns where a structure starts once its attributes (Rust) or decorators
+/// (Python) are included.
+pub fn structure_start_byte(node: &Node, spec: &LanguageSpec) -> usize {
+    if spec.name == "rust" {
+        let mut start_byte = node.start_byte();
+        let mut sibling = node.prev_named_sibling();
+        while let Some(attribute) = sibling.filter(|sibling| sibling.kind() == "attribute_item") {
+            start_byte = attribute.start_byte();
+            sibling = attribute.prev_named_sibling();
+        }
+        return start_byte;
+    }
+    match node.parent() {
+        Some(parent) if parent.kind() == "decorated_definition" => parent.start_byte(),
+        _ => node.start_byte(),
+    }
+}
+
+/// Returns the declaration of an item without its body, on a single line.
+/// Values of `const` and `static` items and the rules of `macro_rules!` are
+/// left out like bodies.
+pub fn item_signature(node: &Node, source_code: &str) -> String {
+    let mut cursor = node.walk();
+    let end_byte = node
+        .child_by_field_name("body")
+        .or_else(|| node.child_by_field_name("value"))
+        .or_else(|| node.children(&mut cursor).find(|child| child.kind() == "macro_rule"))
+        .map(|body| body.start_byte())
+        .unwrap_or(node.end_byte());
+    source_code[node.start_byte()..end_byte]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches([';', '=', '{', '(', '['])
+        .trim_end()
+        .to_string()
+}
+
+/// Returns the docstring statement at the top of a Python body, if any.
+pub fn find_docstring<'tree>(node: &Node<'tree>) -> Option<Node<'tree>> {
+    node.child_by_field_name("body")?
+        .named_child(0)
+        .filter(|statement| statement.kind() == "expression_statement")
+        .filter(|statement| statement.named_child(0).is_some_and(|expression| expression.kind() == "string"))
+}
+
+/// Finds the doc comment directly above `struct_start`: a block such as
+/// `/** ... */` or a run of lines such as `///` or `#`, depending on the language.
+/// Blank lines between the comment and the structure are allowed. Returns an
+/// empty range at `struct_start` when there is none.
+pub fn find_documentation_range(source_code: &str, struct_start: usize, doc_style: DocStyle) -> (usize, usize) {
+    let (block, line_prefix) = match doc_style {
+        DocStyle::Above { block, line_prefix } => (block, line_prefix),
+        DocStyle::Docstring => return (struct_start, struct_start),
+    };
+
+    // Byte ranges of the complete lines before the structure, last one first.
+    let before = &source_code[..struct_start];
+    let before = &before[..before.rfind('\n').map_or(0, |i| i + 1)];
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    for line in before.split_inclusive('\n') {
+        lines.push((line_start, line_start + line.trim_end().len()));
+        line_start += line.len();
+    }
+    let mut lines = lines.into_iter().rev().skip_while(|&(start, end)| source_code[start..end].trim().is_empty()).peekable();
+
+    let trimmed_start = |(start, end): (usize, usize)| end - source_code[start..end].trim_start().len();
+    let mut doc_range: Option<(usize, usize)> = None;
+
+    if let Some(last) = lines.peek().copied() {
+        let last_line = source_code[last.0..last.1].trim();
+        if let Some((open, close)) = block.filter(|(_, close)| last_line.ends_with(close)) {
+            // The block opener without its doc marker, e.g. `/*` for `/**`.
+            let comment_open = &open[..open.len() - 1];
+            for line in lines.by_ref() {
+                let text = source_code[line.0..line.1].trim();
+                if text.starts_with(open) {
+                    doc_range = Some((trimmed_start(line), last.1));
+                    break;
+                }
+                if text.starts_with(comment_open) || (line != last && text.ends_with(close)) {
+                    break;
+                }
+            }
+        } else if let Some(prefix) = line_prefix.filter(|prefix| last_line.starts_with(prefix)) {
+            for line in lines.by_ref() {
+                if !source_code[line.0..line.1].trim().starts_with(prefix) {
+                    break;
+                }
+                doc_range = Some((trimmed_start(line), last.1));
+            }
+        }
+    }
+
+    doc_range.unwrap_or((struct_start, struct_start))
+}
@@ -0,0 +1,352 @@
+//! The template language used by the request prompts.
+//!
+//! Templates keep the `{placeholder}` syntax of the original `[requests]`
+//! templates and add a few tags:
+//!
+//! - `{name}` is replaced by the value of `name`. A name with no value is kept
+//!   as literal text, braces included.
+//! - `{#if name}...{#else}...{/if}` keeps the first part when `name` is set and
+//!   not blank (or a non-empty list), the optional `{#else}` part otherwise.
+//! - `{#each name}...{/each}` repeats its body for every item of the list
+//!   `name`. Inside the body the fields of the item and `{index}` are available.
+//! - `{>path/to/fragment.txt}` includes another template file.
+//! - `{{` and `}}` produce literal braces.
+//!
+//! Any other `{` is kept as is, so code in a template, such as `{x}` in a
+//! format string, does not need escaping.
+//! Values are inserted in a single pass and never re-scanned, so source code
+//! containing `{user_request}` is sent verbatim.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Maximum nesting of `{>include}` tags, to stop include cycles.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Text(String),
+    List(Vec<BTreeMap<String, Value>>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.trim().is_empty(),
+            Value::List(items) => !items.is_empty(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {} column {}", self.message, self.line, self.column)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn error(self, message: impl Into<String>) -> TemplateError {
+        TemplateError { message: message.into(), line: self.line, column: self.column }
+    }
+}
+
+#[derive(Debug)]
+pub enum Node {
+    Text(String),
+    Variable(String, Position),
+    If { name: String, then: Vec<Node>, otherwise: Vec<Node>, position: Position },
+    Each { name: String, body: Vec<Node>, position: Position },
+    Include(String, Position),
+}
+
+enum Token {
+    Text(String),
+    Variable(String),
+    If(String),
+    Else,
+    EndIf,
+    Each(String),
+    EndEach,
+    Include(String),
+}
+
+/// Values available to a template, plus where `{>include}` paths are resolved from.
+#[derive(Default)]
+pub struct Context {
+    values: BTreeMap<String, Value>,
+    include_dir: Option<PathBuf>,
+}
+
+impl Context {
+    pub fn new(include_dir: Option<PathBuf>) -> Self {
+        Context { values: BTreeMap::new(), include_dir }
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.values.insert(name.to_string(), value.into());
+    }
+
+    /// Resolves an include path: absolute and `~/` paths are used as is,
+    /// relative ones are looked up in the include directory.
+    pub fn include_path(&self, path: &str) -> PathBuf {
+        resolve_include(self.include_dir.as_deref(), path)
+    }
+}
+
+pub fn resolve_include(include_dir: Option<&Path>, path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Ok(home) = std::env::var("HOME") {
+            return Path::new(&home).join(rest);
+        }
+    }
+    let path = Path::new(path);
+    match include_dir {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn advance(position: &mut Position, consumed: &str) {
+    for c in consumed.chars() {
+        if c == '\n' {
+            position.line += 1;
+            position.column = 1;
+        } else {
+            position.column += 1;
+        }
+    }
+}
+
+/// Splits a template into tokens, remembering where each one starts.
+fn tokenize(template: &str) -> Result<Vec<(Token, Position)>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut text_position = Position { line: 1, column: 1 };
+    let mut position = Position { line: 1, column: 1 };
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            if text.is_empty() {
+                text_position = position;
+            }
+            text.push_str(&rest[..1]);
+            advance(&mut position, &rest[..2]);
+            rest = &rest[2..];
+            continue;
+        }
+
+        if rest.starts_with('{') {
+            if let Some(close) = rest.find('}') {
+                let inner = &rest[1..close];
+                let token = if is_identifier(inner) {
+                    Some(Token::Variable(inner.to_string()))
+                } else if let Some(name) = inner.strip_prefix("#if ") {
+                    Some(Token::If(name.trim().to_string()))
+                } else if inner == "#else" {
+                    Some(Token::Else)
+                } else if inner == "/if" {
+                    Some(Token::EndIf)
+                } else if let Some(name) = inner.strip_prefix("#each ") {
+                    Some(Token::Each(name.trim().to_string()))
+                } else if inner == "/each" {
+                    Some(Token::EndEach)
+                } else {
+                    inner.strip_prefix('>').map(|path| Token::Include(path.trim().to_string()))
+                };
+
+                if let Some(token) = token {
+                    match &token {
+                        Token::If(name) | Token::Each(name) if !is_identifier(name) => {
+                            return Err(position.error(format!("invalid variable name '{}'", name)));
+                        }
+                        Token::Include(path) if path.is_empty() => {
+                            return Err(position.error("include without a path"));
+                        }
+                        _ => {}
+                    }
+                    if !text.is_empty() {
+                        tokens.push((Token::Text(std::mem::take(&mut text)), text_position));
+                    }
+                    tokens.push((token, position));
+                    advance(&mut position, &rest[..=close]);
+                    rest = &rest[close + 1..];
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        let (current, remaining) = rest.split_at(c.len_utf8());
+        if text.is_empty() {
+            text_position = position;
+        }
+        text.push_str(current);
+        advance(&mut position, current);
+        rest = remaining;
+    }
+
+    if !text.is_empty() {
+        tokens.push((Token::Text(text), text_position));
+    }
+    Ok(tokens)
+}
+
+/// Parses a template into its syntax tree.
+pub fn parse(template: &str) -> Result<Vec<Node>, TemplateError> {
+    let tokens = tokenize(template)?;
+    let mut tokens = tokens.into_iter();
+    let (nodes, terminator) = parse_nodes(&mut tokens)?;
+    match terminator {
+        None => Ok(nodes),
+        Some((token, position)) => Err(position.error(match token {
+            Token::Else => "{#else} without a matching {#if}",
+            Token::EndIf => "{/if} without a matching {#if}",
+            _ => "{/each} without a matching {#each}",
+        })),
+    }
+}
+
+/// Nodes parsed so far and the closing tag that ended them, if any.
+type ParsedNodes = (Vec<Node>, Option<(Token, Position)>);
+
+/// Parses nodes until the end of input or a closing tag, which is returned to the caller.
+fn parse_nodes(
+    tokens: &mut impl Iterator<Item = (Token, Position)>,
+) -> Result<ParsedNodes, TemplateError> {
+    let mut nodes = Vec::new();
+
+    while let Some((token, position)) = tokens.next() {
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text)),
+            Token::Variable(name) => nodes.push(Node::Variable(name, position)),
+            Token::Include(path) => nodes.push(Node::Include(path, position)),
+            Token::If(name) => {
+                let (then, terminator) = parse_nodes(tokens)?;
+                let otherwise = match terminator {
+                    Some((Token::EndIf, _)) => Vec::new(),
+                    Some((Token::Else, _)) => match parse_nodes(tokens)? {
+                        (otherwise, Some((Token::EndIf, _))) => otherwise,
+                        _ => return Err(position.error(format!("{{#if {}}} is not closed with {{/if}}", name))),
+                    },
+                    _ => return Err(position.error(format!("{{#if {}}} is not closed with {{/if}}", name))),
+                };
+                nodes.push(Node::If { name, then, otherwise, position });
+            }
+            Token::Each(name) => {
+                let (body, terminator) = parse_nodes(tokens)?;
+                match terminator {
+                    Some((Token::EndEach, _)) => nodes.push(Node::Each { name, body, position }),
+                    _ => return Err(position.error(format!("{{#each {}}} is not closed with {{/each}}", name))),
+                }
+            }
+            Token::Else | Token::EndIf | Token::EndEach => return Ok((nodes, Some((token, position)))),
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+/// Renders `template` with the values in `context`.
+pub fn render(template: &str, context: &Context) -> Result<String, TemplateError> {
+    let nodes = parse(template)?;
+    let mut output = String::new();
+    render_nodes(&nodes, context, &[&context.values], 0, &mut output)?;
+    Ok(output)
+}
+
+fn lookup<'a>(scopes: &[&'a BTreeMap<String, Value>], name: &str) -> Option<&'a Value> {
+    scopes.iter().rev().find_map(|scope| scope.get(name))
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    context: &Context,
+    scopes: &[&BTreeMap<String, Value>],
+    depth: usize,
+    output: &mut String,
+) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Variable(name, position) => match lookup(scopes, name) {
+                Some(Value::Text(text)) => output.push_str(text),
+                Some(Value::List(_)) => {
+                    return Err(position.error(format!("'{}' is a list, use {{#each {}}} to render it", name, name)));
+                }
+                None => {
+                    output.push('{');
+                    output.push_str(name);
+                    output.push('}');
+                }
+            },
+            Node::If { name, then, otherwise, .. } => {
+                let branch = if lookup(scopes, name).is_some_and(Value::is_truthy) { then } else { otherwise };
+                render_nodes(branch, context, scopes, depth, output)?;
+            }
+            Node::Each { name, body, position } => {
+                let items = match lookup(scopes, name) {
+                    Some(Value::List(items)) => items,
+                    Some(Value::Text(_)) => return Err(position.error(format!("'{}' is not a list", name))),
+                    None => continue,
+                };
+                for (index, item) in items.iter().enumerate() {
+                    let mut item_scope = item.clone();
+                    item_scope.insert("index".to_string(), Value::Text((index + 1).to_string()));
+                    let mut item_scopes = scopes.to_vec();
+                    item_scopes.push(&item_scope);
+                    render_nodes(body, context, &item_scopes, depth, output)?;
+                }
+            }
+            Node::Include(path, position) => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(position.error(format!("includes nested deeper than {} levels", MAX_INCLUDE_DEPTH)));
+                }
+                let include_path = context.include_path(path);
+                let fragment = fs::read_to_string(&include_path).map_err(|e| {
+                    position.error(format!("failed to include {}: {}", include_path.display(), e))
+                })?;
+                let fragment_nodes = parse(&fragment).map_err(|e| {
+                    position.error(format!("in {}: {}", include_path.display(), e))
+                })?;
+                render_nodes(&fragment_nodes, context, scopes, depth + 1, output)?;
+            }
+        }
+    }
+    Ok(())
+}
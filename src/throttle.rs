@@ -0,0 +1,173 @@
+//! The limits on the requests sent to the backend, `max_concurrency` and
+//! `requests_per_minute`.
+//!
+//! They are shared by every `rfcu` process of the repository, such as the
+//! workers of `rfcu run`, through lock files in a temporary directory named
+//! after the repository root: a request waits for one of `max_concurrency`
+//! slot files, each locked while a request is in flight, and the times of the
+//! requests sent in the last minute are kept in a file read and written under
+//! a lock.
+//!
+//! Only the requests run concurrently: one more lock file lets a single
+//! process at a time splice, write and validate its edit, so the checks of one
+//! file never see the unvalidated edit of another.
+
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{fnv1a, CompletionBackend, CompletionRequest, FNV_OFFSET_BASIS};
+use crate::config::Settings;
+use crate::history::Journal;
+
+/// How long to wait before looking for a free slot again.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const MINUTE_MS: u64 = 60_000;
+
+/// A backend whose requests wait for the limits before they are sent.
+pub struct ThrottledBackend {
+    inner: Box<dyn CompletionBackend>,
+    dir: PathBuf,
+    max_concurrency: usize,
+    requests_per_minute: Option<u32>,
+}
+
+/// Wraps `backend` in the limits. Replayed answers do not reach an LLM, so
+/// the replay backend is returned as it is.
+pub fn wrap(backend: Box<dyn CompletionBackend>, settings: &Settings) -> Box<dyn CompletionBackend> {
+    if backend.name() == "replay" {
+        return backend;
+    }
+    Box::new(ThrottledBackend {
+        inner: backend,
+        dir: throttle_dir(),
+        max_concurrency: settings.max_concurrency.max(1),
+        requests_per_minute: settings.requests_per_minute.map(|limit| limit.max(1)),
+    })
+}
+
+/// The directory of the lock files of the current repository.
+fn throttle_dir() -> PathBuf {
+    let journal = Journal::open();
+    let root = fs::canonicalize(journal.root()).unwrap_or_else(|_| journal.root().to_path_buf());
+    let key = fnv1a(root.to_string_lossy().as_bytes(), FNV_OFFSET_BASIS);
+    env::temp_dir().join(format!("rfcu-throttle-{:016x}", key))
+}
+
+/// Waits until no other `rfcu` process of the repository is splicing, writing
+/// or validating an edit, and keeps them waiting until the returned file is
+/// dropped.
+pub fn lock_edits() -> io::Result<File> {
+    let dir = throttle_dir();
+    fs::create_dir_all(&dir)?;
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(dir.join("edits.lock"))?;
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(TryLockError::WouldBlock) => {}
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    eprintln!("Waiting for another run to finish validating its edit...");
+    file.lock()?;
+    Ok(file)
+}
+
+impl ThrottledBackend {
+    /// Locks a free slot, waiting for one if they are all in use. The slot is
+    /// released when the returned file is dropped.
+    fn acquire_slot(&self) -> io::Result<File> {
+        fs::create_dir_all(&self.dir)?;
+        let mut waiting = false;
+        loop {
+            for slot in 0..self.max_concurrency {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(self.dir.join(format!("slot-{}.lock", slot)))?;
+                match file.try_lock() {
+                    Ok(()) => return Ok(file),
+                    Err(TryLockError::WouldBlock) => {}
+                    Err(TryLockError::Error(e)) => return Err(e),
+                }
+            }
+            if !waiting {
+                eprintln!("Waiting for one of the {} requests in flight to finish (max_concurrency)...", self.max_concurrency);
+                waiting = true;
+            }
+            thread::sleep(SLOT_POLL_INTERVAL);
+        }
+    }
+
+    /// Waits until a request can be sent without going over
+    /// `requests_per_minute`, and counts it.
+    fn wait_for_rate(&self, requests_per_minute: u32) -> io::Result<()> {
+        loop {
+            let wait = {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(self.dir.join("requests.lock"))?;
+                file.lock()?;
+
+                let mut content = String::new();
+                file.read_to_string(&mut content)?;
+                let now = now_ms();
+                let mut sent: Vec<u64> = content
+                    .lines()
+                    .filter_map(|line| line.trim().parse().ok())
+                    .filter(|time| now.saturating_sub(*time) < MINUTE_MS)
+                    .collect();
+
+                let wait = if sent.len() < requests_per_minute as usize {
+                    sent.push(now);
+                    None
+                } else {
+                    let oldest = sent.iter().min().copied().unwrap_or(now);
+                    Some(Duration::from_millis(oldest + MINUTE_MS - now))
+                };
+                file.set_len(0)?;
+                file.rewind()?;
+                for time in &sent {
+                    writeln!(file, "{}", time)?;
+                }
+                wait
+            };
+
+            match wait {
+                None => return Ok(()),
+                Some(wait) => {
+                    eprintln!(
+                        "{} requests were sent in the last minute (requests_per_minute), waiting {:.1}s...",
+                        requests_per_minute,
+                        wait.as_secs_f64()
+                    );
+                    thread::sleep(wait);
+                }
+            }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or_default()
+}
+
+impl CompletionBackend for ThrottledBackend {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn complete(&self, request: &CompletionRequest) -> io::Result<String> {
+        let _slot = self.acquire_slot()?;
+        if let Some(requests_per_minute) = self.requests_per_minute {
+            self.wait_for_rate(requests_per_minute)?;
+        }
+        self.inner.complete(request)
+    }
+}
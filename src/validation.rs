@@ -0,0 +1,332 @@
+//! The validation pipeline an edit must pass before it is committed.
+//!
+//! Stages run in the configured order, except `parse` stages, which check the
+//! edited code before the file is written. The first failing `required` stage
+//! rejects the attempt and the stages after it are skipped. A failing
+//! `advisory` stage is reported and the pipeline carries on.
+
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tree_sitter::{Node, Parser};
+
+use crate::config::{Policy, StageKind, ValidationStage};
+use crate::diagnostics;
+use crate::language::LanguageSpec;
+
+/// What is being validated.
+pub struct Target<'a> {
+    pub file_path: &'a str,
+    /// The name of the edited structure, used to pick the tests to run.
+    pub structure_name: &'a str,
+    /// The content of the file before the edit.
+    pub original_code: &'a str,
+    /// The lines of the file changed by the edit (1-based, inclusive).
+    pub edited_lines: (usize, usize),
+    pub spec: &'static LanguageSpec,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Status {
+    Passed,
+    Failed,
+    TimedOut,
+    Skipped,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Passed => "passed",
+            Status::Failed => "failed",
+            Status::TimedOut => "timed out",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+pub struct StageResult {
+    pub name: String,
+    pub policy: Policy,
+    pub status: Status,
+    pub duration: Duration,
+    /// The diagnostics of a failed stage about the edit, for `{previous_errors}`.
+    pub diagnostics: String,
+}
+
+/// The results of every stage of one attempt.
+#[derive(Default)]
+pub struct Report {
+    pub stages: Vec<StageResult>,
+}
+
+impl Report {
+    /// The required stage that rejected the attempt, if any.
+    pub fn failure(&self) -> Option<&StageResult> {
+        self.stages
+            .iter()
+            .find(|result| result.policy == Policy::Required && matches!(result.status, Status::Failed | Status::TimedOut))
+    }
+
+    /// The status of every stage on one line, such as `parse passed, lint
+    /// failed (advisory)`.
+    pub fn summary(&self) -> String {
+        if self.stages.is_empty() {
+            return "no stages".to_string();
+        }
+        self.stages
+            .iter()
+            .map(|result| {
+                let advisory = if result.policy == Policy::Advisory { " (advisory)" } else { "" };
+                format!("{} {}{}", result.name, result.status.name(), advisory)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn print(&self) {
+        eprintln!("Validation report:");
+        for result in &self.stages {
+            let advisory = if result.policy == Policy::Advisory { " (advisory)" } else { "" };
+            eprintln!("  {}: {}{} in {:.1}s", result.name, result.status.name(), advisory, result.duration.as_secs_f64());
+        }
+    }
+}
+
+/// Runs the stages on `updated_code`, calling `write` once the `parse` stages
+/// pass and before any other stage runs.
+pub fn run(
+    stages: &[ValidationStage],
+    target: &Target,
+    updated_code: &str,
+    write: impl FnOnce() -> io::Result<()>,
+) -> io::Result<Report> {
+    let mut report = Report::default();
+    let (parse_stages, other_stages): (Vec<&ValidationStage>, Vec<&ValidationStage>) =
+        stages.iter().partition(|stage| stage.stage == StageKind::Parse);
+
+    let mut write = Some(write);
+    for stage in parse_stages.into_iter().chain(other_stages) {
+        if report.failure().is_some() {
+            report.stages.push(StageResult {
+                name: stage.name().to_string(),
+                policy: stage.policy,
+                status: Status::Skipped,
+                duration: Duration::ZERO,
+                diagnostics: String::new(),
+            });
+            continue;
+        }
+
+        if stage.stage != StageKind::Parse {
+            if let Some(write) = write.take() {
+                write()?;
+            }
+        }
+
+        eprintln!("Running the {} validation stage...", stage.name());
+        let started = Instant::now();
+        let (status, diagnostics) = match stage.stage {
+            StageKind::Parse => check_syntax(target, updated_code),
+            StageKind::CargoCheck => cargo_check(stage, target)?,
+            StageKind::CargoTest => cargo_test(stage, target)?,
+            StageKind::Command => run_command(stage, target)?,
+        };
+        if status != Status::Passed {
+            eprintln!("The {} stage {}:\n{}", stage.name(), status.name(), diagnostics);
+        }
+        report.stages.push(StageResult {
+            name: stage.name().to_string(),
+            policy: stage.policy,
+            status,
+            duration: started.elapsed(),
+            diagnostics,
+        });
+    }
+
+    if report.failure().is_none() {
+        if let Some(write) = write.take() {
+            write()?;
+        }
+    }
+    Ok(report)
+}
+
+/// Rejects code with `ERROR` or `MISSING` nodes introduced by the edit: in
+/// the edited lines, or more of them than the original had. Code the grammar
+/// could not parse before the edit, such as syntax newer than the grammar,
+/// does not block edits elsewhere in the file.
+fn check_syntax(target: &Target, updated_code: &str) -> (Status, String) {
+    let mut parser = Parser::new();
+    if let Err(e) = parser.set_language(&target.spec.grammar()) {
+        return (Status::Failed, format!("error: cannot load the {} grammar: {}", target.spec.name, e));
+    }
+    let tree = match parser.parse(updated_code, None) {
+        Some(tree) => tree,
+        None => return (Status::Failed, "error: the parser gave up".to_string()),
+    };
+    if !tree.root_node().has_error() {
+        return (Status::Passed, String::new());
+    }
+
+    let mut errors = Vec::new();
+    collect_syntax_errors(tree.root_node(), updated_code, target.file_path, &mut errors);
+    let original_errors = match parser.parse(target.original_code, None) {
+        Some(original_tree) if original_tree.root_node().has_error() => {
+            let mut original_errors = Vec::new();
+            collect_syntax_errors(original_tree.root_node(), target.original_code, target.file_path, &mut original_errors);
+            original_errors.len()
+        }
+        _ => 0,
+    };
+
+    let (first, last) = target.edited_lines;
+    let in_edit: Vec<&SyntaxError> = errors.iter().filter(|error| error.line >= first && error.line <= last).collect();
+    if !in_edit.is_empty() {
+        return (Status::Failed, in_edit.iter().map(|error| error.message.as_str()).collect::<Vec<_>>().join("\n"));
+    }
+    if errors.len() > original_errors {
+        return (Status::Failed, errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>().join("\n"));
+    }
+    eprintln!("Ignoring {} syntax error(s) outside the edit, which were there before it.", errors.len());
+    (Status::Passed, String::new())
+}
+
+struct SyntaxError {
+    /// The 1-based line the error starts on.
+    line: usize,
+    message: String,
+}
+
+fn collect_syntax_errors(node: Node, source_code: &str, file_path: &str, errors: &mut Vec<SyntaxError>) {
+    let position = node.start_position();
+    let location = format!("{}:{}:{}", file_path, position.row + 1, position.column + 1);
+    if node.is_missing() {
+        errors.push(SyntaxError { line: position.row + 1, message: format!("error: missing `{}` at {}", node.kind(), location) });
+        return;
+    }
+    if node.is_error() {
+        let text = &source_code[node.start_byte()..node.end_byte()];
+        let excerpt: String = text.lines().next().unwrap_or_default().chars().take(60).collect();
+        errors.push(SyntaxError {
+            line: position.row + 1,
+            message: format!("error: syntax error at {} near `{}`", location, excerpt.trim()),
+        });
+        return;
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.has_error() {
+            collect_syntax_errors(child, source_code, file_path, errors);
+        }
+    }
+}
+
+/// Runs `cargo check --message-format=json` and keeps the rendered errors.
+fn cargo_check(stage: &ValidationStage, target: &Target) -> io::Result<(Status, String)> {
+    let mut command = Command::new("cargo");
+    command.arg("check").arg("--message-format=json").args(&stage.args);
+    let outcome = run_with_timeout(command, stage.timeout_secs)?;
+    if outcome.timed_out {
+        return Ok((Status::TimedOut, format!("cargo check did not finish within {} seconds", stage.timeout_secs.unwrap_or_default())));
+    }
+
+    let errors: Vec<String> = outcome
+        .stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-message" && message["message"]["level"] == "error")
+        .filter_map(|message| message["message"]["rendered"].as_str().map(str::to_string))
+        .collect();
+
+    if outcome.success && errors.is_empty() {
+        return Ok((Status::Passed, String::new()));
+    }
+    let output = if errors.is_empty() { outcome.stderr } else { errors.join("\n") };
+    Ok((Status::Failed, diagnostics::relevant(&output, target.file_path, target.edited_lines)))
+}
+
+/// Runs `cargo test`, filtered by the name of the edited structure when there
+/// is one.
+fn cargo_test(stage: &ValidationStage, target: &Target) -> io::Result<(Status, String)> {
+    let mut command = Command::new("cargo");
+    command.arg("test").args(&stage.args);
+    let filter = target.structure_name.rsplit("::").next().unwrap_or_default();
+    if !filter.is_empty() && filter.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        eprintln!("Running the tests matching: {}", filter);
+        command.arg(filter);
+    }
+    finish(run_with_timeout(command, stage.timeout_secs)?, stage, target)
+}
+
+fn run_command(stage: &ValidationStage, target: &Target) -> io::Result<(Status, String)> {
+    let command_line = match &stage.command {
+        Some(command_line) => command_line,
+        None => return Ok((Status::Failed, format!("the {} stage has no command", stage.name()))),
+    };
+    eprintln!("Executing validation command: {}", command_line);
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    finish(run_with_timeout(command, stage.timeout_secs)?, stage, target)
+}
+
+fn finish(outcome: Outcome, stage: &ValidationStage, target: &Target) -> io::Result<(Status, String)> {
+    if outcome.timed_out {
+        return Ok((Status::TimedOut, format!("{} did not finish within {} seconds", stage.name(), stage.timeout_secs.unwrap_or_default())));
+    }
+    if outcome.success {
+        return Ok((Status::Passed, String::new()));
+    }
+    let output = format!("{}{}", outcome.stdout, outcome.stderr);
+    Ok((Status::Failed, diagnostics::relevant(&output, target.file_path, target.edited_lines)))
+}
+
+struct Outcome {
+    success: bool,
+    timed_out: bool,
+    stdout: String,
+    stderr: String,
+}
+
+/// Runs a command, capturing its output, and kills it after `timeout_secs`.
+fn run_with_timeout(mut command: Command, timeout_secs: Option<u64>) -> io::Result<Outcome> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout_reader = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        output
+    });
+
+    let deadline = timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            child.kill()?;
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    // After a timeout, grandchildren of a killed shell may still hold the
+    // pipes open, so their output is abandoned rather than waited for.
+    let (stdout, stderr) = if timed_out {
+        (String::new(), String::new())
+    } else {
+        (stdout_reader.join().unwrap_or_default(), stderr_reader.join().unwrap_or_default())
+    };
+    Ok(Outcome { success: status.success() && !timed_out, timed_out, stdout, stderr })
+}
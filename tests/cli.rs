@@ -0,0 +1,400 @@
+//! Runs rfcu in a scratch git repository, with a stand-in `fluent` on the
+//! `PATH` that saves the flow and the prompt of every request and answers
+//! with the content of `response.txt`.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const SOURCE: &str = "\
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+fn main() {
+    println!(\"{}\", add(1, 2));
+}
+";
+
+const CONFIG: &str = r#"
+flowname = "user-flow"
+commit_message_flow = "commit"
+documentation_flow = "docs"
+language = "rust"
+max_retries = 1
+
+[requests]
+improvement = "Improve {structure_name}:\n{structure_code}"
+whole_file = "Improve the file:\n{source_code}"
+add_functionality = "Add {user_request} to:\n{source_code}"
+add_tests_function = "Write tests for {structure_name}:\n{structure_code}"
+documentation_whole_file = "Document the file:\n{source_code}"
+documentation_structure = "Document {structure_name}:\n{structure_code}"
+"#;
+
+/// Appends the flow to `flows.txt`, answers commit message requests with
+/// "Test commit" and everything else with `response.txt`, after saving the
+/// prompt in `prompt.txt`.
+const FLUENT_SCRIPT: &str = r#"#!/bin/sh
+cat > /dev/null
+printf '%s\n' "$1" >> flows.txt
+case "$1" in
+  commit) echo "Test commit" ;;
+  *) printf '%s' "$2" > prompt.txt; cat response.txt ;;
+esac
+"#;
+
+struct TestRepo {
+    dir: PathBuf,
+}
+
+impl TestRepo {
+    /// Creates a repository with `SOURCE` committed, `CONFIG` in `config.toml`
+    /// and the stand-in `fluent` in `bin/`.
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("rfcu-cli-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("src/main.rs"), SOURCE).unwrap();
+        fs::write(dir.join("config.toml"), CONFIG).unwrap();
+        fs::write(dir.join("bin/fluent"), FLUENT_SCRIPT).unwrap();
+        fs::set_permissions(dir.join("bin/fluent"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("response.txt"), "pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}").unwrap();
+
+        let repo = TestRepo { dir };
+        repo.git(&["init", "-q"]);
+        repo.git(&["add", "src/main.rs"]);
+        repo.git(&["commit", "-q", "-m", "Initial commit"]);
+        repo.git(&["tag", "start"]);
+        repo
+    }
+
+    /// Goes back to the commit tagged `start`, since a run that changes
+    /// nothing has nothing to commit.
+    fn reset(&self) {
+        self.git(&["reset", "-q", "--hard", "start"]);
+    }
+
+    fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .output()
+            .expect("Failed to run git");
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        let path = self.dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Runs rfcu with `envs` on top of a clean environment whose `HOME` is
+    /// the repository.
+    fn run(&self, args: &[&str], stdin: &str, envs: &[(&str, &str)]) -> Output {
+        let path = format!("{}:{}", self.dir.join("bin").display(), env::var("PATH").unwrap_or_default());
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rfcu"))
+            .args(args)
+            .current_dir(&self.dir)
+            .env("PATH", path)
+            .env("HOME", &self.dir)
+            .env_remove("RFCU_CONFIG")
+            .env_remove("XDG_CONFIG_HOME")
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .envs(envs.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start rfcu");
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+        child.wait_with_output().expect("Failed to wait for rfcu")
+    }
+
+    /// Improves `add` and returns the flow its request was sent to.
+    fn improvement_flow(&self, args: &[&str], envs: &[(&str, &str)]) -> String {
+        self.reset();
+        let _ = fs::remove_file(self.dir.join("flows.txt"));
+        let mut all_args = args.to_vec();
+        all_args.extend(["--file_path", "src/main.rs", "--mode", "improvement", "--structure_name", "add"]);
+        let output = self.run(&all_args, "", envs);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let flows = fs::read_to_string(self.dir.join("flows.txt")).unwrap();
+        flows.lines().next().unwrap().to_string()
+    }
+
+    fn prompt(&self) -> String {
+        fs::read_to_string(self.dir.join("prompt.txt")).unwrap()
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn the_user_config_is_looked_up_in_order() {
+    let repo = TestRepo::new("user-config");
+    let output = repo.run(&["--file_path", "src/main.rs", "--mode", "improvement", "--structure_name", "add"], "", &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No configuration file found"));
+
+    repo.write(".rfcu/config.toml", &CONFIG.replace("user-flow", "home-flow"));
+    assert_eq!(repo.improvement_flow(&[], &[]), "home-flow");
+
+    repo.write("xdg/rfcu/config.toml", &CONFIG.replace("user-flow", "xdg-flow"));
+    let xdg = repo.dir.join("xdg").display().to_string();
+    assert_eq!(repo.improvement_flow(&[], &[("XDG_CONFIG_HOME", &xdg)]), "xdg-flow");
+
+    repo.write("env.toml", &CONFIG.replace("user-flow", "env-flow"));
+    let envs = [("XDG_CONFIG_HOME", xdg.as_str()), ("RFCU_CONFIG", "env.toml")];
+    assert_eq!(repo.improvement_flow(&[], &envs), "env-flow");
+    assert_eq!(repo.improvement_flow(&["--config", "config.toml"], &envs), "user-flow");
+
+    let output = repo.run(&["--config", "missing.toml", "get_structure", "src/main.rs"], "", &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Config file from --config does not exist: missing.toml"));
+}
+
+#[test]
+fn missing_arguments_are_usage_errors() {
+    let repo = TestRepo::new("usage");
+    for args in [
+        &["--config", "config.toml", "--mode", "improvement"][..],
+        &["--config", "config.toml", "--file_path", "src/main.rs"],
+        &["--config", "config.toml", "get_structure"],
+        &["--config", "config.toml", "run"],
+    ] {
+        let output = repo.run(args, "", &[]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("the following required argument"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn project_files_override_the_user_config_key_by_key() {
+    let repo = TestRepo::new("project-config");
+    let config = ["--config", "config.toml"];
+    assert_eq!(repo.improvement_flow(&config, &[]), "user-flow");
+
+    repo.write(".rfcu.toml", "flowname = \"project-flow\"\n");
+    assert_eq!(repo.improvement_flow(&config, &[]), "project-flow");
+    assert!(repo.prompt().starts_with("Improve add:\n"), "the templates still come from the user config");
+
+    repo.write("src/.rfcu.toml", "flowname = \"closer-flow\"\n\n[requests]\nimprovement = \"Closer {structure_name}\"\n");
+    assert_eq!(repo.improvement_flow(&config, &[]), "closer-flow");
+    assert_eq!(repo.prompt(), "Closer add");
+}
+
+#[test]
+fn config_check_reports_keys_syntax_placeholders_and_programs() {
+    let repo = TestRepo::new("config-check");
+    let check = |config: &str| {
+        repo.write("check.toml", config);
+        let output = repo.run(&["--config", "check.toml", "config", "check"], "", &[]);
+        (output.status.success(), format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr)))
+    };
+
+    let (success, report) = check(&format!("colour = \"red\"\n{}", CONFIG));
+    assert!(success, "{}", report);
+    assert!(report.contains("check.toml:1") && report.contains("unknown key 'colour'"), "{}", report);
+
+    let (success, report) = check(&format!("{}\nmax_retries = = 2\n", CONFIG));
+    assert!(!success);
+    assert!(report.contains(&format!("check.toml:{}:", CONFIG.lines().count() + 2)), "{}", report);
+
+    let (success, report) = check(&format!("{}\n[modes.shout]\ntemplate = \"Shout {{structure_name}}\"\nscope = \"structure\"\n", CONFIG));
+    assert!(!success);
+    assert!(report.contains("template is missing the {structure_code} placeholder required by the structure scope"), "{}", report);
+
+    let (success, report) = check(&format!("lint_command = \"cd src && test -f main.rs && if [ -n \\\"$X\\\" ]; then export X=1; fi && rfcu-no-such-linter\"\n{}", CONFIG));
+    assert!(!success);
+    assert!(report.contains("'rfcu-no-such-linter' was not found on PATH"), "{}", report);
+    for builtin in ["cd", "test", "[", "export", "if", "then", "fi"] {
+        assert!(!report.contains(&format!("'{}' was not found on PATH", builtin)), "{}", report);
+    }
+}
+
+#[test]
+fn profiles_override_the_merged_config() {
+    let repo = TestRepo::new("profiles");
+    let config = format!(
+        "{}\n[profiles.fast]\nflowname = \"fast-flow\"\n\n[profiles.thorough]\nflowname = \"thorough-flow\"\n",
+        CONFIG
+    );
+    repo.write("config.toml", &config);
+    // A project file can add to a profile of the user config.
+    repo.write(".rfcu.toml", "flowname = \"project-flow\"\n\n[profiles.fast.requests]\nimprovement = \"Quickly improve {structure_name}\"\n");
+
+    assert_eq!(repo.improvement_flow(&["--config", "config.toml"], &[]), "project-flow");
+    assert_eq!(repo.improvement_flow(&["--config", "config.toml", "--profile", "fast"], &[]), "fast-flow");
+    assert_eq!(repo.prompt(), "Quickly improve add");
+
+    repo.write("config.toml", &format!("{}\n[mode_profiles]\nimprovement = \"thorough\"\n", config));
+    assert_eq!(repo.improvement_flow(&["--config", "config.toml"], &[]), "thorough-flow");
+    assert_eq!(repo.improvement_flow(&["--config", "config.toml", "--profile", "fast"], &[]), "fast-flow");
+
+    let output = repo.run(&["--config", "config.toml", "--profile", "missing", "get_structure", "src/main.rs"], "", &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Profile 'missing' is not defined in any [profiles] table"));
+
+    // A backend of another type does not inherit the fields of the previous one.
+    repo.write(
+        "config.toml",
+        &format!("{}\n[backend]\ntype = \"replay\"\nfixtures = \"fixtures\"\n\n[profiles.record.backend]\ntype = \"record\"\nupstream = {{ type = \"fluent\" }}\n", config),
+    );
+    let args = ["--config", "config.toml", "--profile", "record", "--file_path", "src/main.rs", "--mode", "improvement", "--structure_name", "add"];
+    let output = repo.run(&args, "", &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing field `fixtures`"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn modes_are_sent_to_their_flow() {
+    let repo = TestRepo::new("mode-flows");
+    let modes = "\n[modes.swap_operands]\ntemplate = \"Swap the operands of {structure_name}:\\n{structure_code}\"\nscope = \"structure\"\ninsertion = \"replace\"\nflow = \"swap-flow\"\n";
+    repo.write("config.toml", &format!("{}{}", CONFIG, modes));
+    let flow = |mode: &str| {
+        repo.reset();
+        let _ = fs::remove_file(repo.dir.join("flows.txt"));
+        let output = repo.run(&["--config", "config.toml", "--file_path", "src/main.rs", "--mode", mode, "--structure_name", "add"], "", &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let flows = fs::read_to_string(repo.dir.join("flows.txt")).unwrap();
+        flows.lines().next().unwrap().to_string()
+    };
+
+    assert_eq!(flow("swap_operands"), "swap-flow");
+    assert_eq!(repo.prompt(), "Swap the operands of add:\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}");
+    assert_eq!(flow("improvement"), "user-flow");
+
+    repo.write("response.txt", "/// Adds two numbers.");
+    assert_eq!(flow("documentation_structure"), "docs");
+    assert!(fs::read_to_string(repo.dir.join("src/main.rs")).unwrap().contains("/// Adds two numbers.\npub fn add"));
+
+    repo.write(".rfcu.toml", "[modes.documentation_structure]\nflow = \"project-docs\"\n");
+    assert_eq!(flow("documentation_structure"), "project-docs");
+    assert!(repo.prompt().starts_with("Document add:\n"), "the template still comes from [requests]");
+}
+
+#[test]
+fn templates_render_conditions_lists_includes_and_braces() {
+    let repo = TestRepo::new("templates");
+    let mode = r#"
+[modes.tagged_tests]
+scope = "structure"
+insertion = "append_to_cfg_test"
+template = """{>rules.txt}
+Test {structure_name}{#if impl_type} of {impl_type}{#else} at the top level{/if}.
+{#if user_request}Request: {user_request}{/if}
+{#each siblings}{index}. {name}
+{/each}Literal: {{user_request}}
+{structure_code}"""
+"#;
+    repo.write("config.toml", &format!("prompt_dir = \"prompts\"\n{}{}", CONFIG, mode));
+    repo.write("prompts/rules.txt", "Rules for {language} in {file_path}.");
+    repo.write("src/counter.rs", "pub struct Counter {\n    count: u32,\n}\n\nimpl Counter {\n    pub fn increment(&mut self) {\n        self.count += 1;\n    }\n}\n");
+    repo.write("response.txt", "#[test]\nfn it_works() {}");
+    let prompt = |file_path: &str, structure_name: &str, stdin: &str| {
+        let args = ["--config", "config.toml", "--file_path", file_path, "--mode", "tagged_tests", "--structure_name", structure_name];
+        let output = repo.run(&args, stdin, &[]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        repo.prompt()
+    };
+
+    assert_eq!(
+        prompt("src/main.rs", "add", ""),
+        "Rules for rust in src/main.rs.\nTest add at the top level.\n\n1. main\nLiteral: {user_request}\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}"
+    );
+    let method_prompt = prompt("src/counter.rs", "increment", "Keep it short");
+    assert!(
+        method_prompt.starts_with("Rules for rust in src/counter.rs.\nTest increment of Counter.\nRequest: Keep it short\nLiteral:"),
+        "{}",
+        method_prompt
+    );
+
+    repo.write("prompts/rules.txt", "Rules for {langauge}.");
+    let prompt = prompt("src/main.rs", "add", "");
+    assert!(prompt.starts_with("Rules for {langauge}.\n"), "unknown names are kept as they are: {}", prompt);
+}
+
+const NESTED_SOURCE: &str = "\
+mod outer {
+    pub mod inner {
+        pub fn add(a: i32, b: i32) -> i32 {
+            a + b
+        }
+    }
+
+    pub fn add(a: i32, b: i32) -> i32 {
+        inner::add(a, b)
+    }
+}
+
+pub struct Counter {
+    count: i32,
+}
+
+impl Counter {
+    pub fn add(&mut self, n: i32) {
+        self.count += n;
+    }
+}
+
+impl std::fmt::Display for Counter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, \"{}\", self.count)
+    }
+}
+";
+
+#[test]
+fn qualified_paths_select_structures_in_modules_and_impl_blocks() {
+    let repo = TestRepo::new("qualified-paths");
+    repo.write("src/main.rs", NESTED_SOURCE);
+    repo.git(&["commit", "-q", "-am", "Nest the functions"]);
+    repo.git(&["tag", "-f", "start"]);
+    repo.write("response.txt", "fn edited() {}");
+    let run = |selector: &str| {
+        repo.reset();
+        let args = ["--config", "config.toml", "--file_path", "src/main.rs", "--mode", "improvement", "--structure_name", selector];
+        repo.run(&args, "", &[])
+    };
+
+    for (selector, found) in [
+        ("outer::inner::add", "outer::inner::add"),
+        ("inner::add", "outer::inner::add"),
+        ("outer::add", "outer::add"),
+        ("Counter::add", "Counter::add"),
+        ("impl Counter::add", "Counter::add"),
+        ("Counter::fmt", "<Counter as std::fmt::Display>::fmt"),
+        ("impl std::fmt::Display for Counter::fmt", "<Counter as std::fmt::Display>::fmt"),
+    ] {
+        let output = run(selector);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}: {}", selector, stderr);
+        assert!(stderr.contains(&format!("Structure found: {}\n", found)), "{}: {}", selector, stderr);
+    }
+
+    let output = run("add");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("  outer::inner::add (function_item, line 3)"), "{}", stderr);
+    assert!(stderr.contains("  outer::add (function_item, line 8)"), "{}", stderr);
+    assert!(stderr.contains("  Counter::add (function_item, line 18)"), "{}", stderr);
+}
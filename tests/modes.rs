@@ -0,0 +1,1136 @@
+//! Runs every mode end to end against a scratch git repository. Each test
+//! records the canned answer of a command backend into fixtures, then resets
+//! the repository and replays the same run from the fixtures alone.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const SOURCE: &str = "\
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub struct Counter {
+    count: u32,
+}
+
+impl Counter {
+    /// Old docs.
+    pub fn increment(&mut self) {
+        self.count += 1;
+    }
+}
+
+fn main() {
+    println!(\"{}\", add(1, 2));
+}
+";
+
+const CONFIG: &str = r#"
+flowname = "flow"
+commit_message_flow = "commit"
+documentation_flow = "docs"
+
+[backend]
+type = "replay"
+fixtures = "fixtures"
+
+[profiles.record.backend]
+type = "record"
+fixtures = "fixtures"
+
+[profiles.record.backend.upstream]
+type = "command"
+command = "sh respond.sh"
+
+[modes.add_unit_tests]
+template = "Write tests for {structure_name}:\n{structure_code}"
+insertion = "append_to_cfg_test"
+"#;
+
+/// Answers commit message requests with `$COMMIT_ANSWER`, "Test commit" by
+/// default, and saves them in `commit_prompt.txt`. Answers everything else
+/// with the content of `response.txt`.
+const RESPOND_SCRIPT: &str = r#"input=$(cat)
+case "$input" in
+  *"subject line of a git commit message"*)
+    printf '%s' "$input" > commit_prompt.txt
+    printf '%s\n' "${COMMIT_ANSWER-Test commit}" ;;
+  *) cat response.txt ;;
+esac
+"#;
+
+struct TestRepo {
+    dir: PathBuf,
+}
+
+impl TestRepo {
+    /// Creates a repository with `SOURCE` committed. `extra_config` is added to
+    /// the top of the configuration and defaults `max_retries` to 1.
+    fn new(name: &str, extra_config: &str) -> Self {
+        let dir = env::temp_dir().join(format!("rfcu-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/main.rs"), SOURCE).unwrap();
+        let max_retries = if extra_config.contains("max_retries") { "" } else { "max_retries = 1\n" };
+        fs::write(dir.join("config.toml"), format!("{}{}\n{}", max_retries, extra_config, CONFIG)).unwrap();
+        fs::write(dir.join("respond.sh"), RESPOND_SCRIPT).unwrap();
+
+        let repo = TestRepo { dir };
+        repo.git(&["init", "-q"]);
+        repo.git(&["add", "src/main.rs"]);
+        repo.git(&["commit", "-q", "-m", "Initial commit"]);
+        repo
+    }
+
+    fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .output()
+            .expect("Failed to run git");
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        fs::write(self.dir.join(path), content).unwrap();
+    }
+
+    fn respond_with(&self, response: &str) {
+        self.write("response.txt", response);
+    }
+
+    fn source(&self) -> String {
+        fs::read_to_string(self.dir.join("src/main.rs")).unwrap()
+    }
+
+    fn last_commit_message(&self) -> String {
+        self.git(&["log", "-1", "--format=%s"]).trim().to_string()
+    }
+
+    /// Resets the source to the initial commit and removes the upstream
+    /// script, so only the fixtures can answer.
+    fn reset(&self) {
+        self.git(&["reset", "-q", "--hard", "HEAD~1"]);
+        let _ = fs::remove_file(self.dir.join("respond.sh"));
+    }
+
+    fn run(&self, profile: Option<&str>, args: &[&str], stdin: &str) -> Output {
+        self.run_with_env(profile, args, stdin, &[])
+    }
+
+    /// Runs rfcu on `src/main.rs`, unless `args` pass another `--file_path`.
+    fn run_with_env(&self, profile: Option<&str>, args: &[&str], stdin: &str, envs: &[(&str, &str)]) -> Output {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rfcu"));
+        command.arg("--config").arg("config.toml");
+        if !args.contains(&"--file_path") {
+            command.arg("--file_path").arg("src/main.rs");
+        }
+        command
+            .args(args)
+            .current_dir(&self.dir)
+            .env("HOME", &self.dir)
+            .env_remove("RFCU_CONFIG")
+            .env_remove("XDG_CONFIG_HOME")
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .envs(envs.iter().copied());
+        if let Some(profile) = profile {
+            command.arg("--profile").arg(profile);
+        }
+
+        let mut child = command.spawn().expect("Failed to start rfcu");
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+        child.wait_with_output().expect("Failed to wait for rfcu")
+    }
+
+    /// Runs a mode while recording the answers, then again from the recorded
+    /// fixtures, and returns the edited source after checking both runs agree.
+    fn record_and_replay(&self, args: &[&str], stdin: &str) -> String {
+        let recorded = self.run(Some("record"), args, stdin);
+        assert!(recorded.status.success(), "recording failed: {}", String::from_utf8_lossy(&recorded.stderr));
+        assert_eq!(self.last_commit_message(), "Test commit");
+        let recorded_source = self.source();
+
+        self.reset();
+        let replayed = self.run(None, args, stdin);
+        assert!(replayed.status.success(), "replaying failed: {}", String::from_utf8_lossy(&replayed.stderr));
+        assert_eq!(self.last_commit_message(), "Test commit");
+        assert_eq!(self.source(), recorded_source);
+        recorded_source
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn improvement_replaces_the_selected_method() {
+    let repo = TestRepo::new("improvement", "");
+    repo.respond_with("pub fn increment(&mut self) {\n    self.count = self.count.saturating_add(1);\n}");
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--structure_name", "Counter::increment"], "Avoid overflow");
+
+    assert!(source.contains(
+        "    /// Old docs.\n    pub fn increment(&mut self) {\n        self.count = self.count.saturating_add(1);\n    }\n}"
+    ), "{}", source);
+    assert!(source.contains("pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}"), "{}", source);
+}
+
+#[test]
+fn fenced_answers_are_stripped_to_their_code() {
+    let repo = TestRepo::new("fenced", "");
+    repo.respond_with("Here is the improved method:\n\n```rust\npub fn increment(&mut self) {\n    self.count += 2;\n}\n```\n\nIt counts twice as fast.\n");
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--structure_name", "Counter::increment"], "");
+
+    assert!(source.contains("    pub fn increment(&mut self) {\n        self.count += 2;\n    }\n}"), "{}", source);
+    assert!(!source.contains("```"), "{}", source);
+    assert!(!source.contains("Here is"), "{}", source);
+}
+
+#[test]
+fn improvement_selects_the_structure_under_a_line() {
+    let repo = TestRepo::new("improvement-line", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    a.wrapping_add(b)\n}");
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--line", "2"], "");
+
+    assert!(source.starts_with("pub fn add(a: i32, b: i32) -> i32 {\n    a.wrapping_add(b)\n}\n"), "{}", source);
+}
+
+#[test]
+fn whole_file_replaces_the_file() {
+    let repo = TestRepo::new("whole-file", "");
+    repo.respond_with("fn main() {\n    println!(\"3\");\n}");
+
+    let source = repo.record_and_replay(&["--mode", "whole_file"], "");
+
+    assert_eq!(source, "fn main() {\n    println!(\"3\");\n}");
+}
+
+#[test]
+fn add_functionality_inserts_after_main() {
+    let repo = TestRepo::new("add-functionality", "");
+    repo.respond_with("fn sub(a: i32, b: i32) -> i32 {\n    a - b\n}");
+
+    let source = repo.record_and_replay(&["--mode", "add_functionality"], "Add a sub function");
+
+    assert!(
+        source.ends_with("    println!(\"{}\", add(1, 2));\n}\n\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n"),
+        "{}",
+        source
+    );
+}
+
+#[test]
+fn add_tests_function_inserts_after_the_structure() {
+    let repo = TestRepo::new("add-tests-function", "");
+    repo.respond_with("#[test]\nfn test_add() {\n    assert_eq!(add(1, 2), 3);\n}");
+
+    let source = repo.record_and_replay(&["--mode", "add_tests_function", "--structure_name", "add"], "");
+
+    assert!(source.starts_with(
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\n#[test]\nfn test_add() {\n    assert_eq!(add(1, 2), 3);\n}\n\npub struct Counter"
+    ), "{}", source);
+}
+
+#[test]
+fn documentation_structure_inserts_missing_docs() {
+    let repo = TestRepo::new("documentation-insert", "");
+    repo.respond_with("/// Adds two numbers.");
+
+    let source = repo.record_and_replay(&["--mode", "documentation_structure", "--structure_name", "add"], "");
+
+    assert!(source.starts_with("/// Adds two numbers.\npub fn add(a: i32, b: i32) -> i32 {"), "{}", source);
+}
+
+#[test]
+fn documentation_structure_replaces_existing_docs() {
+    let repo = TestRepo::new("documentation-replace", "");
+    repo.respond_with("/// Increments the counter by one.");
+
+    let source = repo.record_and_replay(&["--mode", "documentation_structure", "--structure_name", "increment"], "");
+
+    assert!(source.contains("    /// Increments the counter by one.\n    pub fn increment(&mut self) {"), "{}", source);
+    assert!(!source.contains("Old docs"));
+}
+
+#[test]
+fn documentation_whole_file_adds_docs_at_the_top() {
+    let repo = TestRepo::new("documentation-whole-file", "");
+    repo.respond_with("//! A tiny calculator.");
+
+    let source = repo.record_and_replay(&["--mode", "documentation_whole_file"], "");
+
+    assert_eq!(source, format!("//! A tiny calculator.\n{}", SOURCE));
+}
+
+#[test]
+fn custom_mode_appends_to_the_test_module() {
+    let repo = TestRepo::new("custom-mode", "");
+    repo.respond_with("#[test]\nfn adds() {\n    assert_eq!(add(2, 2), 4);\n}");
+
+    let source = repo.record_and_replay(&["--mode", "add_unit_tests", "--structure_name", "add"], "");
+
+    assert!(source.contains("#[cfg(test)]\nmod tests {\n#[test]\nfn adds() {\n    assert_eq!(add(2, 2), 4);\n}\n}\n"), "{}", source);
+}
+
+#[test]
+fn unknown_names_in_braces_are_sent_as_literal_text() {
+    let repo = TestRepo::new("template-literal", "");
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    let mode = "\n[modes.print_tests]\ntemplate = \"Write tests for {structure_name} that print format!(\\\"{x}\\\"):\\n{structure_code}\"\ninsertion = \"append_to_cfg_test\"\n";
+    repo.write("config.toml", &format!("{}{}", config, mode));
+    repo.write("respond.sh", &RESPOND_SCRIPT.replace("*) cat response.txt", "*) printf '%s' \"$input\" > prompt.txt; cat response.txt"));
+    repo.respond_with("#[test]\nfn adds() {\n    println!(\"{}\", add(2, 2));\n}");
+
+    let check = repo.run(None, &["config", "check"], "");
+    assert!(check.status.success(), "{}", String::from_utf8_lossy(&check.stderr));
+    let report = format!("{}{}", String::from_utf8_lossy(&check.stdout), String::from_utf8_lossy(&check.stderr));
+    assert!(report.contains("{x} at line 1 column"), "{}", report);
+
+    let output = repo.run(Some("record"), &["--mode", "print_tests", "--structure_name", "add"], "");
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let prompt = fs::read_to_string(repo.dir.join("prompt.txt")).unwrap();
+    assert!(prompt.starts_with("Write tests for add that print format!(\"{x}\"):\npub fn add"), "{}", prompt);
+}
+
+#[test]
+fn structures_of_other_languages_are_edited_in_place() {
+    let repo = TestRepo::new("languages", "");
+    repo.write("src/app.py", "def foo():\n    return 1\n\n\ndef bar():\n    return 2\n");
+    repo.write("package.json", "{\n  \"name\": \"app\",\n  \"scripts\": {\n    \"build\": \"tsc\"\n  }\n}\n");
+    repo.git(&["add", "src/app.py", "package.json"]);
+    repo.git(&["commit", "-q", "-m", "Add other languages"]);
+
+    repo.respond_with("```python\ndef foo():\n    return 42\n```");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--file_path", "src/app.py", "--structure_name", "foo"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(repo.dir.join("src/app.py")).unwrap(), "def foo():\n    return 42\n\n\ndef bar():\n    return 2\n");
+    assert_eq!(repo.git(&["show", "--name-only", "--format=", "HEAD"]), "src/app.py\n");
+
+    let output = repo.run(None, &["get_structure", "package.json", "--format", "json"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let items: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let paths: Vec<&str> = items.iter().map(|item| item["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["name", "scripts", "scripts::build"]);
+
+    repo.respond_with("\"build\": \"tsc -p .\"");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--file_path", "package.json", "--structure_name", "build"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::read_to_string(repo.dir.join("package.json")).unwrap().contains("    \"build\": \"tsc -p .\"\n  }"));
+}
+
+#[test]
+fn failing_lint_retries_and_restores_the_file() {
+    let repo = TestRepo::new("lint-failure", "lint_command = \"false\"\n");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--line", "1"], "");
+    assert!(!output.status.success(), "--structure_name and --line are exclusive");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Validation failed at the lint stage (failed)"), "{}", stderr);
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+}
+
+#[test]
+fn syntax_errors_are_rejected_before_the_file_is_written() {
+    let repo = TestRepo::new("parse-failure", "lint_command = \"touch linted\"\n");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    a + \n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Validation failed at the parse stage (failed)"), "{}", stderr);
+    assert!(stderr.contains("lint: skipped"), "{}", stderr);
+    assert!(!repo.dir.join("linted").exists(), "stages after a required failure do not run");
+    assert_eq!(repo.source(), SOURCE);
+}
+
+#[test]
+fn syntax_the_grammar_cannot_parse_only_blocks_edits_that_add_errors() {
+    let repo = TestRepo::new("parse-existing-errors", "");
+    let source = format!("{}\nunsafe extern \"C\" {{\n    pub safe fn abs(x: i32) -> i32;\n}}\n", SOURCE);
+    repo.write("src/main.rs", &source);
+    repo.git(&["commit", "-q", "-am", "Declare abs"]);
+
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--no-commit"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("    b + a\n"));
+
+    repo.git(&["checkout", "-q", "--", "src/main.rs"]);
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    a + \n}");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--no-commit"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Validation failed at the parse stage (failed)"), "{}", stderr);
+    assert_eq!(repo.source(), source);
+}
+
+#[test]
+fn advisory_stages_do_not_reject_the_edit() {
+    let repo = TestRepo::new("advisory", "");
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    let validation = r#"
+[[validation]]
+stage = "parse"
+
+[[validation]]
+stage = "command"
+name = "slow"
+command = "sleep 5"
+timeout_secs = 1
+policy = "advisory"
+
+[[validation]]
+stage = "command"
+name = "style"
+command = "false"
+policy = "advisory"
+"#;
+    repo.write("config.toml", &format!("{}{}", config, validation));
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("slow: timed out (advisory)"), "{}", stderr);
+    assert!(stderr.contains("style: failed (advisory)"), "{}", stderr);
+    assert!(repo.source().contains("b + a"));
+    assert_eq!(repo.last_commit_message(), "Test commit");
+}
+
+#[test]
+fn failing_lint_sends_the_errors_with_the_retry() {
+    let repo = TestRepo::new("lint-retry", "lint_command = \"sh lint.sh\"\nmax_retries = 2\n");
+    repo.write(
+        "lint.sh",
+        "if grep -q 'b + a' src/main.rs; then\n  echo 'warning: unrelated'; echo ' --> src/other.rs:40:1'; echo\n  echo 'error: operands are swapped'; echo ' --> src/main.rs:2:5'; exit 1\nfi\n",
+    );
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"subject line of a git commit message\"*) echo \"Test commit\" ;;\n  *\"operands are swapped\"*) printf 'pub fn add(a: i32, b: i32) -> i32 {\\n    a + b + 0\\n}' ;;\n  *) printf 'pub fn add(a: i32, b: i32) -> i32 {\\n    b + a\\n}' ;;\nesac\n",
+    );
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--structure_name", "add"], "");
+
+    assert!(source.starts_with("pub fn add(a: i32, b: i32) -> i32 {\n    a + b + 0\n}\n"), "{}", source);
+    let fixtures = fs::read_dir(repo.dir.join("fixtures")).unwrap().count();
+    assert_eq!(fixtures, 3, "two attempts and the commit message are recorded");
+}
+
+#[test]
+fn replay_without_a_fixture_fails_and_restores_the_file() {
+    let repo = TestRepo::new("replay-miss", "");
+
+    let output = repo.run(None, &["--mode", "improvement", "--structure_name", "add"], "");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no recorded response in fixtures/improvement-add-"));
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+}
+
+#[test]
+fn ambiguous_structure_names_list_the_candidates() {
+    let repo = TestRepo::new("ambiguous", "");
+    fs::write(
+        repo.dir.join("src/main.rs"),
+        "struct A;\nstruct B;\nimpl A {\n    fn new() {}\n}\nimpl B {\n    fn new() {}\n}\n",
+    )
+    .unwrap();
+
+    let output = repo.run(None, &["--mode", "improvement", "--structure_name", "new"], "");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("  A::new (function_item, line 4)"), "{}", stderr);
+    assert!(stderr.contains("  B::new (function_item, line 7)"), "{}", stderr);
+}
+
+#[test]
+fn dry_run_prints_a_diff_and_leaves_the_file_alone() {
+    let repo = TestRepo::new("dry-run", "lint_command = \"touch linted\"\n");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--dry-run"], "");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,5 +1,5 @@\n pub fn add(a: i32, b: i32) -> i32 {\n-    a + b\n+    b + a\n }\n \n pub struct Counter {\n");
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+    assert!(!repo.dir.join(".rfcu").exists(), "dry runs are not journaled");
+    assert!(!repo.dir.join("linted").exists(), "the lint stage needs the edit on disk");
+}
+
+#[test]
+fn edits_of_non_ascii_characters_are_located_by_character() {
+    let repo = TestRepo::new("non-ascii", "");
+    repo.write("src/main.rs", &SOURCE.replace("    a + b\n", "    // Café.\n    a + b\n"));
+    repo.git(&["commit", "-q", "-am", "Add a comment"]);
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    // Cafè.\n    a + b\n}");
+
+    let args = ["--mode", "improvement", "--structure_name", "add"];
+    let output = repo.run(Some("record"), &[&args[..], &["--dry-run"]].concat(), "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("-    // Café.\n+    // Cafè.\n"));
+
+    let output = repo.run(Some("record"), &args, "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("    // Cafè.\n"));
+    assert_eq!(repo.last_commit_message(), "Test commit");
+}
+
+#[test]
+fn json_output_reports_the_old_and_new_ranges() {
+    let repo = TestRepo::new("json-output", "");
+    repo.respond_with("pub fn increment(&mut self) {\n    self.count += 2;\n}");
+
+    let output = repo.run(
+        Some("record"),
+        &["--mode", "improvement", "--structure_name", "increment", "--dry-run", "--output", "json"],
+        "",
+    );
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let change: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(change["structure_name"], "increment");
+    assert_eq!(change["old_range"]["start_line"], 12);
+    assert_eq!(change["old_range"]["end_line"], 12);
+    assert_eq!(change["new_range"]["start_line"], 12);
+    assert_eq!(change["old_code"], "        self.count += 1;\n");
+    assert_eq!(change["new_code"], "        self.count += 2;\n");
+    assert_eq!(repo.source(), SOURCE);
+}
+
+#[test]
+fn outline_only_counts_test_attributes_as_tests() {
+    let repo = TestRepo::new("outline-tests", "");
+    repo.write(
+        "src/main.rs",
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\npub fn double(x: i32) -> i32 {\n    x * 2\n}\n\n\
+         #[cfg(not(test))]\nfn startup() -> i32 {\n    double(1)\n}\n\n#[testing_helper]\nfn helper() -> i32 {\n    double(2)\n}\n\n\
+         #[test]\nfn adds() {\n    assert_eq!(add(1, 2), 3);\n}\n",
+    );
+
+    let output = repo.run(None, &["get_structure", "src/main.rs", "--format", "json"], "");
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let items: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let has_tests = |path: &str| items.iter().find(|item| item["path"] == path).unwrap()["has_tests"].clone();
+    assert_eq!(has_tests("add"), true);
+    assert_eq!(has_tests("double"), false);
+}
+
+#[test]
+fn attributes_of_the_previous_item_stay_with_it() {
+    let repo = TestRepo::new("attributes", "");
+    let source = "/// A unit.\n#[derive(Debug)]\nstruct Unit;\n\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\n#[inline]\npub fn double(x: i32) -> i32 {\n    x * 2\n}\n";
+    repo.write("src/main.rs", source);
+    repo.git(&["commit", "-q", "-am", "Add a unit struct"]);
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(None, &["get_structure", "src/main.rs", "--format", "json"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let items: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let has_docs = |path: &str| items.iter().find(|item| item["path"] == path).unwrap()["has_docs"].clone();
+    assert_eq!(has_docs("Unit"), true);
+    assert_eq!(has_docs("add"), false);
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.source(), source.replace("a + b", "b + a"));
+}
+
+#[test]
+fn json_output_handles_non_ascii_edits() {
+    let repo = TestRepo::new("json-non-ascii", "");
+    repo.write("src/main.rs", &SOURCE.replace("    a + b\n", "    // Café.\n    a + b\n"));
+    repo.git(&["commit", "-q", "-am", "Add a comment"]);
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    // Cafè.\n    a + b\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--dry-run", "--output", "json"], "");
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let change: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(change["old_range"]["start_line"], 2);
+    assert_eq!(change["old_range"]["end_line"], 2);
+    assert_eq!(change["old_code"], "    // Café.\n");
+    assert_eq!(change["new_code"], "    // Cafè.\n");
+}
+
+#[test]
+fn output_file_receives_the_updated_code() {
+    let repo = TestRepo::new("output-file", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(
+        Some("record"),
+        &["--mode", "improvement", "--structure_name", "add", "--output-file", "preview.rs"],
+        "",
+    );
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty());
+    let preview = fs::read_to_string(repo.dir.join("preview.rs")).unwrap();
+    assert_eq!(preview, SOURCE.replace("a + b", "b + a"));
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+}
+
+#[test]
+fn interactive_review_applies_the_accepted_hunks() {
+    let repo = TestRepo::new("interactive", "");
+    repo.respond_with(&SOURCE.replace("a + b", "b + a").replace("add(1, 2)", "add(2, 1)"));
+    repo.write("answers", "n\ny\n");
+
+    let output = repo.run_with_env(
+        Some("record"),
+        &["--mode", "whole_file", "--interactive"],
+        "",
+        &[("RFCU_TTY", "answers")],
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("@@ -1,5 +1,5 @@\n pub fn add(a: i32, b: i32) -> i32 {\n-    a + b\n+    b + a\n"), "{}", stderr);
+    assert!(stderr.contains("(2/2) Apply this hunk [y,n,a,d,e,r,q,?]? "), "{}", stderr);
+    assert_eq!(repo.source(), SOURCE.replace("add(1, 2)", "add(2, 1)").trim_end());
+    assert_eq!(repo.last_commit_message(), "Test commit");
+}
+
+#[test]
+fn interactive_review_edits_and_regenerates() {
+    let repo = TestRepo::new("interactive-edit", "");
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"Generate a commit message\"*) echo \"Test commit\" ;;\n  *\"Additional instruction: use wrapping_add\"*) printf 'pub fn add(a: i32, b: i32) -> i32 {\\n    a.wrapping_add(b)\\n}' ;;\n  *) printf 'pub fn add(a: i32, b: i32) -> i32 {\\n    b + a\\n}' ;;\nesac\n",
+    );
+    repo.write("editor.sh", "sed -i 's/wrapping_add/checked_add/' \"$1\"\n");
+    repo.write("answers", "?\nr\nuse wrapping_add\ne\n");
+
+    let output = repo.run_with_env(
+        Some("record"),
+        &["--mode", "improvement", "--structure_name", "add", "--interactive"],
+        "",
+        &[("RFCU_TTY", "answers"), ("VISUAL", "sh editor.sh")],
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("r - ask for a new answer with an extra instruction"), "{}", stderr);
+    assert!(repo.source().starts_with("pub fn add(a: i32, b: i32) -> i32 {\n    a.checked_add(b)\n}\n"), "{}", repo.source());
+}
+
+#[test]
+fn quitting_the_interactive_review_leaves_the_file_alone() {
+    let repo = TestRepo::new("interactive-quit", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    repo.write("answers", "q\n");
+
+    let output = repo.run_with_env(
+        Some("record"),
+        &["--mode", "improvement", "--structure_name", "add", "--interactive"],
+        "",
+        &[("RFCU_TTY", "answers")],
+    );
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+    let history = repo.run(None, &["history"], "");
+    assert!(String::from_utf8_lossy(&history.stdout).contains("  aborted  improvement  src/main.rs  add  "));
+}
+
+#[test]
+fn runs_are_journaled_and_can_be_undone() {
+    let repo = TestRepo::new("undo", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "Swap the operands");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("b + a"));
+    assert_eq!(fs::read_to_string(repo.dir.join(".rfcu/history/.gitignore")).unwrap(), "*\n");
+    assert!(!repo.git(&["status", "--porcelain"]).contains(".rfcu"), "the journal is ignored by git");
+
+    let history = String::from_utf8(repo.run(None, &["history"], "").stdout).unwrap();
+    assert!(history.contains("  applied  improvement  src/main.rs  add  Swap the operands\n"), "{}", history);
+    let run_id = history.split_whitespace().next().unwrap().to_string();
+
+    let show = String::from_utf8(repo.run(None, &["show", &run_id], "").stdout).unwrap();
+    assert!(show.contains("=== Response 1 ===\npub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}"), "{}", show);
+    assert!(show.contains("-    a + b\n+    b + a\n"), "{}", show);
+
+    let undo = repo.run(None, &["undo"], "");
+    assert!(undo.status.success(), "{}", String::from_utf8_lossy(&undo.stderr));
+    assert_eq!(repo.source(), SOURCE);
+    let history = String::from_utf8(repo.run(None, &["history"], "").stdout).unwrap();
+    assert!(history.contains("  undone   improvement"), "{}", history);
+
+    let undo_again = repo.run(None, &["undo", &run_id], "");
+    assert!(!undo_again.status.success());
+    assert!(String::from_utf8_lossy(&undo_again.stderr).contains("was already undone"));
+}
+
+#[test]
+fn undo_refuses_to_overwrite_later_changes() {
+    let repo = TestRepo::new("undo-changed", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    repo.write("src/main.rs", &repo.source().replace("fn main", "fn main2"));
+
+    let undo = repo.run(None, &["undo"], "");
+    assert!(!undo.status.success());
+    assert!(String::from_utf8_lossy(&undo.stderr).contains("was changed after run"));
+    assert!(repo.source().contains("fn main2"));
+
+    let forced = repo.run(None, &["undo", "--force"], "");
+    assert!(forced.status.success(), "{}", String::from_utf8_lossy(&forced.stderr));
+    assert_eq!(repo.source(), SOURCE);
+}
+
+#[test]
+fn a_second_run_on_the_same_file_fails_fast_or_waits() {
+    let repo = TestRepo::new("lock", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    fs::create_dir_all(repo.dir.join(".rfcu/locks")).unwrap();
+    let lock = fs::File::create(repo.dir.join(".rfcu/locks/src%main.rs.lock")).unwrap();
+    lock.lock().unwrap();
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("src/main.rs is being edited by another rfcu run"));
+    assert_eq!(repo.source(), SOURCE);
+
+    let output = thread::scope(|scope| {
+        let waiting = scope.spawn(|| repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--wait"], ""));
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(repo.source(), SOURCE, "the waiting run has not started editing");
+        drop(lock);
+        waiting.join().unwrap()
+    });
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("b + a"));
+}
+
+#[cfg(unix)]
+#[test]
+fn edits_keep_the_file_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let repo = TestRepo::new("permissions", "");
+    fs::set_permissions(repo.dir.join("src/main.rs"), fs::Permissions::from_mode(0o754)).unwrap();
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("b + a"));
+    let mode = fs::metadata(repo.dir.join("src/main.rs")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o754);
+    let leftovers: Vec<_> = fs::read_dir(repo.dir.join("src")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(leftovers.len(), 1, "no temporary file is left behind: {:?}", leftovers);
+}
+
+#[test]
+fn commit_policies_leave_the_edit_unstaged_or_staged() {
+    let repo = TestRepo::new("no-commit", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--no-commit"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(repo.source().contains("b + a"));
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+    assert_eq!(repo.git(&["status", "--porcelain", "src/main.rs"]), " M src/main.rs\n");
+
+    repo.git(&["checkout", "src/main.rs"]);
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    repo.write("config.toml", &format!("commit = \"stage\"\n{}", config));
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+    assert_eq!(repo.git(&["status", "--porcelain", "src/main.rs"]), "M  src/main.rs\n");
+}
+
+#[test]
+fn unrelated_staged_changes_block_the_commit_unless_forced() {
+    let repo = TestRepo::new("staged", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    repo.write("notes.txt", "unrelated\n");
+    repo.git(&["add", "notes.txt"]);
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Other changes are already staged: notes.txt"));
+    assert_eq!(repo.source(), SOURCE);
+    assert!(!repo.dir.join("fixtures").exists(), "nothing was sent to the backend");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--force-commit"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.last_commit_message(), "Test commit");
+    assert_eq!(repo.git(&["show", "--name-only", "--format=", "HEAD"]), "src/main.rs\n");
+    assert_eq!(repo.git(&["diff", "--cached", "--name-only"]), "notes.txt\n", "the unrelated change stays staged");
+}
+
+#[test]
+fn new_branches_receive_the_commit_and_leave_the_checkout_alone() {
+    let repo = TestRepo::new("branch", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    let original_branch = repo.git(&["symbolic-ref", "--short", "HEAD"]);
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--branch", "edit-add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("git merge edit-add"));
+    assert_eq!(repo.git(&["symbolic-ref", "--short", "HEAD"]), original_branch);
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Initial commit");
+    assert!(repo.git(&["show", "edit-add:src/main.rs"]).contains("b + a"));
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--branch", "edit-add"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("the branch edit-add already exists"));
+
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    repo.write("config.toml", &format!("branch_strategy = \"new\"\n{}", config));
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let branches = repo.git(&["branch", "--list", "rfcu/improvement/add-*"]);
+    assert_eq!(branches.lines().count(), 1, "{}", branches);
+    assert_eq!(repo.source(), SOURCE);
+
+    repo.write("src/main.rs", &SOURCE.replace("a + b", "a+b"));
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has uncommitted changes"));
+}
+
+#[test]
+fn new_branches_are_checked_out_while_the_edit_is_validated() {
+    let repo = TestRepo::new("branch-validation", "lint_command = \"git symbolic-ref --short HEAD > lint_branch.txt\"\n");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+    let original_branch = repo.git(&["symbolic-ref", "--short", "HEAD"]);
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--branch", "edit-add"], "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(repo.dir.join("lint_branch.txt")).unwrap(), "edit-add\n");
+    assert_eq!(repo.git(&["symbolic-ref", "--short", "HEAD"]), original_branch);
+    assert_eq!(repo.source(), SOURCE);
+
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    a + \n}");
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add", "--branch", "edit-broken"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Deleting branch edit-broken"));
+    assert_eq!(repo.git(&["branch", "--list", "edit-broken"]), "");
+    assert_eq!(repo.git(&["symbolic-ref", "--short", "HEAD"]), original_branch);
+    assert_eq!(repo.source(), SOURCE);
+}
+
+#[test]
+fn commit_messages_describe_the_diff_and_the_run() {
+    let repo = TestRepo::new("commit-message", "");
+    repo.respond_with("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}");
+
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "Swap the operands");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let prompt = fs::read_to_string(repo.dir.join("commit_prompt.txt")).unwrap();
+    assert!(prompt.contains("-    a + b\n+    b + a"), "{}", prompt);
+    assert!(prompt.contains("Request: Swap the operands"), "{}", prompt);
+    assert!(prompt.contains("Structure: add"), "{}", prompt);
+    let message = repo.git(&["log", "-1", "--format=%B"]);
+    assert_eq!(
+        message.trim(),
+        "Test commit\n\nEdited add in src/main.rs with the improvement mode.\n\nRequest:\nSwap the operands\n\nValidation: parse passed"
+    );
+
+    repo.git(&["reset", "-q", "--hard", "HEAD~1"]);
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    repo.write("config.toml", &format!("commit_style = \"conventional\"\ncommit_template = \"{{subject}}\\n\\n{{validation}}\"\n{}", config));
+    let output = repo.run(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "Swap the operands");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.git(&["log", "-1", "--format=%B"]).trim(), "refactor(main): Test commit\n\nparse passed");
+
+    repo.git(&["reset", "-q", "--hard", "HEAD~1"]);
+    let answer = [("COMMIT_ANSWER", "Here is a commit message:\n\nSwap the operands of add")];
+    let output = repo.run_with_env(Some("record"), &["--mode", "improvement", "--structure_name", "add"], "Swap the operands.", &answer);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.last_commit_message(), "refactor(main): Apply improvement to add: Swap the operands");
+}
+
+/// Answers improvement requests for `add`, `increment` and `main`, one at a
+/// time or combined.
+const BATCH_RESPOND_SCRIPT: &str = r#"input=$(cat)
+add='pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}'
+increment='pub fn increment(&mut self) {\n    self.count += 2;\n}'
+main='fn main() {\n    println!("{}", add(2, 1));\n}'
+case "$input" in
+  *"subject line of a git commit message"*) echo "Test commit" ;;
+  *"requests below"*) printf "\`\`\`rust\n$add\n\`\`\`\n\n\`\`\`rust\n$increment\n\`\`\`\n" ;;
+  *"item 'add'"*) printf "$add" ;;
+  *"item 'Counter::increment'"*) printf "$increment" ;;
+  *"item 'main'"*) printf "$main" ;;
+esac
+"#;
+
+#[test]
+fn several_structures_are_edited_in_one_commit() {
+    let repo = TestRepo::new("batch", "");
+    repo.write("respond.sh", BATCH_RESPOND_SCRIPT);
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--structure_name", "add,Counter::increment"], "");
+    assert!(source.contains("pub fn add(a: i32, b: i32) -> i32 {\n    b + a\n}"), "{}", source);
+    assert!(source.contains("    /// Old docs.\n    pub fn increment(&mut self) {\n        self.count += 2;\n    }"), "{}", source);
+    assert_eq!(repo.git(&["log", "--format=%s", "HEAD~1"]).trim(), "Initial commit");
+    assert!(repo.git(&["log", "-1", "--format=%B"]).contains("Edited add, Counter::increment in src/main.rs"));
+}
+
+#[test]
+fn several_structures_can_share_one_prompt() {
+    let repo = TestRepo::new("batch-combined", "");
+    repo.write("respond.sh", BATCH_RESPOND_SCRIPT);
+
+    let args = ["--mode", "improvement", "--structure_name", "Counter::increment", "--structure_name", "add", "--combined"];
+    let source = repo.record_and_replay(&args, "");
+    assert!(source.contains("    b + a\n"), "{}", source);
+    assert!(source.contains("        self.count += 2;\n"), "{}", source);
+    let fixtures = fs::read_dir(repo.dir.join("fixtures")).unwrap().count();
+    assert_eq!(fixtures, 2, "one request for the structures and one for the commit message");
+}
+
+#[test]
+fn all_kind_selects_every_structure_of_a_kind() {
+    let repo = TestRepo::new("batch-kind", "");
+    repo.write("respond.sh", BATCH_RESPOND_SCRIPT);
+
+    let source = repo.record_and_replay(&["--mode", "improvement", "--all-kind", "function_item"], "");
+    assert!(source.contains("    b + a\n"), "{}", source);
+    assert!(source.contains("        self.count += 2;\n"), "{}", source);
+    assert!(source.contains("add(2, 1)"), "{}", source);
+
+    let output = repo.run(None, &["--mode", "improvement", "--structure_name", "impl Counter,increment"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("impl Counter and increment overlap"));
+}
+
+#[test]
+fn run_edits_the_selected_structures_across_the_tree() {
+    let repo = TestRepo::new("project", "");
+    repo.write("src/util.rs", "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n\n/// Halves.\npub fn half(x: i32) -> i32 {\n    x / 2\n}\n\nfn hidden() {}\n");
+    repo.write("src/generated.rs", "pub fn generated() {}\n");
+    repo.write(".gitignore", "src/generated.rs\nfixtures/\n");
+    repo.git(&["add", "src/util.rs", ".gitignore"]);
+    repo.git(&["commit", "-q", "-m", "Add util"]);
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"subject line of a git commit message\"*) echo \"Test commit\" ;;\n  *\"item 'add'\"*) printf '/// Adds two numbers.' ;;\n  *\"item 'util::double'\"*) printf '/// Doubles x.' ;;\n  *) exit 1 ;;\nesac\n",
+    );
+
+    let args = ["run", "--mode", "documentation_structure", "--path", "src", "--glob", "**/*.rs", "--select", "undocumented pub fn"];
+    let output = repo.run(Some("record"), &args, "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Summary: 2 edited, 0 unchanged, 0 failed, 0 skipped"), "{}", stderr);
+    assert!(repo.source().contains("/// Adds two numbers.\npub fn add"));
+    let util = fs::read_to_string(repo.dir.join("src/util.rs")).unwrap();
+    assert!(util.contains("/// Doubles x.\npub fn double"), "{}", util);
+    assert!(util.contains("/// Halves.\npub fn half"), "{}", util);
+    assert_eq!(fs::read_to_string(repo.dir.join("src/generated.rs")).unwrap(), "pub fn generated() {}\n");
+    assert_eq!(repo.last_commit_message(), "Test commit");
+    assert_eq!(repo.git(&["show", "--name-only", "--format=", "HEAD"]), "src/main.rs\nsrc/util.rs\n");
+
+    repo.git(&["reset", "-q", "--hard", "HEAD~1"]);
+    let output = repo.run(Some("record"), &[&args[..], &["--commits", "per-file", "--jobs", "1"]].concat(), "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(repo.git(&["log", "--format=%s", "-3"]), "Test commit\nTest commit\nAdd util\n");
+
+    repo.git(&["reset", "-q", "--hard", "HEAD~2"]);
+    let output = repo.run(None, &["run", "--mode", "documentation_structure", "--path", "src", "--select", "undocumented pub fn", "--no-commit"], "Other");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Summary: 0 edited, 0 unchanged, 2 failed, 0 skipped"), "{}", stderr);
+    assert_eq!(repo.source(), SOURCE);
+}
+
+#[test]
+fn run_validates_one_file_at_a_time() {
+    let repo = TestRepo::new("project-validation", "lint_command = \"sleep 0.5; ! grep -q BROKEN src/*.rs\"\n");
+    repo.write("src/util.rs", "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n");
+    repo.write(".gitignore", "fixtures/\n");
+    repo.git(&["add", "src/util.rs", ".gitignore"]);
+    repo.git(&["commit", "-q", "-m", "Add util"]);
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"item 'add'\"*) printf '/// Adds two numbers.' ;;\n  *\"item 'util::double'\"*) printf '/// BROKEN' ;;\n  *) exit 1 ;;\nesac\n",
+    );
+
+    let args = ["run", "--mode", "documentation_structure", "--path", "src", "--select", "undocumented pub fn", "--no-commit", "--jobs", "2"];
+    let output = repo.run(Some("record"), &args, "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Summary: 1 edited, 0 unchanged, 1 failed, 0 skipped"), "{}", stderr);
+    assert!(repo.source().contains("/// Adds two numbers.\npub fn add"), "the broken edit of util.rs was not seen");
+    assert_eq!(fs::read_to_string(repo.dir.join("src/util.rs")).unwrap(), "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n");
+}
+
+#[test]
+fn run_commits_every_file_on_one_new_branch() {
+    let repo = TestRepo::new("project-branch", "");
+    repo.write("src/util.rs", "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n");
+    repo.write(".gitignore", "fixtures/\n");
+    repo.git(&["add", "src/util.rs", ".gitignore"]);
+    repo.git(&["commit", "-q", "-m", "Add util"]);
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"subject line of a git commit message\"*) echo \"Test commit\" ;;\n  *\"item 'add'\"*) printf '/// Adds two numbers.' ;;\n  *\"item 'util::double'\"*) printf '/// Doubles x.' ;;\n  *) exit 1 ;;\nesac\n",
+    );
+    let config = fs::read_to_string(repo.dir.join("config.toml")).unwrap();
+    repo.write("config.toml", &format!("branch_strategy = \"new\"\n{}", config));
+    let original_branch = repo.git(&["symbolic-ref", "--short", "HEAD"]);
+
+    let args = ["run", "--mode", "documentation_structure", "--path", "src", "--select", "undocumented pub fn"];
+    let output = repo.run(Some("record"), &args, "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Summary: 2 edited, 0 unchanged, 0 failed, 0 skipped"), "{}", stderr);
+    assert_eq!(repo.git(&["symbolic-ref", "--short", "HEAD"]), original_branch);
+    assert_eq!(repo.source(), SOURCE);
+    assert_eq!(repo.last_commit_message(), "Add util");
+    let branches = repo.git(&["branch", "--list", "rfcu/documentation_structure/src-*"]);
+    assert_eq!(branches.lines().count(), 1, "{}", branches);
+    let branch = branches.trim();
+    assert_eq!(repo.git(&["log", "--format=%s", "-2", branch]), "Test commit\nAdd util\n");
+    assert!(repo.git(&["show", &format!("{}:src/util.rs", branch)]).contains("/// Doubles x.\npub fn double"));
+
+    repo.write("src/util.rs", "pub fn double(x: i32) -> i32 {\n    x*2\n}\n");
+    let output = repo.run(Some("record"), &args, "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("src/util.rs has uncommitted changes"));
+    assert_eq!(repo.git(&["branch", "--list", "rfcu/*"]).lines().count(), 1);
+}
+
+#[test]
+fn run_skips_structures_that_share_a_path() {
+    let repo = TestRepo::new("project-shared-paths", "");
+    let util = "#[cfg(unix)]\npub fn sep() -> char {\n    '/'\n}\n\n#[cfg(not(unix))]\npub fn sep() -> char {\n    '\\\\'\n}\n\npub fn double(x: i32) -> i32 {\n    x * 2\n}\n";
+    repo.write("src/util.rs", util);
+    repo.write("src/other.rs", "#[cfg(unix)]\npub fn only() {}\n\n#[cfg(not(unix))]\npub fn only() {}\n");
+    repo.write(".gitignore", "fixtures/\n");
+    repo.git(&["add", "src", ".gitignore"]);
+    repo.git(&["commit", "-q", "-m", "Add util"]);
+    repo.write(
+        "respond.sh",
+        "input=$(cat)\ncase \"$input\" in\n  *\"subject line of a git commit message\"*) echo \"Test commit\" ;;\n  *\"item 'util::double'\"*) printf '/// Doubles x.' ;;\n  *) exit 1 ;;\nesac\n",
+    );
+
+    let args = ["run", "--mode", "documentation_structure", "--path", "src", "--glob", "{util,other}.rs", "--select", "undocumented pub fn"];
+    let output = repo.run(Some("record"), &args, "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("skipping util::sep, whose path is shared by 2 structures"), "{}", stderr);
+    assert!(stderr.contains("Summary: 1 edited, 0 unchanged, 0 failed, 1 skipped"), "{}", stderr);
+    assert!(stderr.contains("other::only cannot be told apart"), "{}", stderr);
+    let edited = fs::read_to_string(repo.dir.join("src/util.rs")).unwrap();
+    assert_eq!(edited, util.replace("pub fn double", "/// Doubles x.\npub fn double"));
+}
+
+#[test]
+fn jobs_resume_and_skip_the_structures_already_edited() {
+    let repo = TestRepo::new("jobs", "");
+    repo.write("src/util.rs", "pub fn double(x: i32) -> i32 {\n    x * 2\n}\n");
+    repo.write(".gitignore", "fixtures/\n");
+    repo.git(&["add", "src/util.rs", ".gitignore"]);
+    repo.git(&["commit", "-q", "-m", "Add util"]);
+    let jobs = "[jobs.docs]\nmode = \"documentation_structure\"\npath = \"src\"\nselect = \"pub fn\"\nrequest = \"Document it.\"\nprofile = \"record\"\njobs = 1\n";
+    repo.write("rfcu-jobs.toml", jobs);
+    let respond = "input=$(cat)\ncase \"$input\" in\n  *\"subject line of a git commit message\"*) echo \"Test commit\" ;;\n  *\"item 'add'\"*) printf '/// Adds.' ;;\n  *\"item 'Counter::increment'\"*) printf '/// Increments.' ;;\n  *\"item 'util::double'\"*) printf '/// Doubles.' ;;\n  *) exit 1 ;;\nesac\n";
+    repo.write("respond.sh", &respond.replace("printf '/// Doubles.'", "exit 1"));
+
+    let output = repo.run(None, &["jobs", "run"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Summary: 1 edited, 0 unchanged, 1 failed, 0 skipped"), "{}", stderr);
+    assert!(repo.source().contains("/// Adds.\npub fn add"));
+    assert!(repo.dir.join(".rfcu/jobs/state.json").is_file());
+
+    repo.write("respond.sh", respond);
+    let output = repo.run(None, &["jobs", "run", "docs"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Summary: 1 edited, 0 unchanged, 0 failed, 1 skipped"), "{}", stderr);
+    assert!(stderr.contains("unchanged since its last successful run"), "{}", stderr);
+    assert!(fs::read_to_string(repo.dir.join("src/util.rs")).unwrap().contains("/// Doubles.\npub fn double"));
+
+    let output = repo.run(None, &["jobs", "run"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Summary: 0 edited, 0 unchanged, 0 failed, 2 skipped"), "{}", stderr);
+
+    repo.write("src/util.rs", "/// Doubles.\npub fn double(x: i32) -> i32 {\n    x + x\n}\n");
+    repo.git(&["commit", "-q", "-am", "Change double"]);
+    let output = repo.run(None, &["jobs", "run"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Summary: 0 edited, 1 unchanged, 0 failed, 1 skipped"), "{}", stderr);
+
+    repo.write("rfcu-jobs.toml", &jobs.replace("Document it.", "Document it briefly."));
+    let output = repo.run(None, &["jobs", "run"], "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("The job docs changed since its last run, starting it over."), "{}", stderr);
+    assert!(stderr.contains("Summary: 0 edited, 2 unchanged, 0 failed, 0 skipped"), "{}", stderr);
+
+    let output = repo.run(None, &["jobs", "run", "tests"], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No job named 'tests' in rfcu-jobs.toml"));
+}
+
+#[test]
+fn requests_for_several_structures_are_sent_concurrently() {
+    let respond = "input=$(cat)\ncase \"$input\" in\n  *\"item '\"*)\n    echo start >> requests.log\n    case \"$input\" in *\"item 'add'\"*) sleep 0.6 ;; *) sleep 0.3 ;; esac\n    echo end >> requests.log ;;\nesac\nprintf '%s' \"$input\" | sh batch.sh\n";
+    let args = ["--mode", "improvement", "--all-kind", "function_item", "--no-commit"];
+
+    let repo = TestRepo::new("concurrent", "max_concurrency = 3\n");
+    repo.write("batch.sh", BATCH_RESPOND_SCRIPT);
+    repo.write("respond.sh", respond);
+    let output = repo.run(Some("record"), &args, "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let log = fs::read_to_string(repo.dir.join("requests.log")).unwrap();
+    assert!(log.starts_with("start\nstart\nstart\n"), "{}", log);
+    let source = repo.source();
+    assert!(source.contains("    b + a\n"), "{}", source);
+    assert!(source.contains("        self.count += 2;\n"), "{}", source);
+    assert!(source.contains("add(2, 1)"), "{}", source);
+    drop(repo);
+
+    let repo = TestRepo::new("sequential", "max_concurrency = 1\n");
+    repo.write("batch.sh", BATCH_RESPOND_SCRIPT);
+    repo.write("respond.sh", respond);
+    let output = repo.run(Some("record"), &args, "");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(repo.dir.join("requests.log")).unwrap(), "start\nend\n".repeat(3));
+}